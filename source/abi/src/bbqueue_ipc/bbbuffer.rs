@@ -222,6 +222,12 @@ unsafe impl<'a> Send for Producer<'a> {}
 unsafe impl<'a> Sync for Producer<'a> {}
 
 impl<'a> Producer<'a> {
+    /// The total size of the underlying buffer, in bytes
+    pub fn capacity(&self) -> usize {
+        let inner = unsafe { &self.bbq.as_ref() };
+        inner.buf_len.load(Relaxed)
+    }
+
     /// Request a writable, contiguous section of memory of exactly
     /// `sz` bytes. If the buffer size requested is not available,
     /// an error will be returned.
@@ -96,6 +96,15 @@ impl<'a> FrameProducer<'a> {
             grant_w: self.producer.grant_exact(max_sz + HDR_LEN)?,
         })
     }
+
+    /// The size in bytes of the largest frame that can always be granted,
+    /// once enough space has been released by the consumer.
+    ///
+    /// Depending on the current read and write positions, larger frames may
+    /// never fit in the ring.
+    pub fn max_frame_size(&self) -> usize {
+        (self.producer.capacity() / 2).saturating_sub(HDR_LEN)
+    }
 }
 
 /// A consumer of Framed data
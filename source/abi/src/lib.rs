@@ -4,14 +4,20 @@
 
 use bbqueue_ipc::BBBuffer;
 use core::ptr::null_mut;
-use core::sync::atomic::{AtomicPtr, AtomicUsize};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
+// These are set by the kernel before userspace starts, and read by the
+// userspace runtime when it boots.
+//
 // TODO: Put this into a linker section
-pub static K2U_RING: AtomicPtr<BBBuffer> = AtomicPtr::new(null_mut());
-pub static U2K_RING: AtomicPtr<BBBuffer> = AtomicPtr::new(null_mut());
+pub static SYSCALL_RINGS: SysCallRings = SysCallRings::new();
 pub static HEAP_PTR: AtomicPtr<u8> = AtomicPtr::new(null_mut());
 pub static HEAP_LEN: AtomicUsize = AtomicUsize::new(0);
 
+// This is set by the platform before userspace starts, and called by the
+// userspace runtime whenever it is idle.
+pub static WAIT_FOR_EVENT: WaitForEvent = WaitForEvent::new();
+
 // TODO: Move me to mstd
 // pub mod porcelain;
 pub mod bbqueue_ipc;
@@ -30,3 +36,68 @@ pub struct SysCallRings {
     /// KERNEL should take the PRODUCER
     pub kernel_to_user: AtomicPtr<BBBuffer>,
 }
+
+impl SysCallRings {
+    pub const fn new() -> Self {
+        Self {
+            user_to_kernel: AtomicPtr::new(null_mut()),
+            kernel_to_user: AtomicPtr::new(null_mut()),
+        }
+    }
+}
+
+impl Default for SysCallRings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Puts the CPU to sleep until something may have happened, like an interrupt
+/// firing, or the kernel sending userspace a message.
+///
+/// On Cortex-M, this would be `WFE`. The hook must return right away if an
+/// event happened since it last returned, so one sent just before userspace
+/// goes to sleep isn't missed. Waking for no reason is fine, userspace checks
+/// what happened every time the hook returns.
+pub struct WaitForEvent {
+    hook: AtomicPtr<()>,
+}
+
+impl WaitForEvent {
+    pub const fn new() -> Self {
+        Self {
+            hook: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Provide the hook.
+    pub fn set(&self, hook: fn()) {
+        self.hook.store(hook as *mut (), Ordering::Release);
+    }
+
+    /// Sleep with the hook, or if the platform hasn't provided one, return
+    /// right away, so the caller spins instead.
+    pub fn wait(&self) {
+        match self.get() {
+            Some(hook) => hook(),
+            None => core::hint::spin_loop(),
+        }
+    }
+
+    /// The hook, if the platform has provided one.
+    pub fn get(&self) -> Option<fn()> {
+        let hook = self.hook.load(Ordering::Acquire);
+        if hook.is_null() {
+            None
+        } else {
+            // SAFETY: the only non-null pointers stored are from `set`
+            Some(unsafe { core::mem::transmute::<*mut (), fn()>(hook) })
+        }
+    }
+}
+
+impl Default for WaitForEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
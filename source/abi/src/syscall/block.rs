@@ -0,0 +1,152 @@
+//! Named blocks on the block storage device
+//!
+//! Each block holds a single file: up to `capacity` bytes of data, plus a
+//! [BlockKind], its length, and optionally a name of up to
+//! [MAX_NAME_LEN] bytes of UTF-8.
+//!
+//! A block must be opened before it is read or written. The first write after
+//! opening erases the whole block, so partial rewrites are not supported.
+//! Closing the block records its name, length and kind. Offsets must be
+//! 4-byte aligned.
+
+use super::ByteBoxWire;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// The longest name a block can have, in bytes
+pub const MAX_NAME_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum BlockRequest {
+    StoreInfo,
+    /// Get information about a block. Its name, if any, is written to the
+    /// start of `name_buf`.
+    BlockInfo {
+        block: u32,
+        name_buf: ByteBoxWire,
+    },
+    Open {
+        block: u32,
+    },
+    /// Read up to `len` bytes, from `offset` bytes into the block, into the
+    /// start of `buffer`
+    Read {
+        block: u32,
+        offset: u32,
+        buffer: ByteBoxWire,
+        len: usize,
+    },
+    /// Write the first `used` bytes of `buffer`, from `offset` bytes into the
+    /// block
+    Write {
+        block: u32,
+        offset: u32,
+        buffer: ByteBoxWire,
+        used: usize,
+    },
+    /// Close the block, recording the first `name_len` bytes of `name` as its
+    /// name, along with its length and kind
+    Close {
+        block: u32,
+        name: ByteBoxWire,
+        name_len: usize,
+        len: u32,
+        kind: BlockKind,
+    },
+}
+
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum BlockResponse {
+    StoreInfo(StoreInfo),
+    BlockInfo {
+        block: u32,
+        info: BlockInfo,
+        name_buf: ByteBoxWire,
+    },
+    Opened {
+        block: u32,
+    },
+    /// `used` bytes were read. Fewer than requested are read at the end of
+    /// the block.
+    Read {
+        block: u32,
+        buffer: ByteBoxWire,
+        used: usize,
+    },
+    Written {
+        block: u32,
+        buffer: ByteBoxWire,
+    },
+    Closed {
+        block: u32,
+        name: ByteBoxWire,
+    },
+}
+
+/// Requests that fail are never answered with a buffer. If the failed request
+/// carried a buffer, it is returned with
+/// [KernelMsg::Dealloc](super::KernelMsg::Dealloc).
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum BlockError {
+    Unknown,
+    NoSuchBlock,
+    /// The block is already open
+    AlreadyOpen,
+    /// The block has not been opened
+    NotOpen,
+    /// The offset is not 4-byte aligned
+    Unaligned,
+    /// The data, or the block's length, doesn't fit in the block
+    OutOfBounds,
+    /// The name is longer than [MAX_NAME_LEN] bytes, or isn't UTF-8
+    InvalidName,
+    /// The storage device failed to carry out the request
+    DeviceError,
+}
+
+/// Information about the block storage device
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct StoreInfo {
+    /// The number of blocks
+    pub blocks: u32,
+    /// The capacity of each block, in bytes
+    pub capacity: u32,
+}
+
+/// Information about a single block
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct BlockInfo {
+    /// The used length of the block, in bytes
+    pub length: u32,
+    /// The capacity of the block, in bytes
+    pub capacity: u32,
+    pub kind: BlockKind,
+    pub status: BlockStatus,
+    /// The length of the block's name, if it has one
+    pub name_len: Option<usize>,
+}
+
+/// What a block holds
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum BlockKind {
+    Unused,
+    /// A userspace application image
+    Program,
+    Storage,
+}
+
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum BlockStatus {
+    Idle,
+    /// Open, and not yet written, so it still holds its old contents
+    OpenNoWrites,
+    /// Open, and erased by the first write
+    OpenWritten,
+}
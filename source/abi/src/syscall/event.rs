@@ -0,0 +1,46 @@
+//! Unsolicited events, pushed from the kernel to userspace
+//!
+//! Unlike responses, events are not matched to a request by nonce. Userspace
+//! subscribes to the events it is interested in, by [EventService] and
+//! (optionally) [EventKind], and events that nobody has subscribed to are
+//! discarded.
+
+use super::serial::SerialEvent;
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, MaxSize, Debug, Clone)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum KernelEvent {
+    Serial(SerialEvent),
+}
+
+/// The kernel service that produced an event
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum EventService {
+    Serial,
+}
+
+/// The kind of an event, independent of its contents
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum EventKind {
+    SerialDataAvailable,
+}
+
+impl KernelEvent {
+    pub fn service(&self) -> EventService {
+        match self {
+            KernelEvent::Serial(_) => EventService::Serial,
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            KernelEvent::Serial(SerialEvent::DataAvailable { .. }) => {
+                EventKind::SerialDataAvailable
+            }
+        }
+    }
+}
@@ -22,78 +22,155 @@
 //! moment. If this is important to you, pin the exact `common` crate version
 //! you plan to support, or open an issue to discuss changing this policy.
 
+pub mod block;
+pub mod event;
 pub mod serial;
 
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
 // This is SUPPOSED to be used to route incoming userspace requests to the proper
 // kernelspace driver. I'm not sure this is the right abstraction.
 //
 // TODO: This MUST be kept in sync with UserRequestBody!
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DriverKind {
     Serial,
+    Block,
+
+    /// Handled by the kernel itself, rather than by a driver
+    Kernel,
 
     // I'm not sure if I actually want to keep the "driverkind" paradigm.
     Todo,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct UserRequest {
     pub header: UserRequestHeader,
     pub body: UserRequestBody,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct UserRequestHeader {
     pub nonce: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum UserRequestBody {
     Serial(serial::SerialRequest),
+    /// Userspace is no longer waiting for a response to the request with
+    /// this nonce
+    Cancel {
+        nonce: u32,
+    },
+    /// Userspace has stopped, and will not send any more requests. A non-zero
+    /// code means that it stopped because of an error, e.g. a panic.
+    Halt {
+        code: i32,
+    },
+    /// Userspace has nothing to do until the kernel sends it a message, or
+    /// until the kernel's clock reaches `until` (in microseconds since boot).
+    ///
+    /// `received` is the number of messages userspace has read from the k2u
+    /// ring so far (wrapping). If the kernel has sent more than that, the wait
+    /// is ignored, as userspace has not seen them yet. No response is sent.
+    Wait {
+        until: Option<u64>,
+        received: u32,
+    },
+    Block(block::BlockRequest),
 }
 
 impl UserRequest {
     pub fn driver_kind(&self) -> DriverKind {
         match self.body {
             UserRequestBody::Serial(_) => DriverKind::Serial,
+            UserRequestBody::Block(_) => DriverKind::Block,
+            UserRequestBody::Cancel { .. }
+            | UserRequestBody::Halt { .. }
+            | UserRequestBody::Wait { .. } => DriverKind::Kernel,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl UserRequestHeader {
+    /// Attempt to recover the header of a serialized [UserRequest] whose
+    /// body could not be decoded.
+    pub fn recover(bytes: &[u8]) -> Option<Self> {
+        // The header is serialized first, so it can be decoded on its own
+        postcard::take_from_bytes::<Self>(bytes)
+            .ok()
+            .map(|(header, _rest)| header)
+    }
+}
+
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum KernelMsg {
     Timestamp(u64),
     Dealloc(ByteBoxWire),
     Response(KernelResponse),
+    Event(event::KernelEvent),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct KernelResponse {
     pub header: KernelResponseHeader,
     pub body: KernelResponseBody,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct KernelResponseHeader {
     pub nonce: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl KernelResponseHeader {
+    /// Attempt to recover the response header of a serialized [KernelMsg]
+    /// whose body could not be decoded.
+    ///
+    /// Returns `None` if the message is not a [KernelMsg::Response], or if
+    /// even the header could not be decoded.
+    pub fn recover(bytes: &[u8]) -> Option<Self> {
+        match postcard::take_from_bytes::<KernelMsgHeader>(bytes).ok()? {
+            (KernelMsgHeader::Response(header), _rest) => Some(header),
+            _ => None,
+        }
+    }
+}
+
+/// A mirror of [KernelMsg] that only decodes the header of a response.
+///
+/// TODO: This MUST be kept in sync with KernelMsg!
+#[derive(Deserialize)]
+enum KernelMsgHeader {
+    #[allow(dead_code)]
+    Timestamp(u64),
+    #[allow(dead_code)]
+    Dealloc(ByteBoxWire),
+    Response(KernelResponseHeader),
+    #[allow(dead_code)]
+    Event(event::KernelEvent),
+}
+
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum KernelResponseBody {
     Serial(Result<serial::SerialResponse, serial::SerialError>),
     TodoLoopback,
+    /// The kernel could not decode the request with this nonce
+    MalformedRequest,
+    /// No driver is available to handle the request with this nonce
+    NoDriver,
+    Block(Result<block::BlockResponse, block::BlockError>),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct ByteBoxWire {
     pub ptr: usize,
@@ -1,7 +1,8 @@
 use super::ByteBoxWire;
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum SerialRequest {
     OpenPort {
@@ -19,9 +20,14 @@ pub enum SerialRequest {
         buffer: ByteBoxWire,
         used: usize,
     },
+    /// Close the port. Any receive buffers still held by the kernel are
+    /// returned with [KernelMsg::Dealloc](super::KernelMsg::Dealloc).
+    ClosePort {
+        port: u16,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum SerialResponse {
     OpenPort {
@@ -39,10 +45,35 @@ pub enum SerialResponse {
         port: u16,
         buffer: ByteBoxWire,
     },
+    ClosePort {
+        port: u16,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Requests that fail are never answered with a buffer. If the failed request
+/// carried a buffer, it is returned with
+/// [KernelMsg::Dealloc](super::KernelMsg::Dealloc).
+#[derive(Serialize, Deserialize, MaxSize, Debug)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum SerialError {
     Unknown,
+    /// The port is already open
+    AlreadyOpen,
+    /// The port has not been opened
+    NotOpen,
+    /// The port does not exist, or is in use by the kernel
+    PortUnavailable,
+    /// Too many receive buffers are already waiting for data on the port
+    Busy,
+}
+
+#[derive(Serialize, Deserialize, MaxSize, Debug, Clone)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum SerialEvent {
+    /// Data has arrived on an open port, while no receive buffer was waiting
+    /// for it. `len` bytes are waiting to be read.
+    ///
+    /// This is published once, until a receive buffer is provided for the
+    /// port, or it is reopened.
+    DataAvailable { port: u16, len: usize },
 }
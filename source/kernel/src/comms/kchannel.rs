@@ -37,10 +37,11 @@ pub struct KConsumer<T> {
 /// the type-erased driver service registry.
 ///
 /// It contains a VTable of functions necessary for operations while type-erased,
-/// namely cloning and dropping.
+/// namely cloning, closing, and dropping.
 pub(crate) struct ErasedKProducer {
     erased_q: NonNull<MpScQueue<(), sealed::SpiteData<()>>>,
     dropper: unsafe fn(NonNull<MpScQueue<(), sealed::SpiteData<()>>>),
+    closer: unsafe fn(NonNull<MpScQueue<(), sealed::SpiteData<()>>>),
     cloner: unsafe fn(&Self) -> Self,
 }
 
@@ -129,6 +130,14 @@ impl<T> KProducer<T> {
         self.q.enqueue_async(item).await
     }
 
+    /// Permanently close the channel.
+    ///
+    /// Items that have already been sent can still be received by the
+    /// [KConsumer], after which it will receive an error.
+    pub fn close(&self) {
+        self.q.close()
+    }
+
     pub(crate) fn type_erase(self) -> ErasedKProducer {
         let typed_q: NonNull<MpScQueue<T, sealed::SpiteData<T>>> = self.q.leak();
         let erased_q: NonNull<MpScQueue<(), sealed::SpiteData<()>>> = typed_q.cast();
@@ -136,6 +145,7 @@ impl<T> KProducer<T> {
         ErasedKProducer {
             erased_q,
             dropper: ErasedKProducer::drop_erased::<T>,
+            closer: ErasedKProducer::close_erased::<T>,
             cloner: ErasedKProducer::clone_erased::<T>,
         }
     }
@@ -186,6 +196,7 @@ impl ErasedKProducer {
         Self {
             erased_q: self.erased_q,
             dropper: self.dropper,
+            closer: self.closer,
             cloner: self.cloner,
         }
     }
@@ -202,6 +213,22 @@ impl ErasedKProducer {
         KProducer { q: heap_arc }
     }
 
+    /// Close the underlying [KChannel], see [KProducer::close].
+    pub(crate) fn close(&self) {
+        unsafe { (self.closer)(self.erased_q) }
+    }
+
+    /// Close the ErasedKProducer's channel, while also re-typing the leaked [KProducer] type.
+    ///
+    /// SAFETY:
+    ///
+    /// The type `T` MUST be the same `T` that was used to create this ErasedKProducer,
+    /// otherwise undefined behavior will occur.
+    pub(crate) unsafe fn close_erased<T>(ptr: NonNull<MpScQueue<(), sealed::SpiteData<()>>>) {
+        let ptr = ptr.cast::<MpScQueue<T, sealed::SpiteData<T>>>();
+        ptr.as_ref().close();
+    }
+
     /// Drop the ErasedKProducer, while also re-typing the leaked [KProducer] type.
     ///
     /// SAFETY:
@@ -11,7 +11,7 @@ use core::{
 };
 
 use maitake::wait::{Closed, WaitCell};
-use mnemos_alloc::containers::HeapArc;
+use mnemos_alloc::{containers::HeapArc, heap::HeapGuard};
 
 use crate::Kernel;
 
@@ -83,6 +83,16 @@ impl<T> Reusable<T> {
         }
     }
 
+    /// Create a new `Reusable<T>` using the given heap guard. Used for pre-async
+    /// initialization steps.
+    ///
+    /// Returns an error if the heap is out of memory.
+    pub fn try_new(guard: &mut HeapGuard) -> Result<Self, ()> {
+        Ok(Self {
+            inner: guard.alloc_arc(Inner::new()).map_err(drop)?,
+        })
+    }
+
     /// Create a sender for the given `Reusable<T>`. If a sender is already
     /// active, or the previous response has not yet been retrieved, an
     /// error will be immediately returned.
@@ -1 +1,34 @@
+use abi::syscall::{
+    ByteBoxWire, KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader,
+};
+use tracing::warn;
+
+use crate::comms::kchannel::KProducer;
+
 pub mod serial_mux;
+pub mod user_block;
+pub mod user_serial;
+
+// Helpers for the drivers serving userspace
+
+/// Answer the userspace request with this nonce.
+async fn reply(response: &KProducer<KernelMsg>, nonce: u32, body: KernelResponseBody) {
+    let msg = KernelMsg::Response(KernelResponse {
+        header: KernelResponseHeader { nonce },
+        body,
+    });
+    if response.enqueue_async(msg).await.is_err() {
+        warn!(nonce, "Failed to send response to userspace");
+    }
+}
+
+/// Give a buffer back to userspace, without a response.
+async fn return_buffer(response: &KProducer<KernelMsg>, buffer: ByteBoxWire) {
+    if response
+        .enqueue_async(KernelMsg::Dealloc(buffer))
+        .await
+        .is_err()
+    {
+        warn!("Failed to return buffer to userspace");
+    }
+}
@@ -11,7 +11,7 @@ use crate::{
 };
 use maitake::sync::Mutex;
 use mnemos_alloc::containers::{HeapArc, HeapArray, HeapFixedVec};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 /// SerialMux is the registered driver type
@@ -65,6 +65,7 @@ struct CommanderTask {
 }
 
 struct IncomingMuxerTask {
+    kernel: &'static Kernel,
     buf: HeapArray<u8>,
     idx: usize,
     incoming: bbq::Consumer,
@@ -121,6 +122,7 @@ impl SerialMux {
             mux: imutex.clone(),
         };
         let muxer = IncomingMuxerTask {
+            kernel,
             incoming: scons,
             mux: imutex,
             buf,
@@ -128,13 +130,13 @@ impl SerialMux {
         };
 
         kernel
-            .spawn(async move {
+            .spawn_named("SerialMux Commander", async move {
                 commander.run().await;
             })
             .await;
 
         kernel
-            .spawn(async move {
+            .spawn_named("SerialMux Incoming", async move {
                 muxer.run().await;
             })
             .await;
@@ -244,8 +246,9 @@ impl MuxingInfo {
 
 impl CommanderTask {
     async fn run(self) {
-        loop {
-            let msg = self.cmd.dequeue_async().await.map_err(drop).unwrap();
+        // Our request channel is closed when the kernel shuts down. Once all
+        // pending requests have been handled, stop.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
             let Message { msg: req, reply } = msg;
             match req.body {
                 Request::RegisterPort { port_id, capacity } => {
@@ -261,6 +264,7 @@ impl CommanderTask {
                 }
             }
         }
+        info!("SerialMux commander stopped");
     }
 }
 
@@ -268,8 +272,8 @@ impl CommanderTask {
 
 impl IncomingMuxerTask {
     async fn run(mut self) {
-        loop {
-            let mut rgr = self.incoming.read_grant().await;
+        let kernel = self.kernel;
+        while let Some(mut rgr) = kernel.until_shutdown(self.incoming.read_grant()).await {
             let mut used = 0;
             for ch in rgr.split_inclusive_mut(|&num| num == 0) {
                 used += ch.len();
@@ -341,5 +345,6 @@ impl IncomingMuxerTask {
             rgr.release(used);
             debug!(used, "processed incoming bytes");
         }
+        info!("SerialMux incoming muxer stopped");
     }
 }
@@ -0,0 +1,447 @@
+//! Named blocks for userspace
+//!
+//! [UserBlock] handles the [BlockRequest]s sent by userspace, keeping a
+//! single file in each block of the [BlockStorage] device: its data, plus a
+//! name, a length and a [BlockKind], which are recorded when the block is
+//! closed.
+//!
+//! As with [UserSerial](super::user_serial::UserSerial), data is exchanged
+//! using buffers owned by userspace, which are lent to the kernel for the
+//! duration of a request, and always given back.
+//!
+//! ## Metadata
+//!
+//! The device's first block is reserved for a table describing the others,
+//! so userspace sees one block fewer than the device has. Entry `i`, at
+//! `i * ENTRY_SIZE` bytes into the table, describes userspace block `i`,
+//! which is device block `i + 1`:
+//!
+//! | Offset | Size | Contents                                               |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 1    | Kind: 1 for a program, 2 for storage, else unused      |
+//! | 1      | 1    | Length of the name, or `0xFF` if there is none         |
+//! | 2      | 2    | Reserved, `0xFFFF`                                     |
+//! | 4      | 4    | Length of the block's data, little-endian              |
+//! | 8      | 128  | The name, UTF-8                                        |
+//!
+//! An erased table describes a device of unused blocks. The table is kept in
+//! memory, and the whole of it is written back whenever a block is closed.
+
+use core::slice;
+
+use abi::syscall::{
+    block::{
+        BlockError, BlockInfo, BlockKind, BlockRequest, BlockResponse, BlockStatus, StoreInfo,
+        MAX_NAME_LEN,
+    },
+    ByteBoxWire, DriverKind, KernelMsg, KernelResponseBody, UserRequestBody,
+};
+use mnemos_alloc::containers::HeapArray;
+use tracing::{info, warn};
+
+use crate::{
+    comms::kchannel::{KChannel, KConsumer, KProducer},
+    drivers::{reply, return_buffer},
+    registry::block_storage::{BlockStorage, BlockStorageError},
+    Kernel, UserMessage,
+};
+
+/// The number of userspace requests that can be waiting to be handled
+const REQUEST_QUEUE_DEPTH: usize = 8;
+/// The size of each block's entry in the metadata table
+pub const ENTRY_SIZE: usize = 136;
+/// The device block holding the metadata table
+const TABLE_BLOCK: u32 = 0;
+
+const KIND_PROGRAM: u8 = 1;
+const KIND_STORAGE: u8 = 2;
+const NO_NAME: u8 = 0xFF;
+const ERASED: u8 = 0xFF;
+
+/// UserBlock is the driver for userspace's named blocks
+pub struct UserBlock {
+    _inner: (),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+    StorageNotFound,
+    /// The device has no blocks to spare for userspace, or the metadata table
+    /// doesn't fit in one block
+    UnsupportedDevice,
+    /// The metadata table couldn't be read
+    DeviceError,
+    RouteAlreadyRegistered,
+}
+
+struct CommanderTask {
+    kernel: &'static Kernel,
+    cmd: KConsumer<UserMessage>,
+    storage: BlockStorage,
+    /// The capacity of each block
+    capacity: u32,
+    table: HeapArray<u8>,
+    status: HeapArray<BlockStatus>,
+}
+
+// impl UserBlock
+
+impl UserBlock {
+    /// Register the driver, giving userspace access to the blocks of the
+    /// [BlockStorage] device, which must already be registered.
+    pub async fn register(kernel: &'static Kernel) -> Result<(), RegistrationError> {
+        let mut storage = BlockStorage::from_registry(kernel)
+            .await
+            .ok_or(RegistrationError::StorageNotFound)?;
+        let device = storage
+            .store_info()
+            .await
+            .map_err(|_| RegistrationError::DeviceError)?;
+
+        let blocks = device.blocks.saturating_sub(1) as usize;
+        let table_len = blocks * ENTRY_SIZE;
+        if blocks == 0 || table_len > device.capacity as usize {
+            return Err(RegistrationError::UnsupportedDevice);
+        }
+        let table = kernel
+            .heap()
+            .allocate_array_with(|| ERASED, table_len)
+            .await;
+        let table = storage
+            .read(TABLE_BLOCK, 0, table, table_len)
+            .await
+            .map_err(|_| RegistrationError::DeviceError)?;
+        let status = kernel
+            .heap()
+            .allocate_array_with(|| BlockStatus::Idle, blocks)
+            .await;
+
+        let (cmd_prod, cmd_cons) = KChannel::new_async(kernel, REQUEST_QUEUE_DEPTH)
+            .await
+            .split();
+        kernel
+            .with_registry(|reg| reg.register_user_route(DriverKind::Block, &cmd_prod))
+            .await
+            .map_err(|_| RegistrationError::RouteAlreadyRegistered)?;
+
+        let commander = CommanderTask {
+            kernel,
+            cmd: cmd_cons,
+            storage,
+            capacity: device.capacity,
+            table,
+            status,
+        };
+        kernel
+            .spawn_named("UserBlock Commander", async move {
+                commander.run().await;
+            })
+            .await;
+
+        info!(blocks, capacity = device.capacity, "Registered UserBlock");
+        Ok(())
+    }
+}
+
+// impl CommanderTask
+
+impl CommanderTask {
+    async fn run(mut self) {
+        // Our request channel is closed when the kernel shuts down.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
+            let UserMessage { request, response } = msg;
+            let nonce = request.header.nonce;
+            let req = match request.body {
+                UserRequestBody::Block(req) => req,
+                _ => {
+                    warn!(nonce, "Routed a request that isn't for block storage");
+                    reply(&response, nonce, KernelResponseBody::NoDriver).await;
+                    continue;
+                }
+            };
+
+            let result = match req {
+                BlockRequest::StoreInfo => Ok(BlockResponse::StoreInfo(StoreInfo {
+                    blocks: self.status.len() as u32,
+                    capacity: self.capacity,
+                })),
+                BlockRequest::BlockInfo { block, name_buf } => {
+                    let res = self.block_info(block, &name_buf);
+                    with_buffer(&response, name_buf, res, |name_buf, info| {
+                        BlockResponse::BlockInfo {
+                            block,
+                            info,
+                            name_buf,
+                        }
+                    })
+                    .await
+                }
+                BlockRequest::Open { block } => self.open(block),
+                BlockRequest::Read {
+                    block,
+                    offset,
+                    buffer,
+                    len,
+                } => {
+                    let res = self.read(block, offset, &buffer, len).await;
+                    with_buffer(&response, buffer, res, |buffer, used| BlockResponse::Read {
+                        block,
+                        buffer,
+                        used,
+                    })
+                    .await
+                }
+                BlockRequest::Write {
+                    block,
+                    offset,
+                    buffer,
+                    used,
+                } => {
+                    let res = self.write(block, offset, &buffer, used).await;
+                    with_buffer(&response, buffer, res, |buffer, ()| {
+                        BlockResponse::Written { block, buffer }
+                    })
+                    .await
+                }
+                BlockRequest::Close {
+                    block,
+                    name,
+                    name_len,
+                    len,
+                    kind,
+                } => {
+                    let res = self.close(block, &name, name_len, len, kind).await;
+                    with_buffer(&response, name, res, |name, ()| BlockResponse::Closed {
+                        block,
+                        name,
+                    })
+                    .await
+                }
+            };
+            reply(&response, nonce, KernelResponseBody::Block(result)).await;
+        }
+        info!("UserBlock commander stopped");
+    }
+
+    fn block_info(&mut self, block: u32, name_buf: &ByteBoxWire) -> Result<BlockInfo, BlockError> {
+        let status = *self.status(block)?;
+        let entry = self.entry(block);
+
+        let kind = match entry[0] {
+            KIND_PROGRAM => BlockKind::Program,
+            KIND_STORAGE => BlockKind::Storage,
+            _ => BlockKind::Unused,
+        };
+        let name = match entry[1] {
+            NO_NAME => None,
+            len => entry[8..].get(..usize::from(len)),
+        };
+        // A block that was never closed has no length
+        let length = match kind {
+            BlockKind::Unused => 0,
+            _ => u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]).min(self.capacity),
+        };
+
+        if let Some(name) = name {
+            if name.len() > name_buf.len {
+                return Err(BlockError::OutOfBounds);
+            }
+            // SAFETY: Userspace lends us the buffer until we give it back, and
+            // we have checked that the name fits.
+            let dest = unsafe { slice::from_raw_parts_mut(name_buf.ptr as *mut u8, name.len()) };
+            dest.copy_from_slice(name);
+        }
+
+        Ok(BlockInfo {
+            length,
+            capacity: self.capacity,
+            kind,
+            status,
+            name_len: name.map(|name| name.len()),
+        })
+    }
+
+    fn open(&mut self, block: u32) -> Result<BlockResponse, BlockError> {
+        let status = self.status(block)?;
+        if *status != BlockStatus::Idle {
+            return Err(BlockError::AlreadyOpen);
+        }
+        *status = BlockStatus::OpenNoWrites;
+        info!(block, "Opened block");
+        Ok(BlockResponse::Opened { block })
+    }
+
+    async fn read(
+        &mut self,
+        block: u32,
+        offset: u32,
+        buffer: &ByteBoxWire,
+        len: usize,
+    ) -> Result<usize, BlockError> {
+        self.open_status(block)?;
+        if offset & 0b11 != 0 {
+            return Err(BlockError::Unaligned);
+        }
+        if offset > self.capacity || len > buffer.len {
+            return Err(BlockError::OutOfBounds);
+        }
+        let used = len.min((self.capacity - offset) as usize);
+        if used == 0 {
+            return Ok(0);
+        }
+
+        let buf = self.kernel.heap().allocate_array_with(|| 0, used).await;
+        let buf = self
+            .storage
+            .read(block + 1, offset, buf, used)
+            .await
+            .map_err(device_error)?;
+        // SAFETY: Userspace lends us the buffer until we give it back, and
+        // `used` is within the buffer.
+        let dest = unsafe { slice::from_raw_parts_mut(buffer.ptr as *mut u8, used) };
+        dest.copy_from_slice(&buf);
+        Ok(used)
+    }
+
+    async fn write(
+        &mut self,
+        block: u32,
+        offset: u32,
+        buffer: &ByteBoxWire,
+        used: usize,
+    ) -> Result<(), BlockError> {
+        let status = *self.open_status(block)?;
+        if offset & 0b11 != 0 {
+            return Err(BlockError::Unaligned);
+        }
+        if used > buffer.len || u64::from(offset) + used as u64 > u64::from(self.capacity) {
+            return Err(BlockError::OutOfBounds);
+        }
+
+        // The first write after opening erases the block
+        if status == BlockStatus::OpenNoWrites {
+            self.storage.erase(block + 1).await.map_err(device_error)?;
+            *self.open_status(block)? = BlockStatus::OpenWritten;
+            info!(block, "Erased block");
+        }
+        if used == 0 {
+            return Ok(());
+        }
+
+        let mut buf = self.kernel.heap().allocate_array_with(|| 0, used).await;
+        // SAFETY: Userspace lends us the buffer until we give it back, and we
+        // have checked that `used` is within the buffer.
+        buf.copy_from_slice(unsafe { slice::from_raw_parts(buffer.ptr as *const u8, used) });
+        self.storage
+            .write(block + 1, offset, buf, used)
+            .await
+            .map_err(device_error)?;
+        Ok(())
+    }
+
+    async fn close(
+        &mut self,
+        block: u32,
+        name: &ByteBoxWire,
+        name_len: usize,
+        len: u32,
+        kind: BlockKind,
+    ) -> Result<(), BlockError> {
+        self.open_status(block)?;
+        if name_len > MAX_NAME_LEN || name_len > name.len {
+            return Err(BlockError::InvalidName);
+        }
+        // SAFETY: Userspace lends us the buffer until we give it back, and we
+        // have checked that `name_len` is within the buffer.
+        let name_bytes = unsafe { slice::from_raw_parts(name.ptr as *const u8, name_len) };
+        if core::str::from_utf8(name_bytes).is_err() {
+            return Err(BlockError::InvalidName);
+        }
+        if len > self.capacity {
+            return Err(BlockError::OutOfBounds);
+        }
+
+        let entry = self.entry_mut(block);
+        entry.fill(ERASED);
+        entry[0] = match kind {
+            BlockKind::Program => KIND_PROGRAM,
+            BlockKind::Storage => KIND_STORAGE,
+            BlockKind::Unused => ERASED,
+        };
+        // An empty name leaves the block unnamed
+        if name_len > 0 {
+            entry[1] = name_len as u8;
+            entry[8..][..name_len].copy_from_slice(name_bytes);
+        }
+        entry[4..8].copy_from_slice(&len.to_le_bytes());
+        self.write_table().await?;
+
+        *self.open_status(block)? = BlockStatus::Idle;
+        info!(block, len, ?kind, "Closed block");
+        Ok(())
+    }
+
+    /// Write the metadata table back to the device.
+    async fn write_table(&mut self) -> Result<(), BlockError> {
+        let len = self.table.len();
+        let mut buf = self.kernel.heap().allocate_array_with(|| 0, len).await;
+        buf.copy_from_slice(&self.table);
+        self.storage
+            .erase(TABLE_BLOCK)
+            .await
+            .map_err(device_error)?;
+        self.storage
+            .write(TABLE_BLOCK, 0, buf, len)
+            .await
+            .map_err(device_error)?;
+        Ok(())
+    }
+
+    fn status(&mut self, block: u32) -> Result<&mut BlockStatus, BlockError> {
+        self.status
+            .get_mut(block as usize)
+            .ok_or(BlockError::NoSuchBlock)
+    }
+
+    fn open_status(&mut self, block: u32) -> Result<&mut BlockStatus, BlockError> {
+        match self.status(block)? {
+            BlockStatus::Idle => Err(BlockError::NotOpen),
+            status => Ok(status),
+        }
+    }
+
+    /// The metadata of `block`, which must exist
+    fn entry(&self, block: u32) -> &[u8] {
+        &self.table[block as usize * ENTRY_SIZE..][..ENTRY_SIZE]
+    }
+
+    fn entry_mut(&mut self, block: u32) -> &mut [u8] {
+        &mut self.table[block as usize * ENTRY_SIZE..][..ENTRY_SIZE]
+    }
+}
+
+/// Answer a request that lent us `buffer`, giving it back in the response made
+/// by `f` if the request succeeded, or on its own if it failed.
+async fn with_buffer<T>(
+    response: &KProducer<KernelMsg>,
+    buffer: ByteBoxWire,
+    result: Result<T, BlockError>,
+    f: impl FnOnce(ByteBoxWire, T) -> BlockResponse,
+) -> Result<BlockResponse, BlockError> {
+    match result {
+        Ok(t) => Ok(f(buffer, t)),
+        Err(e) => {
+            return_buffer(response, buffer).await;
+            Err(e)
+        }
+    }
+}
+
+fn device_error(error: BlockStorageError) -> BlockError {
+    warn!(?error, "Block storage request failed");
+    match error {
+        BlockStorageError::NoSuchBlock => BlockError::NoSuchBlock,
+        BlockStorageError::OutOfBounds => BlockError::OutOfBounds,
+        BlockStorageError::DeviceError | BlockStorageError::NoResponse => BlockError::DeviceError,
+    }
+}
@@ -0,0 +1,435 @@
+//! Serial ports for userspace
+//!
+//! [UserSerial] handles the [SerialRequest]s sent by userspace, giving it
+//! access to the ports of the [SerialMux](super::serial_mux::SerialMux).
+//!
+//! Data is exchanged using buffers owned by userspace, which are lent to the
+//! kernel as a [ByteBoxWire] for the duration of a request. Every buffer is
+//! given back, either in the response to the request, or with a
+//! [KernelMsg::Dealloc] if the request failed, or the port was closed before
+//! the buffer was filled.
+//!
+//! The mux has no way to unregister a port, so a port is opened on the mux the
+//! first time userspace opens it, and reused if userspace opens it again. Data
+//! that arrives while the port is closed is discarded.
+//!
+//! If data arrives on an open port with no receive buffer waiting for it, a
+//! [SerialEvent::DataAvailable] event is published, so that userspace knows
+//! to provide one.
+
+use core::{
+    slice,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use abi::syscall::{
+    event::KernelEvent,
+    serial::{SerialError, SerialEvent, SerialRequest, SerialResponse},
+    ByteBoxWire, DriverKind, KernelMsg, KernelResponseBody, UserRequestBody,
+};
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use maitake::wait::WaitQueue;
+use mnemos_alloc::containers::{HeapArc, HeapFixedVec};
+use spitebuf::EnqueueError;
+use tracing::{info, warn};
+
+use crate::{
+    comms::{
+        bbq::GrantR,
+        kchannel::{KChannel, KConsumer, KProducer},
+    },
+    drivers::{
+        reply, return_buffer,
+        serial_mux::{PortHandle, SerialMuxHandle},
+    },
+    Kernel, UserMessage,
+};
+
+/// The number of userspace requests that can be waiting to be handled
+const REQUEST_QUEUE_DEPTH: usize = 16;
+/// The number of receive buffers that can be waiting for data on each port
+const READS_PER_PORT: usize = 4;
+
+/// UserSerial is the driver for userspace serial ports
+pub struct UserSerial {
+    _inner: (),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+    MuxNotFound,
+    RouteAlreadyRegistered,
+}
+
+/// A receive buffer lent to the kernel by userspace
+struct PendingRead {
+    /// The session of the port when the buffer was provided
+    session: u32,
+    nonce: u32,
+    buffer: ByteBoxWire,
+    response: KProducer<KernelMsg>,
+}
+
+/// State shared between the commander and the reader of a port
+struct PortState {
+    handle: PortHandle,
+    /// Incremented every time the port is opened or closed, so that the reader
+    /// can tell whether a buffer was provided before the port was closed. Even
+    /// while the port is open, odd while it is closed.
+    session: AtomicU32,
+    /// Woken when the port is closed
+    closed: WaitQueue,
+}
+
+struct PortEntry {
+    state: HeapArc<PortState>,
+    reads: KProducer<PendingRead>,
+    open: bool,
+}
+
+struct CommanderTask {
+    kernel: &'static Kernel,
+    cmd: KConsumer<UserMessage>,
+    mux: SerialMuxHandle,
+    ports: HeapFixedVec<PortEntry>,
+    port_capacity: usize,
+}
+
+struct ReaderTask {
+    kernel: &'static Kernel,
+    state: HeapArc<PortState>,
+    reads: KConsumer<PendingRead>,
+}
+
+// impl UserSerial
+
+impl UserSerial {
+    /// Register the driver, giving userspace access to up to `max_ports` ports
+    /// of the serial mux, each buffering up to `port_capacity` bytes of
+    /// incoming data.
+    ///
+    /// The serial mux must already be registered. Ports that are opened by the
+    /// kernel can not be opened by userspace.
+    pub async fn register(
+        kernel: &'static Kernel,
+        max_ports: usize,
+        port_capacity: usize,
+    ) -> Result<(), RegistrationError> {
+        let mux = SerialMuxHandle::from_registry(kernel)
+            .await
+            .ok_or(RegistrationError::MuxNotFound)?;
+        let ports = kernel.heap().allocate_fixed_vec(max_ports).await;
+        let (cmd_prod, cmd_cons) = KChannel::new_async(kernel, REQUEST_QUEUE_DEPTH)
+            .await
+            .split();
+
+        kernel
+            .with_registry(|reg| reg.register_user_route(DriverKind::Serial, &cmd_prod))
+            .await
+            .map_err(|_| RegistrationError::RouteAlreadyRegistered)?;
+
+        let commander = CommanderTask {
+            kernel,
+            cmd: cmd_cons,
+            mux,
+            ports,
+            port_capacity,
+        };
+        kernel
+            .spawn_named("UserSerial Commander", async move {
+                commander.run().await;
+            })
+            .await;
+
+        Ok(())
+    }
+}
+
+// impl CommanderTask
+
+impl CommanderTask {
+    async fn run(mut self) {
+        // Our request channel is closed when the kernel shuts down. Once all
+        // pending requests have been handled, stop.
+        while let Ok(msg) = self.cmd.dequeue_async().await {
+            let UserMessage { request, response } = msg;
+            let nonce = request.header.nonce;
+            let req = match request.body {
+                UserRequestBody::Serial(req) => req,
+                _ => {
+                    warn!(nonce, "Routed a request that isn't for serial ports");
+                    reply(&response, nonce, KernelResponseBody::NoDriver).await;
+                    continue;
+                }
+            };
+
+            let result = match req {
+                SerialRequest::OpenPort { port } => self.open(port).await,
+                SerialRequest::ProvideReceiveBuffer { port, buffer } => {
+                    match self.provide_buffer(port, nonce, buffer, &response).await {
+                        // The response is sent by the port's reader, once data arrives
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
+                    }
+                }
+                SerialRequest::SendData { port, buffer, used } => {
+                    self.send(port, buffer, used, &response).await
+                }
+                SerialRequest::Flush { port } => self.flush(port),
+                SerialRequest::ClosePort { port } => self.close(port),
+            };
+            reply(&response, nonce, KernelResponseBody::Serial(result)).await;
+        }
+        info!("UserSerial commander stopped");
+    }
+
+    async fn open(&mut self, port: u16) -> Result<SerialResponse, SerialError> {
+        if let Some(entry) = self
+            .ports
+            .iter_mut()
+            .find(|p| p.state.handle.port() == port)
+        {
+            if entry.open {
+                return Err(SerialError::AlreadyOpen);
+            }
+
+            // Discard anything that arrived while the port was closed
+            let cons = entry.state.handle.consumer();
+            while let Some(rgr) = cons.read_grant_sync() {
+                let len = rgr.len();
+                rgr.release(len);
+            }
+            entry.state.session.fetch_add(1, Ordering::AcqRel);
+            entry.open = true;
+            info!(port, "Reopened port");
+            return Ok(SerialResponse::OpenPort { port });
+        }
+
+        if self.ports.is_full() {
+            warn!(port, "Too many open ports");
+            return Err(SerialError::PortUnavailable);
+        }
+        let handle = self
+            .mux
+            .open_port(port, self.port_capacity)
+            .await
+            .ok_or(SerialError::PortUnavailable)?;
+
+        let kernel = self.kernel;
+        let state = kernel
+            .heap()
+            .allocate_arc(PortState {
+                handle,
+                session: AtomicU32::new(0),
+                closed: WaitQueue::new(),
+            })
+            .await;
+        let (reads, reads_cons) = KChannel::new_async(kernel, READS_PER_PORT).await.split();
+        let reader = ReaderTask {
+            kernel,
+            state: state.clone(),
+            reads: reads_cons,
+        };
+        kernel
+            .spawn_named("UserSerial Reader", async move {
+                reader.run().await;
+            })
+            .await;
+
+        self.ports
+            .push(PortEntry {
+                state,
+                reads,
+                open: true,
+            })
+            .map_err(|_| SerialError::PortUnavailable)?;
+        info!(port, "Opened port");
+        Ok(SerialResponse::OpenPort { port })
+    }
+
+    async fn provide_buffer(
+        &mut self,
+        port: u16,
+        nonce: u32,
+        buffer: ByteBoxWire,
+        response: &KProducer<KernelMsg>,
+    ) -> Result<(), SerialError> {
+        let entry = match self.open_entry(port) {
+            Ok(entry) if buffer.len > 0 => entry,
+            Ok(_) => {
+                return_buffer(response, buffer).await;
+                return Err(SerialError::Unknown);
+            }
+            Err(e) => {
+                return_buffer(response, buffer).await;
+                return Err(e);
+            }
+        };
+
+        let read = PendingRead {
+            session: entry.state.session.load(Ordering::Acquire),
+            nonce,
+            buffer,
+            response: response.clone(),
+        };
+        // Don't wait for room, the reader may be waiting for data that never
+        // comes, and we still need to handle other requests.
+        match entry.reads.enqueue_sync(read) {
+            Ok(()) => Ok(()),
+            Err(EnqueueError::Full(read)) | Err(EnqueueError::Closed(read)) => {
+                return_buffer(response, read.buffer).await;
+                Err(SerialError::Busy)
+            }
+        }
+    }
+
+    async fn send(
+        &mut self,
+        port: u16,
+        buffer: ByteBoxWire,
+        used: usize,
+        response: &KProducer<KernelMsg>,
+    ) -> Result<SerialResponse, SerialError> {
+        let entry = match self.open_entry(port) {
+            Ok(entry) if used <= buffer.len => entry,
+            Ok(_) => {
+                return_buffer(response, buffer).await;
+                return Err(SerialError::Unknown);
+            }
+            Err(e) => {
+                return_buffer(response, buffer).await;
+                return Err(e);
+            }
+        };
+
+        if used > 0 {
+            // SAFETY: Userspace lends us the buffer until we give it back, and
+            // we have checked that `used` is within the buffer.
+            let data = unsafe { slice::from_raw_parts(buffer.ptr as *const u8, used) };
+            entry.state.handle.send(data).await;
+        }
+        Ok(SerialResponse::SendComplete { port, buffer })
+    }
+
+    /// Data is handed to the mux as soon as it is sent, so there is nothing
+    /// to wait for.
+    fn flush(&mut self, port: u16) -> Result<SerialResponse, SerialError> {
+        self.open_entry(port)?;
+        Ok(SerialResponse::FlushAck { port })
+    }
+
+    fn close(&mut self, port: u16) -> Result<SerialResponse, SerialError> {
+        let entry = self.open_entry(port)?;
+        entry.open = false;
+        // Any buffers still waiting for data are returned by the reader
+        entry.state.session.fetch_add(1, Ordering::AcqRel);
+        entry.state.closed.wake_all();
+        info!(port, "Closed port");
+        Ok(SerialResponse::ClosePort { port })
+    }
+
+    fn open_entry(&mut self, port: u16) -> Result<&mut PortEntry, SerialError> {
+        self.ports
+            .iter_mut()
+            .find(|p| p.open && p.state.handle.port() == port)
+            .ok_or(SerialError::NotOpen)
+    }
+}
+
+// impl ReaderTask
+
+impl ReaderTask {
+    async fn run(self) {
+        let kernel = self.kernel;
+        let port = self.state.handle.port();
+
+        // Our channel is closed when the commander stops
+        while let Some(Some(read)) = kernel.until_shutdown(self.next_read()).await {
+            let PendingRead {
+                session,
+                nonce,
+                buffer,
+                response,
+            } = read;
+
+            let rgr = match kernel.until_shutdown(self.read_grant(session)).await {
+                Some(Some(rgr)) => rgr,
+                // The port was closed, or the kernel is shutting down
+                _ => {
+                    return_buffer(&response, buffer).await;
+                    continue;
+                }
+            };
+
+            let used = rgr.len().min(buffer.len);
+            // SAFETY: Userspace lends us the buffer until we give it back, and
+            // `used` is within the buffer.
+            let dest = unsafe { slice::from_raw_parts_mut(buffer.ptr as *mut u8, used) };
+            dest.copy_from_slice(&rgr[..used]);
+            rgr.release(used);
+
+            let resp = SerialResponse::ReceiveData { port, buffer, used };
+            reply(&response, nonce, KernelResponseBody::Serial(Ok(resp))).await;
+        }
+        info!(port, "UserSerial reader stopped");
+    }
+
+    /// Wait for the next receive buffer.
+    ///
+    /// If data arrives on the open port first, userspace is told, once each
+    /// time the port is opened. Data that arrives while the port is closed is
+    /// discarded.
+    async fn next_read(&self) -> Option<PendingRead> {
+        let port = self.state.handle.port();
+        loop {
+            let read = self.reads.dequeue_async();
+            let data = self.state.handle.consumer().read_grant();
+            pin_mut!(read, data);
+
+            let rgr = match select(read, data).await {
+                Either::Left((read, _)) => return read.ok(),
+                Either::Right((rgr, _)) => rgr,
+            };
+            let len = rgr.len();
+            if self.state.session.load(Ordering::Acquire) & 1 == 1 {
+                rgr.release(len);
+                continue;
+            }
+
+            // Leave the data for the next receive buffer
+            rgr.release(0);
+            let event = KernelEvent::Serial(SerialEvent::DataAvailable { port, len });
+            if self.kernel.publish_event(event).is_err() {
+                warn!(port, len, "Too many events waiting, dropped DataAvailable");
+            }
+
+            // Don't announce the same data again, unless the port is closed
+            // and reopened first
+            let read = self.reads.dequeue_async();
+            let closed = self.state.closed.wait();
+            pin_mut!(read, closed);
+            if let Either::Left((read, _)) = select(read, closed).await {
+                return read.ok();
+            }
+        }
+    }
+
+    /// Wait for incoming data, unless the port is closed first.
+    async fn read_grant(&self, session: u32) -> Option<GrantR> {
+        // Closing the port can only happen while we are waiting, so checking
+        // the session once per wakeup is enough.
+        while self.state.session.load(Ordering::Acquire) == session {
+            let grant = self.state.handle.consumer().read_grant();
+            let closed = self.state.closed.wait();
+            pin_mut!(grant, closed);
+
+            if let Either::Left((rgr, _)) = select(grant, closed).await {
+                return Some(rgr);
+            }
+        }
+        None
+    }
+}
@@ -5,24 +5,51 @@ pub mod comms;
 pub mod drivers;
 pub(crate) mod fmt;
 pub mod registry;
+pub mod tasks;
+pub mod timer;
 
 use abi::{
     bbqueue_ipc::{
         framed::{FrameConsumer, FrameProducer},
         BBBuffer,
     },
-    syscall::{KernelResponse, UserRequest},
+    syscall::{
+        event::KernelEvent, KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader,
+        UserRequest, UserRequestBody, UserRequestHeader,
+    },
+};
+use comms::{
+    kchannel::{KChannel, KConsumer, KProducer},
+    oneshot::Reusable,
+};
+use futures::{
+    future::{select, Either},
+    pin_mut,
 };
-use comms::kchannel::KChannel;
 use maitake::{
     self,
     scheduler::{StaticScheduler, TaskStub},
     task::Storage,
+    wait::WaitQueue,
 };
 use maitake::{sync::Mutex, task::Task as MaitakeTask};
-use mnemos_alloc::{containers::HeapBox, heap::AHeap};
-use registry::Registry;
-use tracing::info;
+use mnemos_alloc::{
+    containers::{HeapArc, HeapBox},
+    heap::{AHeap, HeapGuard},
+};
+use postcard::experimental::max_size::MaxSize;
+use registry::{Registry, RouteError};
+use spitebuf::EnqueueError;
+use tasks::{JoinHandle, TaskId, TaskList, TaskStats, Tracked};
+use timer::{Instant, Timer};
+use tracing::{debug, info, trace, warn};
+
+/// The kernel is running normally
+const KERNEL_RUNNING: u8 = 0;
+/// Shutdown has been requested, but driver services have not yet been closed
+const KERNEL_SHUTDOWN_REQUESTED: u8 = 1;
+/// Shutdown has been requested, and all driver services have been closed
+const KERNEL_SERVICES_CLOSED: u8 = 2;
 
 pub struct Rings {
     pub u2k: NonNull<BBBuffer>,
@@ -33,13 +60,73 @@ pub struct KernelSettings {
     pub heap_start: *mut u8,
     pub heap_size: usize,
     pub max_drivers: usize,
+    pub max_tasks: usize,
+    /// The length of a single tick of the platform's timer, see [Timer::pend_ticks]
+    pub timer_granularity: Duration,
+    /// The number of events and driver responses that can be waiting to be
+    /// sent to userspace, see [Kernel::publish_event]
+    pub max_pending_messages: usize,
     pub k2u_size: usize,
     pub u2k_size: usize,
 }
 
-pub struct Message {
+/// Counters for messages from userspace that the kernel could not handle
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MessageStats {
+    /// Requests that could not be decoded, and were dropped
+    pub malformed_requests: u32,
+    /// Error responses sent for malformed requests with a recoverable nonce
+    pub error_replies: u32,
+    /// Error responses that could not be sent, because the k2u ring was full
+    pub dropped_replies: u32,
+    /// Requests for which no driver was registered
+    pub unrouted_requests: u32,
+}
+
+/// What happened during a call to [Kernel::tick].
+///
+/// Platforms use this to decide whether to run userspace, and whether they
+/// can sleep until the next interrupt.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TickOutcome {
+    /// Kernel tasks are still ready to run, or requests from userspace are
+    /// still waiting to be routed. [Kernel::tick] should be called again
+    /// without sleeping.
+    pub has_remaining: bool,
+    /// Userspace has not told the kernel that it is waiting, or the kernel
+    /// has since sent it a message, so it should be run.
+    pub userspace_ready: bool,
+    /// The earliest time that a kernel task or userspace wants to be woken.
+    pub next_deadline: Option<Instant>,
+    /// Userspace has halted with this exit code, and will never run again.
+    ///
+    /// The kernel keeps running. Whether to shut it down, restart userspace,
+    /// or carry on without it, is up to the platform.
+    pub userspace_halted: Option<i32>,
+}
+
+/// Is userspace running, or waiting for the kernel?
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum UserState {
+    Running,
+    /// Userspace sent [UserRequestBody::Wait], and the kernel has not sent it
+    /// anything since
+    Waiting {
+        until: Option<Instant>,
+    },
+    /// Userspace sent [UserRequestBody::Halt], and will never run again
+    Halted {
+        code: i32,
+    },
+}
+
+/// A request from userspace, routed to the driver registered for its
+/// [DriverKind](abi::syscall::DriverKind), see
+/// [Registry::register_user_route].
+pub struct UserMessage {
     pub request: UserRequest,
-    pub response: KChannel<KernelResponse>,
+    /// Messages sent here are forwarded to userspace by [Kernel::tick]
+    pub response: KProducer<KernelMsg>,
 }
 
 pub struct Kernel {
@@ -48,6 +135,8 @@ pub struct Kernel {
     inner: KernelInner,
     /// The run-time driver registry, accessed via an async Mutex
     registry: Mutex<Registry>,
+    /// The list of spawned tasks, accessed via an async Mutex
+    tasks: Mutex<TaskList>,
     heap: NonNull<AHeap>,
 }
 
@@ -57,6 +146,20 @@ pub struct KernelInner {
     u2k_ring: BBBuffer,
     k2u_ring: BBBuffer,
     scheduler: StaticScheduler,
+    task_ctr: AtomicU32,
+    shutdown_state: AtomicU8,
+    shutdown_wait: WaitQueue,
+    timer: Timer,
+    malformed_requests: AtomicU32,
+    error_replies: AtomicU32,
+    dropped_replies: AtomicU32,
+    unrouted_requests: AtomicU32,
+    k2u_tx: KProducer<KernelMsg>,
+    k2u_rx: KConsumer<KernelMsg>,
+    /// The number of messages written to the k2u ring (wrapping)
+    k2u_sent: AtomicU32,
+    /// Only accessed from [Kernel::tick]
+    user_state: Cell<UserState>,
 }
 
 impl Kernel {
@@ -70,6 +173,8 @@ impl Kernel {
             .map_err(|_| "failed to initialize heap")?;
 
         let registry = registry::Registry::new(&mut guard, settings.max_drivers);
+        let tasks = TaskList::new(&mut guard, settings.max_tasks);
+        let (k2u_tx, k2u_rx) = KChannel::new(&mut guard, settings.max_pending_messages).split();
         let (nn_u2k_buf, u2k_len) = guard
             .alloc_box_array_with(|| 0, settings.u2k_size)
             .map_err(|_| "failed to allocate u2k ring buf")?
@@ -103,12 +208,25 @@ impl Kernel {
             u2k_ring,
             k2u_ring,
             scheduler,
+            task_ctr: AtomicU32::new(0),
+            shutdown_state: AtomicU8::new(KERNEL_RUNNING),
+            shutdown_wait: WaitQueue::new(),
+            timer: Timer::new(settings.timer_granularity),
+            malformed_requests: AtomicU32::new(0),
+            error_replies: AtomicU32::new(0),
+            dropped_replies: AtomicU32::new(0),
+            unrouted_requests: AtomicU32::new(0),
+            k2u_tx,
+            k2u_rx,
+            k2u_sent: AtomicU32::new(0),
+            user_state: Cell::new(UserState::Running),
         };
 
         let new_kernel = guard
             .alloc_box(Kernel {
                 inner,
                 registry: Mutex::new(registry),
+                tasks: Mutex::new(tasks),
                 heap: nn_heap,
             })
             .map_err(|_| "failed to allocate new kernel box")?;
@@ -129,68 +247,379 @@ impl Kernel {
         }
     }
 
+    /// Tell userspace where to find the syscall rings, and its heap.
+    ///
+    /// The locations are stored in [abi::SYSCALL_RINGS], [abi::HEAP_PTR] and
+    /// [abi::HEAP_LEN], and must be set before userspace starts.
+    ///
+    /// Safety: The given heap region must be valid, and must only be used by
+    /// userspace.
+    pub unsafe fn init_userspace(&'static self, heap_start: *mut u8, heap_len: usize) {
+        let rings = self.rings();
+        abi::SYSCALL_RINGS
+            .user_to_kernel
+            .store(rings.u2k.as_ptr(), Ordering::Release);
+        abi::SYSCALL_RINGS
+            .kernel_to_user
+            .store(rings.k2u.as_ptr(), Ordering::Release);
+        abi::HEAP_PTR.store(heap_start, Ordering::Release);
+        abi::HEAP_LEN.store(heap_len, Ordering::Release);
+    }
+
     pub fn heap(&'static self) -> &'static AHeap {
         unsafe { self.heap.as_ref() }
     }
 
-    pub fn tick(&'static self) {
+    /// The kernel's timer, used to sleep, and by the platform to report the
+    /// passage of time.
+    pub fn timer(&'static self) -> &'static Timer {
+        &self.inner.timer
+    }
+
+    /// Run the kernel once.
+    ///
+    /// This applies pending timer ticks, handles messages from userspace,
+    /// polls kernel tasks, and forwards responses and events to userspace.
+    /// The returned [TickOutcome] tells the platform whether there is more
+    /// work to do, or whether it may sleep. This must never be called
+    /// concurrently with itself.
+    pub fn tick(&'static self) -> TickOutcome {
         // Process heap allocations
         self.heap().poll();
 
-        // process mailbox messages
+        // Apply any ticks reported by the platform, waking sleeping tasks
         let inner = self.inner();
+        let time_advanced = inner.timer.advance();
+        let was_waiting = matches!(inner.user_state.get(), UserState::Waiting { .. });
+
+        // process mailbox messages
         let u2k_buf: *mut BBBuffer = &self.inner.u2k_ring as *const _ as *mut _;
         let k2u_buf: *mut BBBuffer = &self.inner.k2u_ring as *const _ as *mut _;
         let u2k: FrameConsumer<'static> = unsafe { BBBuffer::take_framed_consumer(u2k_buf) };
-        let _k2u: FrameProducer<'static> = unsafe { BBBuffer::take_framed_producer(k2u_buf) };
+        let k2u: FrameProducer<'static> = unsafe { BBBuffer::take_framed_producer(k2u_buf) };
 
-        #[allow(unreachable_code)]
-        if let Some(mut _reg) = self.registry.try_lock() {
+        let mut requests_remaining = false;
+        if let Some(mut reg) = self.registry.try_lock() {
             // Incoming messages
             while let Some(msg) = u2k.read() {
-                match postcard::from_bytes::<UserRequest>(&msg) {
-                    Ok(_req) => {
-                        // let kind = req.driver_kind();
-                        // if let Some(drv) = inner_mut.drivers.iter().find(|drv| drv.kind == kind) {
-                        //     drv.queue
-                        //         .enqueue_sync(Message {
-                        //             request: req,
-                        //             response: inner.user_reply.clone(),
-                        //         })
-                        //         .map_err(drop)
-                        //         .unwrap();
-                        // }
-                        todo!("Driver registry");
+                let request = postcard::from_bytes::<UserRequest>(&msg);
+
+                // Any message other than a wait means userspace is running
+                if !matches!(
+                    request,
+                    Ok(UserRequest {
+                        body: UserRequestBody::Wait { .. },
+                        ..
+                    })
+                ) {
+                    inner.user_running();
+                }
+
+                match request {
+                    Ok(UserRequest {
+                        body: UserRequestBody::Cancel { nonce },
+                        ..
+                    }) => {
+                        // TODO: Drivers can't cancel requests yet, so the
+                        // request still completes, and userspace discards
+                        // the response.
+                        debug!(nonce, "Userspace cancelled request");
                     }
-                    Err(_) => panic!(),
+                    Ok(UserRequest {
+                        body: UserRequestBody::Halt { code },
+                        ..
+                    }) => {
+                        if code == 0 {
+                            info!(code, "Userspace halted");
+                        } else {
+                            warn!(code, "Userspace halted with an error");
+                        }
+                        inner.user_state.set(UserState::Halted { code });
+                    }
+                    Ok(UserRequest {
+                        body: UserRequestBody::Wait { until, received },
+                        ..
+                    }) => inner.user_wait(until.map(Instant::from_micros), received),
+                    Ok(req) => {
+                        let msg = UserMessage {
+                            request: req,
+                            response: inner.k2u_tx.clone(),
+                        };
+                        match reg.route_user(msg) {
+                            Ok(()) => {}
+                            // Leave the request in the ring, and try again
+                            // once the driver has caught up.
+                            Err(RouteError::QueueFull(_)) => {
+                                requests_remaining = true;
+                                break;
+                            }
+                            Err(RouteError::NoRoute(msg)) | Err(RouteError::Closed(msg)) => {
+                                inner.reject_unrouted(&msg.request, &k2u)
+                            }
+                        }
+                    }
+                    Err(_) => inner.reject_malformed(&msg, &k2u),
                 }
                 msg.release();
             }
+
+            // If a shutdown has been requested, close all driver services
+            if inner.shutdown_state.load(Ordering::Acquire) == KERNEL_SHUTDOWN_REQUESTED {
+                reg.close_all();
+                inner
+                    .shutdown_state
+                    .store(KERNEL_SERVICES_CLOSED, Ordering::Release);
+            }
+        } else {
+            requests_remaining = u2k.read().is_some();
         }
 
-        inner.scheduler.tick();
+        let sched = inner.scheduler.tick();
+
+        // Forward any responses and events sent by drivers during this tick.
+        // Messages that don't fit in the ring wait for a later tick.
+        while let Ok(mut wgr) = k2u.grant(KernelMsg::POSTCARD_MAX_SIZE) {
+            let msg = match inner.k2u_rx.dequeue_sync() {
+                Some(msg) => msg,
+                None => break,
+            };
+            match postcard::to_slice(&msg, &mut wgr) {
+                Ok(used) => {
+                    let used = used.len();
+                    wgr.commit(used);
+                    inner.k2u_committed();
+                }
+                Err(_) => warn!("Failed to serialize message for userspace"),
+            }
+        }
+
+        // Let userspace know what time it is. If there is no room in the ring,
+        // skip it, a later tick will send a newer timestamp. While userspace
+        // is waiting, only wake it once its deadline has passed, and make sure
+        // it knows the time once it has been woken.
+        let now = inner.timer.now();
+        let send_time = match inner.user_state.get() {
+            UserState::Running => time_advanced || was_waiting,
+            UserState::Waiting { until } => matches!(until, Some(until) if until <= now),
+            UserState::Halted { .. } => false,
+        };
+        if send_time {
+            let _ = inner.send_k2u(&k2u, &KernelMsg::Timestamp(now.as_micros()));
+        }
+
+        let user_deadline = match inner.user_state.get() {
+            UserState::Waiting { until } => until,
+            _ => None,
+        };
+        let next_deadline = match (inner.timer.next_deadline(), user_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        TickOutcome {
+            has_remaining: sched.has_remaining || requests_remaining || inner.timer.has_pending(),
+            userspace_ready: inner.user_state.get() == UserState::Running,
+            next_deadline,
+            userspace_halted: match inner.user_state.get() {
+                UserState::Halted { code } => Some(code),
+                _ => None,
+            },
+        }
+    }
+
+    /// Publish an event to userspace, without a matching request.
+    ///
+    /// Events are sent on the next call to [Kernel::tick], and only delivered to
+    /// userspace tasks that have subscribed to them. Returns the event if too
+    /// many events are already waiting to be sent.
+    pub fn publish_event(&'static self, event: KernelEvent) -> Result<(), KernelEvent> {
+        self.inner
+            .k2u_tx
+            .enqueue_sync(KernelMsg::Event(event))
+            .map_err(|e| match e {
+                EnqueueError::Full(KernelMsg::Event(event))
+                | EnqueueError::Closed(KernelMsg::Event(event)) => event,
+                _ => unreachable!("only events are published"),
+            })
+    }
+
+    /// Counters for messages from userspace that could not be handled.
+    pub fn message_stats(&'static self) -> MessageStats {
+        MessageStats {
+            malformed_requests: self.inner.malformed_requests.load(Ordering::Relaxed),
+            error_replies: self.inner.error_replies.load(Ordering::Relaxed),
+            dropped_replies: self.inner.dropped_replies.load(Ordering::Relaxed),
+            unrouted_requests: self.inner.unrouted_requests.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Request that the kernel shut down.
+    ///
+    /// On the next call to [Kernel::tick], the request channels of every
+    /// registered driver service are closed. Tasks waiting in
+    /// [Kernel::wait_for_shutdown] or [Kernel::until_shutdown] are woken, so
+    /// that drivers can flush any pending data and stop their tasks.
+    ///
+    /// Once every tracked task has finished, [Kernel::is_shut_down] will return
+    /// true, and the platform may stop calling [Kernel::tick].
+    ///
+    /// This may be called from any context, including from outside of the
+    /// kernel's scheduler.
+    pub fn shutdown(&'static self) {
+        let swap = self.inner.shutdown_state.compare_exchange(
+            KERNEL_RUNNING,
+            KERNEL_SHUTDOWN_REQUESTED,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+
+        if swap.is_ok() {
+            info!("Kernel shutdown requested");
+            // Closing the wait queue wakes all current waiters, and causes any
+            // future waits to return immediately.
+            self.inner.shutdown_wait.close();
+        }
+    }
+
+    /// Has a shutdown been requested with [Kernel::shutdown]?
+    pub fn is_shutting_down(&'static self) -> bool {
+        self.inner.shutdown_state.load(Ordering::Acquire) != KERNEL_RUNNING
+    }
+
+    /// Has the kernel finished shutting down?
+    ///
+    /// This is true once all driver services have been closed, and all tracked
+    /// tasks have either completed or been cancelled.
+    pub fn is_shut_down(&'static self) -> bool {
+        self.inner.shutdown_state.load(Ordering::Acquire) == KERNEL_SERVICES_CLOSED
+            && self
+                .try_with_tasks(|tasks| tasks.live_tasks() == 0)
+                .unwrap_or(false)
+    }
+
+    /// Wait until a shutdown has been requested with [Kernel::shutdown].
+    pub async fn wait_for_shutdown(&'static self) {
+        while !self.is_shutting_down() {
+            let _ = self.inner.shutdown_wait.wait().await;
+        }
+    }
 
-        // TODO: Send time to userspace?
+    /// Run the given future until it completes, or until a shutdown has been
+    /// requested, whichever happens first.
+    ///
+    /// Returns `None` if the kernel began shutting down before the future
+    /// completed. This is useful for driver tasks that would otherwise loop
+    /// forever.
+    pub async fn until_shutdown<F: Future>(&'static self, fut: F) -> Option<F::Output> {
+        let shutdown = self.wait_for_shutdown();
+        pin_mut!(fut, shutdown);
+
+        match select(fut, shutdown).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
     }
 
-    // TODO: This prooooobably should instead use a joinhandle, and poll on the initialize future
-    // to completion, to make sure that certain actions actually complete.
-    pub fn initialize<F: Future + 'static>(&'static self, fut: F) -> Result<(), ()> {
-        let task = self.new_task(fut);
+    /// Spawn the kernel's initialization task.
+    ///
+    /// This is intended to be called before the first call to [Kernel::tick].
+    /// The returned [JoinHandle] can be used to tick the kernel until
+    /// initialization has actually completed.
+    pub fn initialize<F: Future + 'static>(
+        &'static self,
+        fut: F,
+    ) -> Result<JoinHandle<F::Output>, ()> {
         let mut guard = self.heap().lock().map_err(drop)?;
+        let (task, hdl) = self.new_task(&mut guard, Some("initialize"), fut)?;
         let task_box = guard.alloc_box(task).map_err(drop)?;
         self.spawn_allocated(task_box);
-        Ok(())
+        Ok(hdl)
+    }
+
+    /// Create a new task without spawning it.
+    ///
+    /// The task can later be spawned with [Kernel::spawn_allocated], which adds
+    /// it to the kernel's task list. Used for pre-async initialization steps.
+    pub fn new_task<F: Future + 'static>(
+        &'static self,
+        guard: &mut HeapGuard,
+        name: Option<&'static str>,
+        fut: F,
+    ) -> Result<(Task<F>, JoinHandle<F::Output>), ()> {
+        let stats = guard
+            .alloc_arc(TaskStats::new(self.next_task_id(), name))
+            .map_err(drop)?;
+        let rx = Reusable::try_new(guard)?;
+        Ok(self.wrap_task(fut, stats, rx))
     }
 
-    pub fn new_task<F: Future + 'static>(&'static self, fut: F) -> Task<F> {
-        Task(MaitakeTask::new(&self.inner.scheduler, fut))
+    /// Spawn an anonymous task onto the kernel's scheduler.
+    pub async fn spawn<F: Future + 'static>(&'static self, fut: F) -> JoinHandle<F::Output> {
+        self.spawn_inner(None, fut).await
     }
 
-    pub async fn spawn<F: Future + 'static>(&'static self, fut: F) {
-        let task = Task(MaitakeTask::new(&self.inner.scheduler, fut));
+    /// Spawn a named task onto the kernel's scheduler.
+    ///
+    /// The name is shown in the kernel's task list, see [Kernel::with_tasks].
+    pub async fn spawn_named<F: Future + 'static>(
+        &'static self,
+        name: &'static str,
+        fut: F,
+    ) -> JoinHandle<F::Output> {
+        self.spawn_inner(Some(name), fut).await
+    }
+
+    async fn spawn_inner<F: Future + 'static>(
+        &'static self,
+        name: Option<&'static str>,
+        fut: F,
+    ) -> JoinHandle<F::Output> {
+        let stats = self
+            .heap()
+            .allocate_arc(TaskStats::new(self.next_task_id(), name))
+            .await;
+        let rx = Reusable::new_async(self).await;
+        let (task, hdl) = self.wrap_task(fut, stats, rx);
+
         let atask = self.heap().allocate(task).await;
-        self.spawn_allocated(atask);
+        self.tasks.lock().await.track(&atask.stats);
+        self.schedule(atask);
+        hdl
+    }
+
+    fn wrap_task<F: Future + 'static>(
+        &'static self,
+        fut: F,
+        stats: HeapArc<TaskStats>,
+        rx: Reusable<F::Output>,
+    ) -> (Task<F>, JoinHandle<F::Output>) {
+        let (tracked, hdl) = Tracked::new(fut, stats.clone(), rx);
+        let task = Task {
+            task: MaitakeTask::new(&self.inner.scheduler, tracked),
+            stats,
+        };
+        (task, hdl)
+    }
+
+    /// Inspect the kernel's list of spawned tasks.
+    pub async fn with_tasks<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&TaskList) -> R,
+    {
+        let guard = self.tasks.lock().await;
+        f(&guard)
+    }
+
+    /// Inspect the kernel's list of spawned tasks from outside of the scheduler,
+    /// e.g. from the platform's main loop.
+    ///
+    /// Returns `None` if the task list is currently locked.
+    pub fn try_with_tasks<F, R>(&'static self, f: F) -> Option<R>
+    where
+        F: FnOnce(&TaskList) -> R,
+    {
+        let guard = self.tasks.try_lock()?;
+        Some(f(&guard))
     }
 
     pub async fn with_registry<F, R>(&'static self, f: F) -> R
@@ -201,28 +630,162 @@ impl Kernel {
         f(&mut guard)
     }
 
+    /// Inspect the driver registry from outside of the scheduler, e.g. from
+    /// the platform's main loop.
+    ///
+    /// Returns `None` if the registry is currently locked.
+    pub fn try_with_registry<F, R>(&'static self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Registry) -> R,
+    {
+        let mut guard = self.registry.try_lock()?;
+        Some(f(&mut guard))
+    }
+
+    /// Spawn a task created with [Kernel::new_task], adding it to the kernel's
+    /// task list.
     pub fn spawn_allocated<F: Future + 'static>(&'static self, task: HeapBox<Task<F>>) {
-        self.inner.scheduler.spawn_allocated::<F, HBStorage>(task)
+        match self.tasks.try_lock() {
+            Some(mut tasks) => tasks.track(&task.stats),
+            None => warn!(
+                name = ?task.stats.name(),
+                "Task list busy, task will not be tracked"
+            ),
+        }
+        self.schedule(task);
+    }
+
+    fn schedule<F: Future + 'static>(&'static self, task: HeapBox<Task<F>>) {
+        self.inner
+            .scheduler
+            .spawn_allocated::<Tracked<F>, HBStorage>(task)
+    }
+
+    fn next_task_id(&'static self) -> TaskId {
+        TaskId(self.inner.task_ctr.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl KernelInner {
+    /// Drop a request from userspace that could not be decoded.
+    ///
+    /// If the nonce of the request can be recovered, userspace is told that
+    /// the request was malformed, rather than leaving it waiting forever.
+    fn reject_malformed(&self, msg: &[u8], k2u: &FrameProducer<'static>) {
+        self.malformed_requests.fetch_add(1, Ordering::Relaxed);
+
+        let header = match UserRequestHeader::recover(msg) {
+            Some(header) => header,
+            None => {
+                warn!(len = msg.len(), "Dropped malformed request from userspace");
+                return;
+            }
+        };
+        warn!(
+            nonce = header.nonce,
+            len = msg.len(),
+            "Dropped malformed request from userspace"
+        );
+
+        self.send_error_reply(header.nonce, KernelResponseBody::MalformedRequest, k2u);
+    }
+
+    /// Reject a request from userspace that no driver is registered to handle.
+    fn reject_unrouted(&self, req: &UserRequest, k2u: &FrameProducer<'static>) {
+        self.unrouted_requests.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            nonce = req.header.nonce,
+            kind = ?req.driver_kind(),
+            "No driver for request from userspace"
+        );
+        self.send_error_reply(req.header.nonce, KernelResponseBody::NoDriver, k2u);
+    }
+
+    fn send_error_reply(&self, nonce: u32, body: KernelResponseBody, k2u: &FrameProducer<'static>) {
+        let reply = KernelMsg::Response(KernelResponse {
+            header: KernelResponseHeader { nonce },
+            body,
+        });
+        match self.send_k2u(k2u, &reply) {
+            Ok(()) => self.error_replies.fetch_add(1, Ordering::Relaxed),
+            Err(()) => self.dropped_replies.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Serialize a message into the k2u ring, failing if there is no room.
+    fn send_k2u(&self, k2u: &FrameProducer<'static>, msg: &KernelMsg) -> Result<(), ()> {
+        let mut wgr = k2u.grant(KernelMsg::POSTCARD_MAX_SIZE).map_err(drop)?;
+        let used = postcard::to_slice(msg, &mut wgr).map_err(drop)?.len();
+        wgr.commit(used);
+        self.k2u_committed();
+        Ok(())
+    }
+
+    /// Record that a message was written to the k2u ring, waking userspace
+    /// if it was waiting.
+    fn k2u_committed(&self) {
+        self.k2u_sent.fetch_add(1, Ordering::AcqRel);
+        self.user_running();
+    }
+
+    /// Userspace has work to do, if it was waiting it should run again.
+    fn user_running(&self) {
+        if let UserState::Waiting { .. } = self.user_state.get() {
+            self.user_state.set(UserState::Running);
+        }
+    }
+
+    /// Handle a [UserRequestBody::Wait] from userspace.
+    fn user_wait(&self, until: Option<Instant>, received: u32) {
+        if let UserState::Halted { .. } = self.user_state.get() {
+            return;
+        }
+        // If userspace hasn't read everything we've sent, it has more work
+        // to do, and is not really idle.
+        if received != self.k2u_sent.load(Ordering::Acquire) {
+            trace!(received, "Userspace waited with unread messages");
+            return;
+        }
+        trace!(until = ?until, "Userspace is waiting");
+        self.user_state.set(UserState::Waiting { until });
     }
 }
 
 // TODO: De-dupe with userspace?
-use core::{future::Future, ptr::NonNull};
+use core::{
+    cell::Cell,
+    future::Future,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+    time::Duration,
+};
 
-#[repr(transparent)]
-pub struct Task<F: Future + 'static>(MaitakeTask<&'static StaticScheduler, F, HBStorage>);
+/// A task that has been created, but not necessarily spawned.
+///
+/// The scheduler only sees `task`, through a pointer to the whole `Task`, so
+/// it must stay the first field. `stats` lets the task be added to the task
+/// list once it is spawned.
+#[repr(C)]
+pub struct Task<F: Future + 'static> {
+    task: MaitakeTask<&'static StaticScheduler, Tracked<F>, HBStorage>,
+    stats: HeapArc<TaskStats>,
+}
 
 struct HBStorage;
 
-impl<F: Future + 'static> Storage<&'static StaticScheduler, F> for HBStorage {
+impl<F: Future + 'static> Storage<&'static StaticScheduler, Tracked<F>> for HBStorage {
     type StoredTask = HeapBox<Task<F>>;
 
-    fn into_raw(task: HeapBox<Task<F>>) -> NonNull<MaitakeTask<&'static StaticScheduler, F, Self>> {
+    fn into_raw(
+        task: HeapBox<Task<F>>,
+    ) -> NonNull<MaitakeTask<&'static StaticScheduler, Tracked<F>, Self>> {
         task.leak()
-            .cast::<MaitakeTask<&'static StaticScheduler, F, HBStorage>>()
+            .cast::<MaitakeTask<&'static StaticScheduler, Tracked<F>, HBStorage>>()
     }
 
-    fn from_raw(ptr: NonNull<MaitakeTask<&'static StaticScheduler, F, Self>>) -> HeapBox<Task<F>> {
+    fn from_raw(
+        ptr: NonNull<MaitakeTask<&'static StaticScheduler, Tracked<F>, Self>>,
+    ) -> HeapBox<Task<F>> {
         unsafe { HeapBox::from_leaked(ptr.cast::<Task<F>>()) }
     }
 }
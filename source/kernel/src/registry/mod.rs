@@ -1,5 +1,6 @@
 use core::any::TypeId;
 
+use abi::syscall::DriverKind;
 use mnemos_alloc::{containers::HeapFixedVec, heap::HeapGuard};
 use postcard::experimental::max_size::MaxSize;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -7,10 +8,13 @@ use spitebuf::EnqueueError;
 use tracing::{debug, info};
 use uuid::{uuid, Uuid};
 
-use crate::comms::{
-    bbq,
-    kchannel::{ErasedKProducer, KProducer},
-    oneshot::{ReusableError, Sender},
+use crate::{
+    comms::{
+        bbq,
+        kchannel::{ErasedKProducer, KProducer},
+        oneshot::{ReusableError, Sender},
+    },
+    UserMessage,
 };
 
 /// A partial list of known UUIDs of driver services
@@ -23,10 +27,17 @@ pub mod known_uuids {
 
         pub const SERIAL_MUX: Uuid = uuid!("54c983fa-736f-4223-b90d-c4360a308647");
         pub const SIMPLE_SERIAL_PORT: Uuid = uuid!("f06aac01-2773-4266-8681-583ffe756554");
+        pub const BLOCK_STORAGE: Uuid = uuid!("3b8a7d4e-5f0c-4d2a-9e61-c2f7a0b94d18");
+        pub const PCM_SINK: Uuid = uuid!("a41f6c92-0d3e-4b87-8c5a-7e19d2f3b604");
     }
 
     // In case you need to iterate over every UUID
-    pub static ALL: &[Uuid] = &[kernel::SERIAL_MUX, kernel::SIMPLE_SERIAL_PORT];
+    pub static ALL: &[Uuid] = &[
+        kernel::SERIAL_MUX,
+        kernel::SIMPLE_SERIAL_PORT,
+        kernel::BLOCK_STORAGE,
+        kernel::PCM_SINK,
+    ];
 }
 
 /// A marker trait designating a registerable driver service.
@@ -63,6 +74,7 @@ pub struct RegistryType {
 /// The driver registry used by the kernel.
 pub struct Registry {
     items: HeapFixedVec<RegistryItem>,
+    user_routes: HeapFixedVec<UserRoute>,
     counter: u32,
 }
 
@@ -202,6 +214,18 @@ pub enum UserHandlerError {
 pub enum RegistrationError {
     UuidAlreadyRegistered,
     RegistryFull,
+    RouteAlreadyRegistered,
+}
+
+/// The error returned by [Registry::route_user]. The message is handed back,
+/// so that the caller can reply to, or retry, the request.
+pub enum RouteError {
+    /// No driver is registered for this kind of request
+    NoRoute(UserMessage),
+    /// The driver's request queue is full
+    QueueFull(UserMessage),
+    /// The driver has stopped accepting requests
+    Closed(UserMessage),
 }
 
 impl From<ReusableError> for ReplyError {
@@ -270,6 +294,15 @@ struct RegistryItem {
     value: RegistryValue,
 }
 
+/// Where userspace requests of a given [DriverKind] are sent.
+///
+/// This is a stopgap until userspace requests are addressed by UUID, see
+/// [UserRequest].
+struct UserRoute {
+    kind: DriverKind,
+    queue: KProducer<UserMessage>,
+}
+
 // RegistryType
 
 impl RegistryType {
@@ -285,6 +318,7 @@ impl Registry {
     pub fn new(guard: &mut HeapGuard, max_items: usize) -> Self {
         Self {
             items: guard.alloc_fixed_vec(max_items).map_err(drop).unwrap(),
+            user_routes: guard.alloc_fixed_vec(max_items).map_err(drop).unwrap(),
             counter: 0,
         }
     }
@@ -362,6 +396,18 @@ impl Registry {
         Ok(result)
     }
 
+    /// Is a driver service of type `RD` registered?
+    pub fn contains<RD: RegisteredDriver>(&self) -> bool {
+        self.items
+            .iter()
+            .any(|i| i.key == RD::UUID && i.value.req_resp_tuple_id == RD::type_id().type_of())
+    }
+
+    /// The UUIDs of all registered driver services, in order of registration.
+    pub fn uuids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.items.iter().map(|i| i.key)
+    }
+
     /// Get a kernelspace (including drivers) handle of a given driver service.
     ///
     /// This can be used by drivers and tasks to interface with a registered driver
@@ -425,6 +471,65 @@ impl Registry {
             client_id: ClientId(client_id),
         })
     }
+
+    /// Close the request channel of every registered driver service.
+    ///
+    /// Used when the kernel is shutting down. Driver services will still receive
+    /// any requests that were sent before closing, after which their
+    /// [KConsumer](crate::comms::kchannel::KConsumer) will return an error, which
+    /// signals that the service should clean up and stop.
+    pub(crate) fn close_all(&mut self) {
+        for item in self.items.iter() {
+            item.value.req_prod.close();
+            info!(uuid = ?item.key, service_id = item.value.service_id.0, "Closed");
+        }
+        for route in self.user_routes.iter() {
+            route.queue.close();
+            info!(kind = ?route.kind, "Closed user route");
+        }
+    }
+
+    /// Register the driver that handles userspace requests of the given
+    /// [DriverKind].
+    ///
+    /// Requests are decoded by the kernel, and sent to the driver as a
+    /// [UserMessage]. Only one driver may handle each kind of request.
+    #[tracing::instrument(
+        name = "Registry::register_user_route",
+        level = "debug",
+        skip(self, kch)
+    )]
+    pub fn register_user_route(
+        &mut self,
+        kind: DriverKind,
+        kch: &KProducer<UserMessage>,
+    ) -> Result<(), RegistrationError> {
+        if self.user_routes.iter().any(|r| r.kind == kind) {
+            return Err(RegistrationError::RouteAlreadyRegistered);
+        }
+        self.user_routes
+            .push(UserRoute {
+                kind,
+                queue: kch.clone(),
+            })
+            .map_err(|_| RegistrationError::RegistryFull)?;
+        info!(?kind, "Registered user route");
+        Ok(())
+    }
+
+    /// Send a request from userspace to the driver registered for its
+    /// [DriverKind], without waiting.
+    pub(crate) fn route_user(&self, msg: UserMessage) -> Result<(), RouteError> {
+        let kind = msg.request.driver_kind();
+        let route = match self.user_routes.iter().find(|r| r.kind == kind) {
+            Some(route) => route,
+            None => return Err(RouteError::NoRoute(msg)),
+        };
+        route.queue.enqueue_sync(msg).map_err(|e| match e {
+            EnqueueError::Full(msg) => RouteError::QueueFull(msg),
+            EnqueueError::Closed(msg) => RouteError::Closed(msg),
+        })
+    }
 }
 
 // UserRequest
@@ -444,6 +549,18 @@ impl<P> Envelope<P> {
             request_id: RequestResponseId::new(self.request_id.id(), MessageKind::Response),
         }
     }
+
+    /// Like [Envelope::reply_with], but consumes the request, so that the
+    /// response can be made from its body.
+    pub fn reply_with_body<U>(self, f: impl FnOnce(P) -> U) -> Envelope<U> {
+        let request_id = RequestResponseId::new(self.request_id.id(), MessageKind::Response);
+        Envelope {
+            body: f(self.body),
+            service_id: self.service_id,
+            client_id: self.client_id,
+            request_id,
+        }
+    }
 }
 
 // Message
@@ -636,9 +753,16 @@ where
 ///
 /// For non-kernel-depended services, it should be enough to depend on the
 /// actual driver you are consuming
+///
+/// A platform may have more than one serial port, so ports are numbered.
+/// Port 0 is the platform's main serial port, which carries the SerialMux.
+///
+/// Ports whose far end can come and go, like a USB-serial adapter or a
+/// simulated port reached over TCP, may also report [LinkEvent]s.
 pub mod simple_serial {
     use super::*;
     use crate::comms::bbq::BidiHandle;
+    use crate::comms::kchannel::KConsumer;
     use crate::comms::oneshot::Reusable;
     use crate::Kernel;
 
@@ -652,6 +776,17 @@ pub mod simple_serial {
     #[derive(Debug, Eq, PartialEq)]
     pub enum SimpleSerialError {
         AlreadyAssignedPort,
+        NoSuchPort,
+        /// The port doesn't report link events, or they have already been
+        /// handed out
+        NoLinkEvents,
+    }
+
+    /// A change in whether anything is connected to the far end of a port
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum LinkEvent {
+        Connected,
+        Disconnected,
     }
 
     impl SimpleSerial {
@@ -666,15 +801,44 @@ pub mod simple_serial {
             })
         }
 
+        /// Get port 0, the platform's main serial port.
         pub async fn get_port(&mut self) -> Option<BidiHandle> {
+            self.get_port_num(0).await
+        }
+
+        /// Get the given serial port. Each port can only be handed out once.
+        pub async fn get_port_num(&mut self, port: u8) -> Option<BidiHandle> {
             self.kprod
-                .send(Request::GetPort, ReplyTo::OneShot(self.rosc.sender().ok()?))
+                .send(
+                    Request::GetPort { port },
+                    ReplyTo::OneShot(self.rosc.sender().ok()?),
+                )
                 .await
                 .ok()?;
             let resp = self.rosc.receive().await.ok()?;
 
-            let Response::PortHandle { handle } = resp.body.ok()?;
-            Some(handle)
+            match resp.body.ok()? {
+                Response::PortHandle { handle } => Some(handle),
+                _ => None,
+            }
+        }
+
+        /// Get the link events of the given serial port, if it reports them.
+        /// Each port's events can only be handed out once.
+        pub async fn link_events(&mut self, port: u8) -> Option<KConsumer<LinkEvent>> {
+            self.kprod
+                .send(
+                    Request::GetLinkEvents { port },
+                    ReplyTo::OneShot(self.rosc.sender().ok()?),
+                )
+                .await
+                .ok()?;
+            let resp = self.rosc.receive().await.ok()?;
+
+            match resp.body.ok()? {
+                Response::LinkEvents { events } => Some(events),
+                _ => None,
+            }
         }
     }
 
@@ -687,10 +851,439 @@ pub mod simple_serial {
     }
 
     pub enum Request {
-        GetPort,
+        GetPort { port: u8 },
+        GetLinkEvents { port: u8 },
     }
 
     pub enum Response {
         PortHandle { handle: BidiHandle },
+        LinkEvents { events: KConsumer<LinkEvent> },
+    }
+}
+
+/// A block storage device, like the QSPI flash
+///
+/// The device is made of a number of equally sized blocks. Like flash, an
+/// erased block reads as all `0xFF`, and writing can only clear bits: each
+/// byte written is ANDed with the byte already there. A block must be erased
+/// before it can be rewritten.
+pub mod block_storage {
+    use super::*;
+    use crate::comms::oneshot::Reusable;
+    use crate::Kernel;
+    use mnemos_alloc::containers::HeapArray;
+
+    use super::RegisteredDriver;
+
+    pub struct BlockStorage {
+        kprod: KernelHandle<BlockStorage>,
+        rosc: Reusable<Envelope<Result<Response, BlockStorageError>>>,
+    }
+
+    /// The shape of a block storage device
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct StoreInfo {
+        /// The number of blocks
+        pub blocks: u32,
+        /// The size of each block, in bytes
+        pub capacity: u32,
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum BlockStorageError {
+        NoSuchBlock,
+        /// The bytes read or written don't fit inside the block, or the
+        /// buffer
+        OutOfBounds,
+        /// The device failed to carry out the request
+        DeviceError,
+        /// The request couldn't be sent to the driver, or wasn't answered
+        NoResponse,
+    }
+
+    impl BlockStorage {
+        pub async fn from_registry(kernel: &'static Kernel) -> Option<Self> {
+            let kprod = kernel
+                .with_registry(|reg| reg.get::<BlockStorage>())
+                .await?;
+
+            Some(BlockStorage {
+                kprod,
+                rosc: Reusable::new_async(kernel).await,
+            })
+        }
+
+        /// Get the number and size of the device's blocks.
+        pub async fn store_info(&mut self) -> Result<StoreInfo, BlockStorageError> {
+            match self.request(Request::StoreInfo).await? {
+                Response::StoreInfo(info) => Ok(info),
+                _ => Err(BlockStorageError::NoResponse),
+            }
+        }
+
+        /// Read `len` bytes, from `offset` bytes into `block`, into the start
+        /// of `buf`, which is handed back.
+        pub async fn read(
+            &mut self,
+            block: u32,
+            offset: u32,
+            buf: HeapArray<u8>,
+            len: usize,
+        ) -> Result<HeapArray<u8>, BlockStorageError> {
+            let req = Request::Read {
+                block,
+                offset,
+                buf,
+                len,
+            };
+            match self.request(req).await? {
+                Response::Read { buf } => Ok(buf),
+                _ => Err(BlockStorageError::NoResponse),
+            }
+        }
+
+        /// Write the first `len` bytes of `buf` to `block`, starting `offset`
+        /// bytes in. `buf` is handed back once it has been written.
+        pub async fn write(
+            &mut self,
+            block: u32,
+            offset: u32,
+            buf: HeapArray<u8>,
+            len: usize,
+        ) -> Result<HeapArray<u8>, BlockStorageError> {
+            let req = Request::Write {
+                block,
+                offset,
+                buf,
+                len,
+            };
+            match self.request(req).await? {
+                Response::Written { buf } => Ok(buf),
+                _ => Err(BlockStorageError::NoResponse),
+            }
+        }
+
+        /// Erase `block`, setting every byte to `0xFF`.
+        pub async fn erase(&mut self, block: u32) -> Result<(), BlockStorageError> {
+            match self.request(Request::Erase { block }).await? {
+                Response::Erased => Ok(()),
+                _ => Err(BlockStorageError::NoResponse),
+            }
+        }
+
+        async fn request(&mut self, req: Request) -> Result<Response, BlockStorageError> {
+            let reply = self
+                .rosc
+                .sender()
+                .map_err(|_| BlockStorageError::NoResponse)?;
+            self.kprod
+                .send(req, ReplyTo::OneShot(reply))
+                .await
+                .map_err(|_| BlockStorageError::NoResponse)?;
+            let resp = self
+                .rosc
+                .receive()
+                .await
+                .map_err(|_| BlockStorageError::NoResponse)?;
+            resp.body
+        }
+    }
+
+    impl RegisteredDriver for BlockStorage {
+        type Request = Request;
+        type Response = Response;
+        type Error = BlockStorageError;
+
+        const UUID: Uuid = known_uuids::kernel::BLOCK_STORAGE;
+    }
+
+    pub enum Request {
+        StoreInfo,
+        /// Read into the first `len` bytes of `buf`
+        Read {
+            block: u32,
+            offset: u32,
+            buf: HeapArray<u8>,
+            len: usize,
+        },
+        /// Write the first `len` bytes of `buf`
+        Write {
+            block: u32,
+            offset: u32,
+            buf: HeapArray<u8>,
+            len: usize,
+        },
+        Erase {
+            block: u32,
+        },
+    }
+
+    pub enum Response {
+        StoreInfo(StoreInfo),
+        Read { buf: HeapArray<u8> },
+        Written { buf: HeapArray<u8> },
+        Erased,
+    }
+
+    impl StoreInfo {
+        /// Check that `len` bytes, `offset` bytes into `block`, are on the
+        /// device.
+        pub fn check_bounds(
+            &self,
+            block: u32,
+            offset: u32,
+            len: usize,
+        ) -> Result<(), BlockStorageError> {
+            if block >= self.blocks {
+                return Err(BlockStorageError::NoSuchBlock);
+            }
+            let end = u64::from(offset) + len as u64;
+            if end > u64::from(self.capacity) {
+                return Err(BlockStorageError::OutOfBounds);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// An audio output device, playing PCM samples
+///
+/// Samples are signed 16-bit, with the channels of each frame interleaved.
+/// They are streamed to the device in buffers, with a [PcmStream]: while one
+/// buffer plays, the next is filled, and the device hands each buffer back
+/// once it has been played.
+pub mod pcm_sink {
+    use super::*;
+    use crate::comms::kchannel::{KChannel, KConsumer, KProducer};
+    use crate::comms::oneshot::Reusable;
+    use crate::Kernel;
+    use mnemos_alloc::containers::HeapArray;
+
+    use super::RegisteredDriver;
+
+    /// The most buffers a device holds at once, including the one playing
+    pub const MAX_QUEUED: usize = 2;
+
+    pub struct PcmSink {
+        kernel: &'static Kernel,
+        kprod: KernelHandle<PcmSink>,
+        rosc: Reusable<Envelope<Result<Response, PcmSinkError>>>,
+    }
+
+    /// A stream of sample buffers to a [PcmSink], double-buffered
+    ///
+    /// The stream owns [MAX_QUEUED] buffers. Each is taken with
+    /// [PcmStream::buffer], filled, and handed to the device with
+    /// [PcmStream::submit], which returns straight away. Once every buffer is
+    /// with the device, [PcmStream::buffer] waits until one has been played.
+    pub struct PcmStream {
+        kprod: KernelHandle<PcmSink>,
+        kernel: &'static Kernel,
+        /// The length of each buffer, in samples
+        len: usize,
+        free: heapless::Vec<HeapArray<i16>, MAX_QUEUED>,
+        played_prod: KProducer<Envelope<Result<Response, PcmSinkError>>>,
+        played: KConsumer<Envelope<Result<Response, PcmSinkError>>>,
+        in_flight: usize,
+    }
+
+    /// How the device plays samples
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct PcmFormat {
+        /// Frames per second
+        pub sample_rate: u32,
+        /// Samples per frame
+        pub channels: u8,
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum PcmSinkError {
+        /// The device must be enabled before samples are played
+        NotEnabled,
+        /// The device already holds [MAX_QUEUED] buffers
+        Busy,
+        /// The buffer holds fewer samples than given, or a partial frame
+        InvalidLength,
+        /// The request couldn't be sent to the driver, or wasn't answered
+        NoResponse,
+    }
+
+    impl PcmSink {
+        pub async fn from_registry(kernel: &'static Kernel) -> Option<Self> {
+            let kprod = kernel.with_registry(|reg| reg.get::<PcmSink>()).await?;
+
+            Some(PcmSink {
+                kernel,
+                kprod,
+                rosc: Reusable::new_async(kernel).await,
+            })
+        }
+
+        pub async fn format(&mut self) -> Result<PcmFormat, PcmSinkError> {
+            match self.request(Request::Format).await? {
+                Response::Format(format) => Ok(format),
+                _ => Err(PcmSinkError::NoResponse),
+            }
+        }
+
+        /// Start playing samples.
+        pub async fn enable(&mut self) -> Result<(), PcmSinkError> {
+            match self.request(Request::Enable).await? {
+                Response::Enabled => Ok(()),
+                _ => Err(PcmSinkError::NoResponse),
+            }
+        }
+
+        /// Stop accepting samples. Buffers the device already holds are
+        /// still played.
+        pub async fn disable(&mut self) -> Result<(), PcmSinkError> {
+            match self.request(Request::Disable).await? {
+                Response::Disabled => Ok(()),
+                _ => Err(PcmSinkError::NoResponse),
+            }
+        }
+
+        /// Open a stream of buffers of `frames` frames each.
+        pub async fn open_stream(&mut self, frames: usize) -> Result<PcmStream, PcmSinkError> {
+            let format = self.format().await?;
+            let kernel = self.kernel;
+            let kprod = kernel
+                .with_registry(|reg| reg.get::<PcmSink>())
+                .await
+                .ok_or(PcmSinkError::NoResponse)?;
+            let (played_prod, played) = KChannel::new_async(kernel, MAX_QUEUED).await.split();
+
+            Ok(PcmStream {
+                kprod,
+                kernel,
+                len: frames * usize::from(format.channels),
+                free: heapless::Vec::new(),
+                played_prod,
+                played,
+                in_flight: 0,
+            })
+        }
+
+        async fn request(&mut self, req: Request) -> Result<Response, PcmSinkError> {
+            let reply = self.rosc.sender().map_err(|_| PcmSinkError::NoResponse)?;
+            self.kprod
+                .send(req, ReplyTo::OneShot(reply))
+                .await
+                .map_err(|_| PcmSinkError::NoResponse)?;
+            let resp = self
+                .rosc
+                .receive()
+                .await
+                .map_err(|_| PcmSinkError::NoResponse)?;
+            resp.body
+        }
+    }
+
+    impl PcmStream {
+        /// The length of each buffer, in samples
+        pub fn buffer_len(&self) -> usize {
+            self.len
+        }
+
+        /// Take a buffer to fill, waiting until one has been played if the
+        /// device holds them all.
+        pub async fn buffer(&mut self) -> Result<HeapArray<i16>, PcmSinkError> {
+            // Pick up any buffers played since, reporting the first one that
+            // was refused
+            while let Some(resp) = self.played.dequeue_sync() {
+                let samples = self.take_played(resp)?;
+                // There is always room for the stream's own buffers
+                let _ = self.free.push(samples);
+            }
+            if let Some(samples) = self.free.pop() {
+                return Ok(samples);
+            }
+            // Buffers of rejected requests aren't handed back, so replace them
+            if self.in_flight < MAX_QUEUED {
+                return Ok(self.kernel.heap().allocate_array_with(|| 0, self.len).await);
+            }
+            self.wait_played().await
+        }
+
+        /// Hand the first `len` samples of `samples` to the device, to be
+        /// played after any it already holds.
+        ///
+        /// This doesn't wait for the samples to be played. The buffer is
+        /// handed back, by [PcmStream::buffer], once they have been. If the
+        /// device refuses the samples, the error is returned by the next
+        /// [PcmStream::buffer] or [PcmStream::flush] instead.
+        pub async fn submit(
+            &mut self,
+            samples: HeapArray<i16>,
+            len: usize,
+        ) -> Result<(), PcmSinkError> {
+            self.kprod
+                .send(
+                    Request::Play { samples, len },
+                    ReplyTo::KChannel(self.played_prod.clone()),
+                )
+                .await
+                .map_err(|_| PcmSinkError::NoResponse)?;
+            self.in_flight += 1;
+            Ok(())
+        }
+
+        /// Wait until every buffer submitted has been played.
+        pub async fn flush(&mut self) -> Result<(), PcmSinkError> {
+            while self.in_flight > 0 {
+                let samples = self.wait_played().await?;
+                // There is always room for the stream's own buffers
+                let _ = self.free.push(samples);
+            }
+            Ok(())
+        }
+
+        async fn wait_played(&mut self) -> Result<HeapArray<i16>, PcmSinkError> {
+            let resp = self
+                .played
+                .dequeue_async()
+                .await
+                .map_err(|_| PcmSinkError::NoResponse)?;
+            self.take_played(resp)
+        }
+
+        /// Handle the device's answer to a submitted buffer.
+        fn take_played(
+            &mut self,
+            resp: Envelope<Result<Response, PcmSinkError>>,
+        ) -> Result<HeapArray<i16>, PcmSinkError> {
+            self.in_flight -= 1;
+            match resp.body? {
+                Response::Played { samples } => Ok(samples),
+                _ => Err(PcmSinkError::NoResponse),
+            }
+        }
+    }
+
+    impl RegisteredDriver for PcmSink {
+        type Request = Request;
+        type Response = Response;
+        type Error = PcmSinkError;
+
+        const UUID: Uuid = known_uuids::kernel::PCM_SINK;
+    }
+
+    pub enum Request {
+        Format,
+        Enable,
+        Disable,
+        /// Play the first `len` samples of `samples`. Answered once they
+        /// have been played.
+        Play {
+            samples: HeapArray<i16>,
+            len: usize,
+        },
+    }
+
+    pub enum Response {
+        Format(PcmFormat),
+        Enabled,
+        Disabled,
+        Played { samples: HeapArray<i16> },
     }
 }
@@ -0,0 +1,282 @@
+//! Kernel Task Tracking
+//!
+//! Every task spawned onto the kernel's scheduler is wrapped in a [Tracked]
+//! future. The tracked future records the current state of the task, as well
+//! as how many times it has been polled, in a [TaskStats] item shared with
+//! the kernel's [TaskList].
+//!
+//! When the task completes, its output is sent to the [JoinHandle] that was
+//! returned when the task was spawned.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use mnemos_alloc::{
+    containers::{HeapArc, HeapArray},
+    heap::HeapGuard,
+};
+use tracing::warn;
+
+use crate::comms::oneshot::{Reusable, ReusableError, Sender};
+
+/// The task has been spawned, and is waiting to be polled
+const TASK_IDLE: u8 = 0;
+/// The task is currently being polled
+const TASK_POLLING: u8 = 1;
+/// The task has completed, and its output has been sent to the JoinHandle
+const TASK_COMPLETED: u8 = 2;
+/// The task was dropped before completing
+const TASK_CANCELLED: u8 = 3;
+
+/// A unique identifier for a task spawned on the kernel
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TaskId(pub(crate) u32);
+
+/// The current state of a spawned task
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TaskState {
+    /// The task is waiting to be polled, either for the first time, or after
+    /// it has been woken
+    Idle,
+    /// The task is currently being polled
+    Polling,
+    /// The task ran to completion
+    Completed,
+    /// The task was dropped before it could complete
+    Cancelled,
+}
+
+/// A snapshot of the state of a single spawned task
+#[derive(Debug, Copy, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<&'static str>,
+    pub state: TaskState,
+    pub polls: u32,
+}
+
+/// The list of tasks known to the kernel.
+///
+/// Like the [Registry](crate::registry::Registry), this has a fixed capacity.
+/// Once the list is full, entries for finished tasks are reused. If there are
+/// no finished tasks to replace, newly spawned tasks will still run, but will
+/// not appear in the list.
+pub struct TaskList {
+    slots: HeapArray<Option<HeapArc<TaskStats>>>,
+}
+
+/// A handle to a spawned task, which can be used to await the task's output.
+///
+/// Dropping a `JoinHandle` does NOT cancel the task, it only discards the
+/// task's output.
+pub struct JoinHandle<T> {
+    rx: Reusable<T>,
+    stats: HeapArc<TaskStats>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum JoinError {
+    /// The task was dropped before it completed
+    Cancelled,
+    /// The join channel reported an unexpected state
+    InternalError,
+}
+
+/// A future wrapper used to record the state of a spawned task, and to
+/// deliver its output to the task's [JoinHandle].
+pub(crate) struct Tracked<F: Future> {
+    fut: F,
+    stats: HeapArc<TaskStats>,
+    tx: Option<Sender<F::Output>>,
+}
+
+/// State shared between a [Tracked] future, its [JoinHandle], and the [TaskList]
+pub(crate) struct TaskStats {
+    id: TaskId,
+    name: Option<&'static str>,
+    state: AtomicU8,
+    polls: AtomicU32,
+}
+
+// TaskState
+
+impl TaskState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            TASK_IDLE => TaskState::Idle,
+            TASK_POLLING => TaskState::Polling,
+            TASK_COMPLETED => TaskState::Completed,
+            _ => TaskState::Cancelled,
+        }
+    }
+
+    /// Has the task finished, either by completing or by being cancelled?
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TaskState::Completed | TaskState::Cancelled)
+    }
+}
+
+// TaskList
+
+impl TaskList {
+    /// Create a new task list with room for up to `max_tasks` tracked tasks.
+    pub fn new(guard: &mut HeapGuard, max_tasks: usize) -> Self {
+        Self {
+            slots: guard.alloc_box_array_with(|| None, max_tasks).unwrap(),
+        }
+    }
+
+    /// Start tracking a task, replacing a finished task if the list is full.
+    pub(crate) fn track(&mut self, stats: &HeapArc<TaskStats>) {
+        let slot = self.slots.iter_mut().find(|s| match s {
+            None => true,
+            Some(s) => s.state().is_finished(),
+        });
+
+        match slot {
+            Some(slot) => *slot = Some(stats.clone()),
+            None => warn!(
+                task.id = stats.id.0,
+                task.name = ?stats.name,
+                "Task list full, task will not be tracked"
+            ),
+        }
+    }
+
+    /// Iterate over a snapshot of every tracked task.
+    pub fn iter(&self) -> impl Iterator<Item = TaskInfo> + '_ {
+        self.slots
+            .iter()
+            .filter_map(|s| s.as_ref())
+            .map(|s| s.info())
+    }
+
+    /// The number of tracked tasks that have not yet finished.
+    pub fn live_tasks(&self) -> usize {
+        self.iter().filter(|t| !t.state.is_finished()).count()
+    }
+}
+
+// JoinHandle
+
+impl<T> JoinHandle<T> {
+    /// The ID of the spawned task
+    pub fn id(&self) -> TaskId {
+        self.stats.id
+    }
+
+    /// The current state of the spawned task
+    pub fn state(&self) -> TaskState {
+        self.stats.state()
+    }
+
+    /// Has the task finished, either by completing or by being cancelled?
+    pub fn is_finished(&self) -> bool {
+        self.state().is_finished()
+    }
+
+    /// Wait for the task to complete, returning its output.
+    pub async fn join(self) -> Result<T, JoinError> {
+        self.rx.receive().await.map_err(|e| match e {
+            ReusableError::NoSenderActive | ReusableError::ChannelClosed => JoinError::Cancelled,
+            _ => JoinError::InternalError,
+        })
+    }
+}
+
+// Tracked
+
+impl<F: Future> Tracked<F> {
+    /// Wrap the given future, returning the wrapped future and a [JoinHandle]
+    /// for its output.
+    pub(crate) fn new(
+        fut: F,
+        stats: HeapArc<TaskStats>,
+        rx: Reusable<F::Output>,
+    ) -> (Self, JoinHandle<F::Output>) {
+        // A freshly allocated Reusable never has an active sender.
+        let tx = rx.sender().ok();
+        let tracked = Self {
+            fut,
+            stats: stats.clone(),
+            tx,
+        };
+        (tracked, JoinHandle { rx, stats })
+    }
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `fut` is structurally pinned, and is never moved out of `self`.
+        // `stats` and `tx` are never pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+
+        this.stats.polls.fetch_add(1, Ordering::Relaxed);
+        this.stats.state.store(TASK_POLLING, Ordering::Release);
+
+        match fut.poll(cx) {
+            Poll::Ready(output) => {
+                this.stats.state.store(TASK_COMPLETED, Ordering::Release);
+                if let Some(tx) = this.tx.take() {
+                    // The JoinHandle may have been dropped, that's fine.
+                    let _ = tx.send(output);
+                }
+                Poll::Ready(())
+            }
+            Poll::Pending => {
+                this.stats.state.store(TASK_IDLE, Ordering::Release);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<F: Future> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        // Mark the task as cancelled if it never completed. Dropping `tx`
+        // will notify any JoinHandle waiting on the output.
+        let _ = self.stats.state.compare_exchange(
+            TASK_IDLE,
+            TASK_CANCELLED,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+// TaskStats
+
+impl TaskStats {
+    pub(crate) fn new(id: TaskId, name: Option<&'static str>) -> Self {
+        Self {
+            id,
+            name,
+            state: AtomicU8::new(TASK_IDLE),
+            polls: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name,
+            state: self.state(),
+            polls: self.polls.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! Kernel Timer
+//!
+//! The kernel does not have any hardware-specific time source. Instead, the
+//! platform is responsible for telling the kernel how much time has passed, by
+//! calling [Timer::pend_ticks] from a timer interrupt (or, in the simulator,
+//! from the main loop). The length of a single tick is set by
+//! [KernelSettings::timer_granularity](crate::KernelSettings).
+//!
+//! Pending ticks are applied at the start of every call to
+//! [Kernel::tick](crate::Kernel::tick), which then wakes any tasks whose
+//! deadlines have passed.
+//!
+//! Sleeping tasks are stored in a hashed timer wheel. Each slot of the wheel is
+//! a [WaitQueue], and a task sleeping until tick `N` waits on slot
+//! `N % WHEEL_SLOTS`. When the wheel advances past a slot, every task waiting on
+//! that slot is woken, and tasks whose deadline is still a full lap (or more)
+//! away simply go back to sleep.
+//!
+//! Each slot also remembers the earliest deadline of the tasks waiting on it,
+//! so that the platform can ask for [Timer::next_deadline], and sleep until
+//! then when the kernel is idle.
+
+use core::{
+    future::Future,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use maitake::wait::WaitQueue;
+
+/// The number of slots in the timer wheel
+const WHEEL_SLOTS: usize = 64;
+const MICROS_PER_SEC: u64 = 1_000_000;
+
+/// A point in time, measured in microseconds since the kernel booted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Instant {
+    micros: u64,
+}
+
+/// The error returned by [Timer::timeout] when the timeout elapses before the
+/// future completes.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Elapsed;
+
+/// The kernel's timer.
+///
+/// This is accessed with [Kernel::timer](crate::Kernel::timer).
+pub struct Timer {
+    /// The length of one tick, in microseconds
+    tick_micros: u64,
+    /// Ticks reported by the platform, but not yet applied to `now`
+    pending: AtomicU32,
+    /// The number of ticks applied since boot
+    now: TickCounter,
+    wheel: [WaitQueue; WHEEL_SLOTS],
+    /// The earliest deadline tick of any task waiting on each slot, or
+    /// `u64::MAX` if there are none
+    deadlines: [TickCounter; WHEEL_SLOTS],
+}
+
+/// A periodic timer, created with [Timer::interval].
+pub struct Interval {
+    timer: &'static Timer,
+    period: Duration,
+    next: Instant,
+}
+
+/// A 64-bit tick counter, built from 32-bit atomics.
+///
+/// 64-bit atomics are not available on all of our targets, so the count is
+/// split into two halves. A sequence counter (odd while a write is in progress)
+/// is used to detect torn reads. Counters are only written from within
+/// [Kernel::tick](crate::Kernel::tick), so writes never race.
+struct TickCounter {
+    seq: AtomicU32,
+    hi: AtomicU32,
+    lo: AtomicU32,
+}
+
+// Instant
+
+impl Instant {
+    /// The instant the kernel booted
+    pub const ZERO: Self = Self { micros: 0 };
+
+    pub const fn from_micros(micros: u64) -> Self {
+        Self { micros }
+    }
+
+    /// The number of microseconds since the kernel booted
+    pub const fn as_micros(&self) -> u64 {
+        self.micros
+    }
+
+    /// The amount of time elapsed from `earlier` to `self`, or a zero duration
+    /// if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.micros.saturating_sub(earlier.micros))
+    }
+
+    /// Returns `None` if the result would overflow.
+    pub fn checked_add(&self, dur: Duration) -> Option<Instant> {
+        let micros = self.micros.checked_add(duration_to_micros(dur)?)?;
+        Some(Instant { micros })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the maximum representable instant, rather than panicking
+    fn add(self, dur: Duration) -> Instant {
+        self.checked_add(dur)
+            .unwrap_or(Instant { micros: u64::MAX })
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    /// Saturates at zero, see [Instant::duration_since]
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// Timer
+
+impl Timer {
+    pub(crate) fn new(granularity: Duration) -> Self {
+        const EMPTY: WaitQueue = WaitQueue::new();
+        let tick_micros = duration_to_micros(granularity).unwrap_or(u64::MAX).max(1);
+
+        Self {
+            tick_micros,
+            pending: AtomicU32::new(0),
+            now: TickCounter::new(),
+            wheel: [EMPTY; WHEEL_SLOTS],
+            deadlines: core::array::from_fn(|_| TickCounter::with_value(u64::MAX)),
+        }
+    }
+
+    /// Report that `ticks` ticks of the platform's timer have elapsed.
+    ///
+    /// This only records the ticks, and is safe to call from an interrupt. The
+    /// ticks are applied, and sleeping tasks are woken, on the next call to
+    /// [Kernel::tick](crate::Kernel::tick).
+    pub fn pend_ticks(&self, ticks: u32) {
+        self.pending.fetch_add(ticks, Ordering::AcqRel);
+    }
+
+    /// The length of a single tick
+    pub fn granularity(&self) -> Duration {
+        Duration::from_micros(self.tick_micros)
+    }
+
+    /// The current time
+    pub fn now(&self) -> Instant {
+        Instant {
+            micros: self.now.load().saturating_mul(self.tick_micros),
+        }
+    }
+
+    /// The earliest deadline of any sleeping task, if there are any.
+    ///
+    /// This may be earlier than the real next deadline, e.g. if a sleep was
+    /// cancelled, but never later. Platforms can use this to decide how long
+    /// to sleep while the kernel is idle.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let tick = self
+            .deadlines
+            .iter()
+            .map(TickCounter::load)
+            .min()
+            .filter(|tick| *tick != u64::MAX)?;
+        Some(Instant {
+            micros: tick.saturating_mul(self.tick_micros),
+        })
+    }
+
+    /// Has the platform reported ticks that have not been applied yet?
+    pub(crate) fn has_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire) != 0
+    }
+
+    /// Apply any pending ticks, waking all tasks whose deadlines have passed.
+    ///
+    /// Returns `true` if time has advanced. This must only be called from
+    /// [Kernel::tick](crate::Kernel::tick).
+    pub(crate) fn advance(&self) -> bool {
+        let pending = self.pending.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            return false;
+        }
+
+        let old = self.now.load();
+        let new = old.saturating_add(pending.into());
+        self.now.store(new);
+
+        // Tasks that still have a lap to go will record their deadline again
+        // when they go back to sleep.
+        if pending as usize >= WHEEL_SLOTS {
+            // We've gone (at least) a full lap, wake everyone
+            self.wheel.iter().for_each(WaitQueue::wake_all);
+            self.deadlines.iter().for_each(|d| d.store(u64::MAX));
+        } else {
+            for tick in (old + 1)..=new {
+                self.slot(tick).wake_all();
+                self.slot_deadline(tick).store(u64::MAX);
+            }
+        }
+
+        true
+    }
+
+    /// Sleep for (at least) the given duration.
+    pub async fn sleep(&self, dur: Duration) {
+        self.sleep_until(self.now() + dur).await
+    }
+
+    /// Sleep until (at least) the given instant.
+    ///
+    /// Deadlines are rounded up to the next whole tick.
+    pub async fn sleep_until(&self, deadline: Instant) {
+        let deadline_tick = self.ticks_ceil(deadline);
+
+        // Ticks are only applied by `Kernel::tick`, before the scheduler runs,
+        // so time cannot move between checking the deadline and starting to
+        // wait on the slot.
+        while self.now.load() < deadline_tick {
+            let earliest = self.slot_deadline(deadline_tick);
+            if deadline_tick < earliest.load() {
+                earliest.store(deadline_tick);
+            }
+            let _ = self.slot(deadline_tick).wait().await;
+        }
+    }
+
+    /// Run the given future until it completes, or until the timeout elapses,
+    /// whichever happens first.
+    pub async fn timeout<F: Future>(&self, dur: Duration, fut: F) -> Result<F::Output, Elapsed> {
+        let sleep = self.sleep(dur);
+        pin_mut!(fut, sleep);
+
+        match select(fut, sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Elapsed),
+        }
+    }
+
+    /// Create a periodic timer that ticks every `period`.
+    ///
+    /// The first tick completes immediately.
+    pub fn interval(&'static self, period: Duration) -> Interval {
+        Interval {
+            timer: self,
+            period,
+            next: self.now(),
+        }
+    }
+
+    fn ticks_ceil(&self, instant: Instant) -> u64 {
+        // Saturating is fine here, a deadline that far out will never happen anyway
+        instant.micros.saturating_add(self.tick_micros - 1) / self.tick_micros
+    }
+
+    fn slot(&self, tick: u64) -> &WaitQueue {
+        &self.wheel[(tick % WHEEL_SLOTS as u64) as usize]
+    }
+
+    fn slot_deadline(&self, tick: u64) -> &TickCounter {
+        &self.deadlines[(tick % WHEEL_SLOTS as u64) as usize]
+    }
+}
+
+// Interval
+
+impl Interval {
+    /// Wait until the next tick of the interval, returning the instant that
+    /// tick was scheduled for.
+    ///
+    /// If the caller falls more than a whole period behind, the missed ticks
+    /// are skipped rather than completing in a burst.
+    pub async fn tick(&mut self) -> Instant {
+        let scheduled = self.next;
+        self.timer.sleep_until(scheduled).await;
+
+        let now = self.timer.now();
+        self.next = scheduled + self.period;
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+        scheduled
+    }
+
+    /// The interval's period
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+// TickCounter
+
+impl TickCounter {
+    const fn new() -> Self {
+        Self::with_value(0)
+    }
+
+    const fn with_value(ticks: u64) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            hi: AtomicU32::new((ticks >> 32) as u32),
+            lo: AtomicU32::new(ticks as u32),
+        }
+    }
+
+    fn load(&self) -> u64 {
+        loop {
+            let seq = self.seq.load(Ordering::SeqCst);
+            if seq & 1 == 0 {
+                let hi = self.hi.load(Ordering::SeqCst);
+                let lo = self.lo.load(Ordering::SeqCst);
+                if self.seq.load(Ordering::SeqCst) == seq {
+                    return ((hi as u64) << 32) | (lo as u64);
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Must never be called concurrently with itself.
+    fn store(&self, ticks: u64) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        self.hi.store((ticks >> 32) as u32, Ordering::SeqCst);
+        self.lo.store(ticks as u32, Ordering::SeqCst);
+        self.seq.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Convert a duration to microseconds, without using `u128`s.
+fn duration_to_micros(dur: Duration) -> Option<u64> {
+    dur.as_secs()
+        .checked_mul(MICROS_PER_SEC)?
+        .checked_add(dur.subsec_micros().into())
+}
@@ -0,0 +1,108 @@
+//! Built-in userspace applications
+//!
+//! Melpomene can't load userspace binaries yet, so instead it links in a few
+//! demo applications, and runs the one selected with `--app`. Each of these
+//! is the `main` of a normal `mstd` application, and talks to the kernel only
+//! through the syscall rings.
+
+use mstd::{
+    abi::syscall::serial::SerialError,
+    block::{self, Block, BlockError, BlockKind},
+    executor::{time::sleep, EXECUTOR},
+    println, runtime,
+    serial::SerialPort,
+};
+use std::time::Duration;
+
+/// The serial mux port used by the echo app
+pub const ECHO_PORT: u16 = 3;
+/// The name of the block the blocks app counts its runs in
+pub const BOOTS_BLOCK: &str = "boots";
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum App {
+    /// Print a few greetings from a couple of tasks, then exit
+    Hello,
+    /// Echo everything received on serial mux port 3
+    Echo,
+    /// List the named blocks on the block storage device, then count this run
+    /// in the block named "boots"
+    Blocks,
+}
+
+impl App {
+    /// Spawn the app's `main` task. The runtime must already be initialized.
+    pub fn spawn(self) {
+        match self {
+            App::Hello => runtime::spawn_main(hello()),
+            App::Echo => runtime::spawn_main(echo()),
+            App::Blocks => runtime::spawn_main(blocks()),
+        }
+    }
+}
+
+async fn hello() {
+    EXECUTOR
+        .spawn(async {
+            for i in 0..3 {
+                println!("[subtask] Hello from a subtask! ({i})");
+                sleep(Duration::from_millis(1500)).await;
+            }
+            println!("[subtask] Done!");
+        })
+        .await;
+
+    for i in 0..5 {
+        println!("[main] Hello from userspace! ({i})");
+        sleep(Duration::from_secs(1)).await;
+    }
+    println!("[main] Goodbye!");
+}
+
+async fn echo() -> Result<(), SerialError> {
+    let mut port = SerialPort::open(ECHO_PORT).await?;
+    println!("Echoing serial mux port {ECHO_PORT}");
+
+    let mut buf = [0u8; 128];
+    loop {
+        let len = port.read(&mut buf).await?;
+        port.write(&buf[..len]).await?;
+    }
+}
+
+async fn blocks() -> Result<(), BlockError> {
+    let store = block::store_info().await?;
+    println!("{} blocks of {} bytes", store.blocks, store.capacity);
+
+    let mut boots = None;
+    let mut unused = None;
+    for i in 0..store.blocks {
+        let info = block::block_info(i).await?;
+        match info.kind {
+            BlockKind::Unused => {
+                unused.get_or_insert(i);
+                continue;
+            }
+            _ if info.name.as_deref() == Some(BOOTS_BLOCK) => boots = Some(i),
+            _ => {}
+        }
+        let name = info.name.as_deref().unwrap_or("<unnamed>");
+        println!("{i:>3}: {name} ({:?}, {} bytes)", info.kind, info.length);
+    }
+
+    // Count this run, in a new block if this is the first
+    let mut count = [0u8; 4];
+    let mut block = match boots {
+        Some(i) => {
+            let mut block = Block::open(i).await?;
+            block.read(0, &mut count).await?;
+            block
+        }
+        None => Block::open(unused.ok_or(BlockError::NoSuchBlock)?).await?,
+    };
+    let count = u32::from_le_bytes(count) + 1;
+    block.write(0, &count.to_le_bytes()).await?;
+    block.close(BOOTS_BLOCK, 4, BlockKind::Storage).await?;
+    println!("Run number {count}");
+    Ok(())
+}
@@ -1,6 +1,8 @@
-use crate::{sim_drivers::tcp_serial, sim_tracing};
+use crate::{
+    apps::App, sim_drivers::serial_ports::SerialAddr, sim_faults::FaultConfig, sim_tracing,
+};
 use clap::Parser;
-use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -14,7 +16,62 @@ pub struct Args {
 
 #[derive(Debug, clap::Args)]
 pub struct MelpomeneOptions {
-    /// Address to bind the TCP listener for the simulated serial port.
-    #[clap(long, default_value_t = tcp_serial::default_addr())]
-    pub serial_addr: SocketAddr,
+    /// Address to listen on for serial port 0, which carries the serial mux.
+    ///
+    /// Either a TCP address, `unix:<path>` for a Unix socket, or `pty` for a
+    /// new pseudo-terminal. This overrides the address in the config file. If
+    /// neither is set, 127.0.0.1:9999 is used.
+    #[clap(long)]
+    pub serial_addr: Option<SerialAddr>,
+
+    /// A JSON or TOML file describing the simulated board.
+    ///
+    /// This sets the kernel's settings, the serial port's buffers, and the
+    /// serial mux ports that are opened. Anything not set in the file keeps
+    /// its default.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Back the block storage device with this image file, creating it if it
+    /// doesn't exist.
+    ///
+    /// This overrides the image in the config file. If the config has no
+    /// block storage device, one of 16 blocks of 64KiB is added.
+    #[clap(long)]
+    pub block_image: Option<PathBuf>,
+
+    /// Record the audio output device to this WAV file, replacing it if it
+    /// exists.
+    ///
+    /// This overrides the file in the config file. If the config has no
+    /// audio output device, a 44.1kHz stereo one is added.
+    #[clap(long)]
+    pub wav: Option<PathBuf>,
+
+    /// Shut the kernel down after running for this many seconds.
+    ///
+    /// If this is not set, the simulator will run until it receives Ctrl-C.
+    /// With `--seed`, this counts seconds of virtual time.
+    #[clap(long)]
+    pub run_for: Option<u64>,
+
+    /// Run deterministically in virtual time, using this seed to schedule the
+    /// kernel, userspace and simulated devices.
+    ///
+    /// In this mode the serial port is simulated in-process rather than over
+    /// TCP, and everything the kernel sends on it is printed to stdout. The
+    /// simulator stops when the userspace app exits, or after `--run-for`
+    /// seconds of virtual time. Running again with the same seed and options
+    /// replays exactly the same run.
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    #[clap(flatten)]
+    pub faults: FaultConfig,
+
+    /// The built-in userspace application to run.
+    ///
+    /// If this is not set, userspace is started, but has no tasks to run.
+    #[clap(long, value_enum)]
+    pub app: Option<App>,
 }
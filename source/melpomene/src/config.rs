@@ -0,0 +1,424 @@
+//! Describing the simulated board
+//!
+//! The kernel's settings, the serial port, and the drivers and tasks started
+//! on top of it are all described by a [Config]. The defaults describe the
+//! board melpomene has always simulated. Other boards can be described in a
+//! JSON or TOML file, and loaded with `--config`. Any setting left out of the
+//! file keeps its default.
+//!
+//! ```toml
+//! [kernel]
+//! heap_size = 65536
+//! max_tasks = 16
+//!
+//! [serial_mux]
+//! max_ports = 2
+//!
+//! [user_serial]
+//! enabled = false
+//!
+//! [[ports]]
+//! port = 2
+//! task = "loopback"
+//!
+//! [block_storage]
+//! image = "flash.img"
+//!
+//! [pcm_sink]
+//! wav = "out.wav"
+//! sample_rate = 8000
+//! channels = 1
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::sim_drivers::{
+    serial_ports::SerialAddr,
+    tcp_serial::{ClientPolicy, OfflinePolicy},
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub kernel: KernelConfig,
+    /// The board's serial ports, in order. Port 0 carries the serial mux.
+    pub serial: Vec<SerialConfig>,
+    pub serial_mux: SerialMuxConfig,
+    pub user_serial: UserSerialConfig,
+    /// The serial mux ports opened by the kernel, and the task serving each
+    pub ports: Vec<PortConfig>,
+    /// The block storage device, if the board has one
+    pub block_storage: Option<BlockStorageConfig>,
+    /// The audio output device, if the board has one
+    pub pcm_sink: Option<PcmSinkConfig>,
+}
+
+/// Mirrors [KernelSettings](mnemos_kernel::KernelSettings), plus the size of
+/// the userspace heap
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KernelConfig {
+    pub heap_size: usize,
+    pub user_heap_size: usize,
+    pub max_drivers: usize,
+    pub max_tasks: usize,
+    /// The length of a single tick of the kernel's timer, in microseconds
+    pub timer_granularity_us: u64,
+    pub max_pending_messages: usize,
+    pub k2u_size: usize,
+    pub u2k_size: usize,
+}
+
+/// A simulated serial port
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialConfig {
+    /// Address to listen on, see [SerialAddr]. For port 0, this is
+    /// overridden by `--serial-addr`.
+    pub addr: Option<SerialAddr>,
+    /// Bytes buffered on their way to the kernel
+    pub incoming_size: usize,
+    /// Bytes buffered on their way out of the kernel
+    pub outgoing_size: usize,
+    /// What happens to the kernel's output while no client is connected to
+    /// a socket
+    pub offline: OfflinePolicy,
+    /// What happens when a client connects to a socket while another is
+    /// already connected
+    pub clients: ClientPolicy,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialMuxConfig {
+    pub max_ports: usize,
+    pub max_frame: usize,
+}
+
+/// Userspace's access to the serial mux
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserSerialConfig {
+    pub enabled: bool,
+    /// How many mux ports userspace may open, including stdout
+    pub max_ports: usize,
+    /// Bytes of incoming data buffered for each port
+    pub port_capacity: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    pub port: u16,
+    /// Bytes of incoming data buffered for the port
+    #[serde(default = "PortConfig::default_capacity")]
+    pub capacity: usize,
+    pub task: PortTask,
+}
+
+/// A kernel task serving a serial mux port
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortTask {
+    /// Echo everything received on the port
+    Loopback,
+    /// Send "hello" on the port every second
+    Hello,
+}
+
+/// A block storage device, backed by an image file on the host, see
+/// [FileBlockStorage](crate::sim_drivers::file_block::FileBlockStorage)
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockStorageConfig {
+    /// The image file, created if it doesn't exist. For the CLI, this is
+    /// overridden by `--block-image`.
+    pub image: PathBuf,
+    /// The number of blocks
+    #[serde(default = "BlockStorageConfig::default_blocks")]
+    pub blocks: u32,
+    /// The size of each block, in bytes
+    #[serde(default = "BlockStorageConfig::default_capacity")]
+    pub capacity: u32,
+}
+
+/// An audio output device, recording everything played to a WAV file on the
+/// host, see [WavSink](crate::sim_drivers::wav_sink::WavSink)
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PcmSinkConfig {
+    /// The WAV file, replaced if it exists. For the CLI, this is overridden
+    /// by `--wav`.
+    pub wav: PathBuf,
+    /// Frames per second
+    #[serde(default = "PcmSinkConfig::default_sample_rate")]
+    pub sample_rate: u32,
+    /// Samples per frame
+    #[serde(default = "PcmSinkConfig::default_channels")]
+    pub channels: u8,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    /// The file's extension is neither `.json` nor `.toml`
+    UnknownFormat,
+    /// There must be at least one serial port, to carry the serial mux
+    NoSerialPorts,
+    /// Serial ports are numbered with a `u8`
+    TooManySerialPorts(usize),
+    /// A serial mux port is opened more than once
+    DuplicatePort(u16),
+    /// More mux ports may be opened than the mux has room for
+    TooManyPorts {
+        needed: usize,
+        max_ports: usize,
+    },
+    /// The block storage device has no blocks, or they hold nothing
+    EmptyBlockStorage,
+    /// The audio output device has no channels, or plays no frames
+    InvalidPcmFormat,
+}
+
+// Config
+
+impl Config {
+    /// Load a config from a JSON or TOML file, picked by its extension.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Read)?;
+        let config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text).map_err(ConfigError::Json)?,
+            Some("toml") => toml::from_str(&text).map_err(ConfigError::Toml)?,
+            _ => return Err(ConfigError::UnknownFormat),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the serial ports can be numbered, that the serial mux can
+    /// hold every port that may be opened, that any block storage device
+    /// can hold something, and that any audio output device can play
+    /// something.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.serial.is_empty() {
+            return Err(ConfigError::NoSerialPorts);
+        }
+        if self.serial.len() > usize::from(u8::MAX) + 1 {
+            return Err(ConfigError::TooManySerialPorts(self.serial.len()));
+        }
+
+        let mut ports = BTreeSet::new();
+        for port in self.ports.iter() {
+            if !ports.insert(port.port) {
+                return Err(ConfigError::DuplicatePort(port.port));
+            }
+        }
+
+        let mut needed = self.ports.len();
+        if self.user_serial.enabled {
+            needed += self.user_serial.max_ports;
+        }
+        if needed > self.serial_mux.max_ports {
+            return Err(ConfigError::TooManyPorts {
+                needed,
+                max_ports: self.serial_mux.max_ports,
+            });
+        }
+
+        if let Some(storage) = self.block_storage.as_ref() {
+            if storage.blocks == 0 || storage.capacity == 0 {
+                return Err(ConfigError::EmptyBlockStorage);
+            }
+        }
+        if let Some(sink) = self.pcm_sink.as_ref() {
+            if sink.sample_rate == 0 || sink.channels == 0 {
+                return Err(ConfigError::InvalidPcmFormat);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            kernel: KernelConfig::default(),
+            serial: vec![SerialConfig::default()],
+            serial_mux: SerialMuxConfig::default(),
+            user_serial: UserSerialConfig::default(),
+            // Port 0 is left for userspace's stdout
+            ports: vec![
+                PortConfig {
+                    port: 1,
+                    capacity: PortConfig::default_capacity(),
+                    task: PortTask::Hello,
+                },
+                PortConfig {
+                    port: 2,
+                    capacity: PortConfig::default_capacity(),
+                    task: PortTask::Loopback,
+                },
+            ],
+            block_storage: None,
+            pcm_sink: None,
+        }
+    }
+}
+
+// KernelConfig
+
+impl KernelConfig {
+    pub fn timer_granularity(&self) -> Duration {
+        Duration::from_micros(self.timer_granularity_us)
+    }
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        Self {
+            heap_size: 192 * 1024,
+            user_heap_size: 192 * 1024,
+            max_drivers: 16,
+            max_tasks: 64,
+            timer_granularity_us: 1000,
+            max_pending_messages: 32,
+            k2u_size: 4096,
+            u2k_size: 4096,
+        }
+    }
+}
+
+// SerialConfig
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            incoming_size: 4096,
+            outgoing_size: 4096,
+            offline: OfflinePolicy::default(),
+            clients: ClientPolicy::default(),
+        }
+    }
+}
+
+// SerialMuxConfig
+
+impl Default for SerialMuxConfig {
+    fn default() -> Self {
+        Self {
+            max_ports: 4,
+            max_frame: 512,
+        }
+    }
+}
+
+// UserSerialConfig
+
+impl Default for UserSerialConfig {
+    fn default() -> Self {
+        // Ports 0 for stdout, and 3 for the echo app
+        Self {
+            enabled: true,
+            max_ports: 2,
+            port_capacity: 1024,
+        }
+    }
+}
+
+// PortConfig
+
+impl PortConfig {
+    fn default_capacity() -> usize {
+        1024
+    }
+}
+
+// BlockStorageConfig
+
+impl BlockStorageConfig {
+    /// A device of the default size, backed by `image`
+    pub fn new(image: PathBuf) -> Self {
+        Self {
+            image,
+            blocks: Self::default_blocks(),
+            capacity: Self::default_capacity(),
+        }
+    }
+
+    /// One block holds the named blocks' metadata, leaving 15 for userspace
+    fn default_blocks() -> u32 {
+        16
+    }
+
+    fn default_capacity() -> u32 {
+        64 * 1024
+    }
+}
+
+// PcmSinkConfig
+
+impl PcmSinkConfig {
+    /// A CD quality stereo device, recording to `wav`
+    pub fn new(wav: PathBuf) -> Self {
+        Self {
+            wav,
+            sample_rate: Self::default_sample_rate(),
+            channels: Self::default_channels(),
+        }
+    }
+
+    fn default_sample_rate() -> u32 {
+        44_100
+    }
+
+    fn default_channels() -> u8 {
+        2
+    }
+}
+
+// ConfigError
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(e) => write!(f, "failed to read the config file: {e}"),
+            ConfigError::Json(e) => write!(f, "invalid JSON config: {e}"),
+            ConfigError::Toml(e) => write!(f, "invalid TOML config: {e}"),
+            ConfigError::UnknownFormat => {
+                f.write_str("the config file must end in `.json` or `.toml`")
+            }
+            ConfigError::NoSerialPorts => f.write_str("at least one serial port is needed"),
+            ConfigError::TooManySerialPorts(count) => {
+                write!(
+                    f,
+                    "{count} serial ports were given, but at most 256 are supported"
+                )
+            }
+            ConfigError::DuplicatePort(port) => {
+                write!(f, "serial mux port {port} is opened more than once")
+            }
+            ConfigError::TooManyPorts { needed, max_ports } => write!(
+                f,
+                "up to {needed} serial mux ports may be opened, but the mux only has {max_ports}"
+            ),
+            ConfigError::EmptyBlockStorage => {
+                f.write_str("the block storage device needs at least one non-empty block")
+            }
+            ConfigError::InvalidPcmFormat => f.write_str(
+                "the audio output device needs at least one channel, and a non-zero sample rate",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
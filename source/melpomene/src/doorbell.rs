@@ -0,0 +1,55 @@
+//! Waking the simulated CPU
+//!
+//! On real hardware, the kernel sleeps until an interrupt fires. In the
+//! simulator, the kernel's thread waits on a [Doorbell] instead, which is rung
+//! by anything running outside of the kernel that may have given it work,
+//! like the simulated drivers' Tokio tasks.
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+/// The doorbell that wakes the kernel's thread
+pub static KERNEL_DOORBELL: Doorbell = Doorbell::new();
+
+pub struct Doorbell {
+    rung: Mutex<bool>,
+    bell: Condvar,
+}
+
+impl Doorbell {
+    pub const fn new() -> Self {
+        Self {
+            rung: Mutex::new(false),
+            bell: Condvar::new(),
+        }
+    }
+
+    /// Wake the thread waiting on the doorbell, or if none is, make its next
+    /// wait return immediately.
+    pub fn ring(&self) {
+        *self.rung.lock().unwrap() = true;
+        self.bell.notify_one();
+    }
+
+    /// Wait until the doorbell is rung, or the timeout elapses.
+    ///
+    /// Returns `true` if the doorbell was rung.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let rung = self.rung.lock().unwrap();
+        let (mut rung, _) = self
+            .bell
+            .wait_timeout_while(rung, timeout, |rung| !*rung)
+            .unwrap();
+        let was_rung = *rung;
+        *rung = false;
+        was_rung
+    }
+}
+
+impl Default for Doorbell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,451 @@
+//! Running the simulator in-process, for tests
+//!
+//! A [Harness] boots a kernel with a [VirtualSerial] port for each serial port
+//! on the board described by a [Config], and runs it in virtual time on a
+//! seeded schedule, just like `melpomene --seed`. Nothing runs unless the
+//! harness is stepped, so a test can inject data on the serial mux ports, or
+//! raw bytes on the other serial ports, run the machine until something
+//! happens, then check what was sent back, what is in the driver registry,
+//! and what was traced along the way.
+//!
+//! ```rust,no_run
+//! use melpomene::harness::{Harness, HarnessSettings};
+//! use std::time::Duration;
+//!
+//! let mut harness = Harness::new(HarnessSettings::default());
+//! harness.send(2, b"ping");
+//! assert!(harness.run_until(Duration::from_secs(1), |h| h.output(2) == b"ping"));
+//! assert_eq!(harness.shutdown(), 0);
+//! ```
+//!
+//! Userspace's runtime is global, so only one harness per process may start
+//! userspace. Tests that run an app should each live in their own file under
+//! `tests/`, which cargo builds as a separate binary.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use mnemos_kernel::{registry::RegisteredDriver, Kernel};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tracing::{
+    field::{Field, Visit},
+    subscriber::DefaultGuard,
+    Event, Instrument, Level, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    Layer,
+};
+
+use crate::{
+    apps::App,
+    config::Config,
+    machine::{self, Machine},
+    sim_drivers::{
+        serial_ports::{SerialPorts, Uart},
+        virtual_serial::{VirtualSerial, VirtualSerialPort},
+    },
+    sim_faults::FaultConfig,
+    sim_time::VirtualClock,
+};
+
+/// Set once a harness has started userspace in this process
+static USERSPACE_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct HarnessSettings {
+    /// Seeds the schedule of the kernel, userspace and the serial ports
+    pub seed: u64,
+    pub config: Config,
+    /// Start userspace once the kernel has booted.
+    ///
+    /// Only one harness per process may start userspace.
+    pub start_userspace: bool,
+    /// The app to run, if userspace is started
+    pub app: Option<App>,
+    /// Shut the kernel down after this much virtual time
+    pub run_for: Option<Duration>,
+    /// Record trace events at this level and above, see [Harness::events]
+    pub trace_level: Option<Level>,
+    /// Faults injected into every serial port
+    pub faults: FaultConfig,
+}
+
+/// A simulated machine, run in-process and in virtual time
+pub struct Harness {
+    k: &'static Kernel,
+    machine: Machine,
+    clock: VirtualClock,
+    rng: ChaCha8Rng,
+    /// The simulator's end of each serial port. Port 0 carries the mux.
+    ports: Vec<VirtualSerialPort>,
+    /// Data received from the kernel, by serial mux port
+    output: BTreeMap<u16, Vec<u8>>,
+    /// Raw bytes received from the kernel on the other serial ports
+    uart_output: BTreeMap<u8, Vec<u8>>,
+    shutdown_requested: bool,
+    exit_status: Option<i32>,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+    _trace_guard: Option<DefaultGuard>,
+}
+
+/// A trace event recorded by a [Harness]
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// Records events into a [Harness]
+struct Recorder {
+    level: Level,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+// HarnessSettings
+
+impl Default for HarnessSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            config: Config::default(),
+            start_userspace: false,
+            app: None,
+            run_for: None,
+            trace_level: Some(Level::INFO),
+            faults: FaultConfig::default(),
+        }
+    }
+}
+
+// Harness
+
+impl Harness {
+    /// Boot a kernel, and run it until its drivers are initialized.
+    ///
+    /// If trace events are recorded, the harness is the current thread's
+    /// default subscriber until it is dropped.
+    ///
+    /// Panics if userspace is to be started, but has already been started by
+    /// another harness.
+    pub fn new(settings: HarnessSettings) -> Self {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let _trace_guard = settings.trace_level.map(|level| {
+            let recorder = Recorder {
+                level,
+                events: events.clone(),
+            };
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder))
+        });
+
+        let config = settings.config;
+        let (k, user_heap) = machine::new_kernel(&config.kernel);
+        let (ports_tx, ports_rx) = mpsc::channel();
+        let initialization_future = async move {
+            let mut uarts = Vec::with_capacity(config.serial.len());
+            let mut ports = Vec::with_capacity(config.serial.len());
+            for (i, serial) in config.serial.iter().enumerate() {
+                let (handle, port) =
+                    VirtualSerial::open(k, serial.incoming_size, serial.outgoing_size, i == 0)
+                        .await;
+                uarts.push(Uart {
+                    handle,
+                    link_events: None,
+                });
+                ports.push(port);
+            }
+            SerialPorts::register(k, uarts).await.unwrap();
+            ports_tx.send(ports).unwrap();
+
+            machine::init_drivers(k, &config).await;
+        }
+        .instrument(tracing::info_span!("Initialize"));
+
+        let init = k.initialize(initialization_future).unwrap();
+
+        let mut clock = VirtualClock::new(k.timer().granularity());
+        while !init.is_finished() {
+            let tick = k.tick();
+            if !tick.has_remaining {
+                clock.advance_to(k, machine::idle_deadline(k, &tick));
+            }
+        }
+
+        if settings.start_userspace {
+            let started = USERSPACE_STARTED.swap(true, Ordering::AcqRel);
+            assert!(
+                !started,
+                "userspace has already been started in this process"
+            );
+            // SAFETY: we checked that this is the only time userspace starts
+            unsafe {
+                machine::start_userspace(k, user_heap, settings.app);
+            }
+        }
+
+        let mut ports: Vec<VirtualSerialPort> = ports_rx.recv().unwrap();
+        if settings.faults.is_enabled() {
+            ports = ports
+                .into_iter()
+                .enumerate()
+                .map(|(i, port)| port.with_faults(&settings.faults, i as u8))
+                .collect();
+        }
+
+        Self {
+            k,
+            machine: Machine::new(k, settings.run_for),
+            clock,
+            rng: ChaCha8Rng::seed_from_u64(settings.seed),
+            ports,
+            output: BTreeMap::new(),
+            uart_output: BTreeMap::new(),
+            shutdown_requested: false,
+            exit_status: None,
+            events,
+            _trace_guard,
+        }
+    }
+
+    pub fn kernel(&self) -> &'static Kernel {
+        self.k
+    }
+
+    /// The virtual time elapsed since the kernel booted
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// Queue data to be sent to the given serial mux port.
+    pub fn send(&mut self, port: u16, data: &[u8]) {
+        self.ports[0].send(port, data);
+    }
+
+    /// Queue raw bytes to be sent on serial port 0, without any framing.
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.ports[0].send_raw(bytes);
+    }
+
+    /// Queue raw bytes to be sent on one of the other serial ports.
+    ///
+    /// Panics if the port is port 0, or doesn't exist.
+    pub fn send_uart(&mut self, uart: u8, bytes: &[u8]) {
+        assert_ne!(uart, 0, "serial port 0 carries the mux, use `send`");
+        self.ports[usize::from(uart)].send_raw(bytes);
+    }
+
+    /// Take one step: tick the kernel, poll the serial ports, then either run
+    /// userspace, or move time forward.
+    ///
+    /// While there is work to do, whether userspace runs, and whether time
+    /// moves forward by a tick, is left to chance. Once everything is idle,
+    /// time skips straight to the next deadline.
+    ///
+    /// Returns the exit status of the simulator once the kernel has stopped.
+    pub fn step(&mut self) -> Option<i32> {
+        if self.exit_status.is_some() {
+            return self.exit_status;
+        }
+
+        let k = self.k;
+        self.machine
+            .before_tick(self.clock.elapsed(), self.shutdown_requested);
+        let tick = k.tick();
+        if let Some(status) = self.machine.after_tick(self.clock.elapsed(), &tick) {
+            self.exit_status = Some(status);
+            return self.exit_status;
+        }
+
+        let now = self.clock.elapsed();
+        let mut moved = false;
+        let mut busy = tick.has_remaining;
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            moved |= port.poll(now, &mut self.rng);
+            busy |= port.is_busy(now);
+            if i == 0 {
+                while let Some((port, data)) = port.recv() {
+                    self.output.entry(port).or_default().extend(data);
+                }
+            } else {
+                let data = port.recv_raw();
+                if !data.is_empty() {
+                    self.uart_output.entry(i as u8).or_default().extend(data);
+                }
+            }
+        }
+
+        let busy = busy || moved;
+        let run_user = tick.userspace_ready && self.machine.userspace_alive();
+        if run_user && (!busy || self.rng.gen_bool(0.5)) {
+            self.machine.run_userspace();
+        } else if busy {
+            if self.rng.gen_ratio(1, 16) {
+                self.clock.advance(k, 1);
+            }
+        } else {
+            // Wake for bytes arriving over a faulty link, too
+            let mut deadline = machine::idle_deadline(k, &tick);
+            for port in self.ports.iter() {
+                if let Some(next) = port.next_event(now) {
+                    deadline = deadline.min(k.timer().now() + next.saturating_sub(now));
+                }
+            }
+            self.clock.advance_to(k, deadline);
+        }
+
+        None
+    }
+
+    /// Run for (at least) the given amount of virtual time.
+    ///
+    /// Returns the exit status of the simulator if the kernel stopped.
+    pub fn run_for(&mut self, duration: Duration) -> Option<i32> {
+        let until = self.clock.elapsed() + duration;
+        while self.clock.elapsed() < until {
+            if let Some(status) = self.step() {
+                return Some(status);
+            }
+        }
+        None
+    }
+
+    /// Run until `done` returns `true`, the kernel stops, or `timeout` of
+    /// virtual time has elapsed.
+    ///
+    /// Returns whether `done` returned `true`.
+    pub fn run_until(&mut self, timeout: Duration, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let until = self.clock.elapsed() + timeout;
+        loop {
+            if done(self) {
+                return true;
+            }
+            if self.clock.elapsed() >= until || self.step().is_some() {
+                return done(self);
+            }
+        }
+    }
+
+    /// Request a shutdown, and run until the kernel stops, returning the exit
+    /// status of the simulator.
+    pub fn shutdown(mut self) -> i32 {
+        self.shutdown_requested = true;
+        loop {
+            if let Some(status) = self.step() {
+                return status;
+            }
+        }
+    }
+
+    /// The exit status of the simulator, if the kernel has stopped
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Everything received from the kernel on the given serial mux port so far
+    pub fn output(&self, port: u16) -> &[u8] {
+        self.output.get(&port).map_or(&[], Vec::as_slice)
+    }
+
+    /// Take everything received from the kernel on the given serial mux port
+    /// so far.
+    pub fn take_output(&mut self, port: u16) -> Vec<u8> {
+        self.output.remove(&port).unwrap_or_default()
+    }
+
+    /// Take everything received from the kernel so far, on every serial mux
+    /// port.
+    pub fn drain_output(&mut self) -> BTreeMap<u16, Vec<u8>> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Everything received from the kernel on one of the other serial ports
+    /// so far
+    pub fn uart_output(&self, uart: u8) -> &[u8] {
+        self.uart_output.get(&uart).map_or(&[], Vec::as_slice)
+    }
+
+    /// Take everything received from the kernel so far, on every serial port
+    /// other than port 0.
+    pub fn drain_uart_output(&mut self) -> BTreeMap<u8, Vec<u8>> {
+        std::mem::take(&mut self.uart_output)
+    }
+
+    /// Is a driver service of type `RD` registered?
+    ///
+    /// Panics if a kernel task is holding the registry's lock.
+    pub fn is_registered<RD: RegisteredDriver>(&self) -> bool {
+        self.k
+            .try_with_registry(|reg| reg.contains::<RD>())
+            .expect("the registry is locked")
+    }
+
+    /// The trace events recorded so far
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Has a trace event matching `f` been recorded?
+    pub fn has_event(&self, f: impl FnMut(&TraceEvent) -> bool) -> bool {
+        self.events.lock().unwrap().iter().any(f)
+    }
+}
+
+// TraceEvent
+
+impl TraceEvent {
+    /// The value of one of the event's fields, formatted with `Debug`
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Visit for TraceEvent {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push((field.name(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields.push((field.name(), format!("{value:?}")));
+        }
+    }
+}
+
+// Recorder
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        // More verbose levels compare greater
+        if *meta.level() > self.level {
+            return;
+        }
+        let mut recorded = TraceEvent {
+            level: *meta.level(),
+            target: meta.target().to_string(),
+            message: String::new(),
+            fields: Vec::new(),
+        };
+        event.record(&mut recorded);
+        self.events.lock().unwrap().push(recorded);
+    }
+}
@@ -1,3 +1,10 @@
+pub mod apps;
 pub mod cli;
+pub mod config;
+pub mod doorbell;
+pub mod harness;
+pub mod machine;
 pub mod sim_drivers;
+pub mod sim_faults;
+pub mod sim_time;
 pub mod sim_tracing;
@@ -0,0 +1,283 @@
+//! The simulated machine
+//!
+//! Booting the kernel, its drivers and userspace, and deciding when the
+//! machine stops, is the same whether the simulator runs in real time or in
+//! virtual time, and whether it is run from the command line or by
+//! [Harness](crate::harness::Harness). Only the serial port and the main loop
+//! differ.
+
+use std::{panic, time::Duration};
+
+use mnemos_kernel::{
+    drivers::{
+        serial_mux::{SerialMux, SerialMuxHandle},
+        user_block::UserBlock,
+        user_serial::UserSerial,
+    },
+    timer::Instant,
+    Kernel, KernelSettings, TickOutcome,
+};
+use tracing::Instrument;
+
+use crate::{
+    apps::App,
+    config::{Config, KernelConfig, PortTask},
+    sim_drivers::{file_block::FileBlockStorage, wav_sink::WavSink},
+};
+
+/// How long drivers are given to stop their tasks after a shutdown is requested
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest the kernel sleeps while idle, so that the run time limit and
+/// shutdown timeout are still checked
+pub const MAX_IDLE: Duration = Duration::from_millis(100);
+
+/// Create the kernel, and the heap that will be given to userspace.
+pub fn new_kernel(config: &KernelConfig) -> (&'static Kernel, &'static mut [u8]) {
+    // First, we'll do some stuff that later the linker script will do...
+    let kernel_heap = Box::leak(vec![0u8; config.heap_size].into_boxed_slice());
+    let user_heap = Box::leak(vec![0u8; config.user_heap_size].into_boxed_slice());
+
+    let settings = KernelSettings {
+        heap_start: kernel_heap.as_mut_ptr(),
+        heap_size: kernel_heap.len(),
+        max_drivers: config.max_drivers,
+        max_tasks: config.max_tasks,
+        timer_granularity: config.timer_granularity(),
+        max_pending_messages: config.max_pending_messages,
+        k2u_size: config.k2u_size,
+        u2k_size: config.u2k_size,
+    };
+
+    let k = unsafe { Kernel::new(settings).unwrap().leak().as_ref() };
+    (k, user_heap)
+}
+
+/// Set up the drivers and tasks that sit on top of the serial port, which
+/// must already be registered, and the block storage and audio output
+/// devices, if there are any.
+pub async fn init_drivers(k: &'static Kernel, config: &Config) {
+    if let Some(storage) = config.block_storage.as_ref() {
+        let device = FileBlockStorage::open(storage).unwrap_or_else(|error| {
+            panic!(
+                "failed to open block storage image {}: {error}",
+                storage.image.display()
+            )
+        });
+        device.register(k).await.unwrap();
+        // Userspace keeps named blocks on the device, if their metadata fits
+        if let Err(error) = UserBlock::register(k).await {
+            tracing::warn!(?error, "Userspace can't use the block storage device");
+        }
+    }
+
+    if let Some(sink) = config.pcm_sink.as_ref() {
+        let device = WavSink::create(sink).unwrap_or_else(|error| {
+            panic!("failed to create WAV file {}: {error}", sink.wav.display())
+        });
+        device.register(k).await.unwrap();
+    }
+
+    SerialMux::register(k, config.serial_mux.max_ports, config.serial_mux.max_frame)
+        .await
+        .unwrap();
+
+    let mut mux_hdl = SerialMuxHandle::from_registry(k).await.unwrap();
+    for port in config.ports.iter() {
+        let handle = mux_hdl.open_port(port.port, port.capacity).await.unwrap();
+        match port.task {
+            PortTask::Loopback => {
+                k.spawn_named(
+                    "Loopback",
+                    async move {
+                        while let Some(rgr) = k.until_shutdown(handle.consumer().read_grant()).await
+                        {
+                            let len = rgr.len();
+                            handle.send(&rgr).await;
+                            rgr.release(len);
+                        }
+                    }
+                    .instrument(tracing::info_span!("Loopback", port = port.port)),
+                )
+                .await;
+            }
+            PortTask::Hello => {
+                k.spawn_named(
+                    "Hello Loop",
+                    async move {
+                        let mut interval = k.timer().interval(Duration::from_secs(1));
+                        while k.until_shutdown(interval.tick()).await.is_some() {
+                            handle.send(b"hello\r\n").await;
+                        }
+                    }
+                    .instrument(tracing::info_span!("Hello Loop", port = port.port)),
+                )
+                .await;
+            }
+        }
+    }
+    drop(mux_hdl);
+
+    // Let userspace open any ports the kernel hasn't
+    if config.user_serial.enabled {
+        UserSerial::register(
+            k,
+            config.user_serial.max_ports,
+            config.user_serial.port_capacity,
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Log the kernel's tasks, then start userspace, running `app` if one was
+/// selected.
+///
+/// # Safety
+///
+/// Userspace's runtime is global, so this must only be called once per
+/// process, with the userspace heap returned by [new_kernel].
+pub unsafe fn start_userspace(k: &'static Kernel, user_heap: &'static mut [u8], app: Option<App>) {
+    k.try_with_tasks(|tasks| {
+        for task in tasks.iter() {
+            tracing::debug!(
+                task.id = ?task.id,
+                task.name = ?task.name,
+                task.state = ?task.state,
+                task.polls = task.polls,
+                "Kernel task"
+            );
+        }
+    });
+
+    // Publish the syscall rings and the userspace heap, as the bootloader
+    // would, then let the runtime pick them up.
+    k.init_userspace(user_heap.as_mut_ptr(), user_heap.len());
+    mstd::runtime::init();
+    if let Some(app) = app {
+        tracing::info!(?app, "Starting userspace app");
+        app.spawn();
+    }
+}
+
+/// The state of the simulated machine shared by the main loops. Times passed
+/// in are measured by the main loop, from any fixed starting point.
+///
+/// Like a program run on the host, the simulator stops once userspace halts,
+/// and exits with its exit code.
+pub struct Machine {
+    k: &'static Kernel,
+    userspace_span: tracing::Span,
+    userspace_alive: bool,
+    exit_code: i32,
+    run_for: Option<Duration>,
+    shutdown_deadline: Option<Duration>,
+}
+
+impl Machine {
+    pub fn new(k: &'static Kernel, run_for: Option<Duration>) -> Self {
+        Self {
+            k,
+            userspace_span: tracing::info_span!("userspace"),
+            userspace_alive: true,
+            exit_code: 0,
+            run_for,
+            shutdown_deadline: None,
+        }
+    }
+
+    /// Is userspace still running?
+    pub fn userspace_alive(&self) -> bool {
+        self.userspace_alive
+    }
+
+    /// Request a shutdown if one is due, `requested` is set, or userspace has
+    /// halted.
+    pub fn before_tick(&mut self, now: Duration, requested: bool) {
+        let ran_out = matches!(self.run_for, Some(dur) if now >= dur);
+        let halted = !self.userspace_alive;
+        if ran_out || requested || halted {
+            self.request_shutdown(now);
+        }
+    }
+
+    /// Ask the kernel to shut down, unless it already has been.
+    fn request_shutdown(&mut self, now: Duration) {
+        if self.shutdown_deadline.is_none() {
+            self.k.shutdown();
+            self.shutdown_deadline = Some(now + SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    /// Note whether the kernel saw userspace halt, then return the exit status
+    /// of the simulator once the kernel has shut down, or has failed to in
+    /// time.
+    pub fn after_tick(&mut self, now: Duration, tick: &TickOutcome) -> Option<i32> {
+        if let (true, Some(code)) = (self.userspace_alive, tick.userspace_halted) {
+            self.userspace_alive = false;
+            self.exit_code = code;
+            // Before the main loop has a chance to go idle
+            self.request_shutdown(now);
+        }
+
+        if self.k.is_shut_down() {
+            tracing::info!("Kernel shut down cleanly.");
+            return Some(self.exit_code);
+        }
+
+        if matches!(self.shutdown_deadline, Some(deadline) if now >= deadline) {
+            tracing::error!("Timed out waiting for kernel tasks to stop!");
+            self.k.try_with_tasks(|tasks| {
+                for task in tasks.iter().filter(|t| !t.state.is_finished()) {
+                    tracing::error!(
+                        task.id = ?task.id,
+                        task.name = ?task.name,
+                        task.state = ?task.state,
+                        task.polls = task.polls,
+                        "Task still running"
+                    );
+                }
+            });
+            return Some(1);
+        }
+
+        None
+    }
+
+    /// Give userspace a turn on the CPU.
+    pub fn run_userspace(&mut self) {
+        match self
+            .userspace_span
+            .in_scope(|| panic::catch_unwind(mstd::runtime::run_once))
+        {
+            Ok(None) => {}
+            Ok(Some(code)) => {
+                tracing::info!(code, "Userspace app exited");
+                self.userspace_alive = false;
+                self.exit_code = code;
+            }
+            Err(payload) => {
+                // On hardware the panic handler would print the message and
+                // halt userspace, do the same here.
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("Box<dyn Any>");
+                tracing::error!(message, "Userspace panicked!");
+                mstd::runtime::report_panic(&format_args!("panicked: {message}"));
+                self.userspace_alive = false;
+                self.exit_code = mstd::PANIC_EXIT_CODE;
+            }
+        }
+    }
+}
+
+/// The instant a kernel with nothing to do should next be woken, in virtual
+/// time.
+pub fn idle_deadline(k: &'static Kernel, tick: &TickOutcome) -> Instant {
+    let max = k.timer().now() + MAX_IDLE;
+    match tick.next_deadline {
+        Some(deadline) => deadline.min(max),
+        None => max,
+    }
+}
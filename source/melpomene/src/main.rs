@@ -1,60 +1,86 @@
 use std::{
     sync::atomic::{AtomicBool, Ordering},
-    thread::{sleep, spawn},
+    time::Instant,
 };
 
-use abi::bbqueue_ipc::BBBuffer;
 use clap::Parser;
 use melpomene::{
     cli::{self, MelpomeneOptions},
-    sim_drivers::{delay::Delay, tcp_serial::TcpSerial},
-};
-use mnemos_kernel::{
-    drivers::serial_mux::{SerialMux, SerialMuxHandle},
-    Kernel, KernelSettings,
+    config::{BlockStorageConfig, Config, PcmSinkConfig},
+    doorbell::KERNEL_DOORBELL,
+    harness::{Harness, HarnessSettings},
+    machine::{self, Machine, MAX_IDLE},
+    sim_drivers::serial_ports::{self, SerialAddr, SerialPorts},
+    sim_faults,
 };
+use mnemos_kernel::{Kernel, TickOutcome};
 use tokio::{
+    sync::oneshot,
     task,
     time::{self, Duration},
 };
 
 use tracing::Instrument;
 
-const HEAP_SIZE: usize = 192 * 1024;
-static KERNEL_LOCK: AtomicBool = AtomicBool::new(true);
+/// Set to request that the kernel shuts down
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
 fn main() {
     let args = cli::Args::parse();
     args.tracing.setup_tracing();
     let _span = tracing::info_span!("Melpo").entered();
-    run_melpomene(args.melpomene);
+    let mut config = match args.melpomene.config.as_deref() {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(error) => {
+                tracing::error!(path = %path.display(), %error, "Failed to load the config");
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+    if let Some(image) = args.melpomene.block_image.clone() {
+        match config.block_storage.as_mut() {
+            Some(storage) => storage.image = image,
+            None => config.block_storage = Some(BlockStorageConfig::new(image)),
+        }
+    }
+    if let Some(wav) = args.melpomene.wav.clone() {
+        match config.pcm_sink.as_mut() {
+            Some(sink) => sink.wav = wav,
+            None => config.pcm_sink = Some(PcmSinkConfig::new(wav)),
+        }
+    }
+    let status = match args.melpomene.seed {
+        Some(seed) => run_deterministic(args.melpomene, config, seed),
+        None => run_melpomene(args.melpomene, config),
+    };
+    std::process::exit(status);
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn run_melpomene(opts: cli::MelpomeneOptions) {
+async fn run_melpomene(opts: cli::MelpomeneOptions, config: Config) -> i32 {
     println!("========================================");
-    let kernel = task::spawn_blocking(move || {
-        kernel_entry(opts);
-    });
+    let (initialized_tx, initialized_rx) = oneshot::channel();
+    let kernel = task::spawn_blocking(move || kernel_entry(opts, config, initialized_tx));
     tracing::info!("Kernel started.");
 
+    // Request a shutdown of the kernel on Ctrl-C
+    let _ = tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("Received Ctrl-C, shutting down...");
+            SHUTDOWN.store(true, Ordering::Release);
+            KERNEL_DOORBELL.ring();
+        }
+    });
+
     // Wait for the kernel to complete initialization...
-    while KERNEL_LOCK.load(Ordering::Acquire) {
-        task::yield_now().await;
+    if initialized_rx.await.is_ok() {
+        tracing::debug!("Kernel initialized.");
     }
 
-    tracing::debug!("Kernel initialized.");
-
-    // let userspace = spawn(move || {
-    //     userspace_entry();
-    // });
-    // println!("[Melpo]: Userspace started.");
-    // println!("========================================");
-
-    // let uj = userspace.join();
     println!("========================================");
     time::sleep(Duration::from_millis(50)).await;
-    // println!("[Melpo]: Userspace ended: {:?}", uj);
 
     let kj = kernel.await;
     time::sleep(Duration::from_millis(50)).await;
@@ -62,183 +88,183 @@ async fn run_melpomene(opts: cli::MelpomeneOptions) {
 
     println!("========================================");
 
-    tracing::error!("You've met with a terrible fate, haven't you?");
+    match kj {
+        Ok(status) => status,
+        Err(_) => {
+            tracing::error!("You've met with a terrible fate, haven't you?");
+            1
+        }
+    }
 }
 
-#[tracing::instrument(name = "Kernel", level = "info", skip(opts))]
-fn kernel_entry(opts: MelpomeneOptions) {
-    // First, we'll do some stuff that later the linker script will do...
-    let kernel_heap = Box::into_raw(Box::new([0u8; HEAP_SIZE]));
-    let user_heap = Box::into_raw(Box::new([0u8; HEAP_SIZE]));
-
-    let settings = KernelSettings {
-        heap_start: kernel_heap.cast(),
-        heap_size: HEAP_SIZE,
-        max_drivers: 16,
-        k2u_size: 4096,
-        u2k_size: 4096,
-    };
+/// Runs the kernel until it has shut down, returning the exit status of the
+/// simulator.
+#[tracing::instrument(name = "Kernel", level = "info", skip(opts, config, initialized))]
+fn kernel_entry(opts: MelpomeneOptions, config: Config, initialized: oneshot::Sender<()>) -> i32 {
+    // Port 0's address may be overridden on the command line, and any port
+    // without an address listens next to port 0's default.
+    let serial_addrs: Vec<SerialAddr> = config
+        .serial
+        .iter()
+        .enumerate()
+        .map(|(i, serial)| {
+            let cli_addr = if i == 0 {
+                opts.serial_addr.clone()
+            } else {
+                None
+            };
+            cli_addr
+                .or_else(|| serial.addr.clone())
+                .unwrap_or_else(|| serial_ports::default_addr(i as u8))
+        })
+        .collect();
+    let faults = opts.faults.clone();
+    let (k, user_heap) = machine::new_kernel(&config.kernel);
 
-    let k = unsafe { Kernel::new(settings).unwrap().leak().as_ref() };
-
-    // First let's make a dummy driver just to make sure some stuff happens
     let initialization_future = async move {
         // Delay for one second, just for funsies
-        Delay::new(Duration::from_secs(1)).await;
-
-        // Set up the bidirectional, async bbqueue channel between the TCP port
-        // (acting as a serial port) and the virtual serial port mux.
-        //
-        // Create the buffer, and spawn the worker task, giving it one of the
-        // queue handles
-        TcpSerial::register(k, opts.serial_addr, 4096, 4096)
-            .await
-            .unwrap();
-
-        // Now, right now this is a little awkward, but what I'm doing here is spawning
-        // a new virtual mux, and configuring it with:
-        // * Up to 4 virtual ports max
-        // * Framed messages up to 512 bytes max each
-        SerialMux::register(k, 4, 512).await.unwrap();
-
-        let mut mux_hdl = SerialMuxHandle::from_registry(k).await.unwrap();
-        let p0 = mux_hdl.open_port(0, 1024).await.unwrap();
-        let p1 = mux_hdl.open_port(1, 1024).await.unwrap();
-        drop(mux_hdl);
-
-        k.spawn(
-            async move {
-                loop {
-                    let rgr = p0.consumer().read_grant().await;
-                    let len = rgr.len();
-                    p0.send(&rgr).await;
-                    rgr.release(len);
-                }
-            }
-            .instrument(tracing::info_span!("Loopback")),
-        )
-        .await;
-
-        // Now we just send out data every second
-        k.spawn(
-            async move {
-                loop {
-                    Delay::new(Duration::from_secs(1)).await;
-                    p1.send(b"hello\r\n").await;
-                }
+        k.timer().sleep(Duration::from_secs(1)).await;
+
+        // Set up the bidirectional, async bbqueue channel between each socket
+        // or pty (acting as a serial port) and the kernel, then hand them all
+        // to the serial ports driver. Port 0 carries the virtual serial port
+        // mux.
+        let mut ports = Vec::with_capacity(serial_addrs.len());
+        for (serial, addr) in config.serial.iter().zip(serial_addrs) {
+            let mut port = serial_ports::open_port(k, addr, serial).await.unwrap();
+            if faults.is_enabled() {
+                let i = ports.len() as u8;
+                port = sim_faults::inject(
+                    k,
+                    port,
+                    &faults,
+                    i,
+                    serial.incoming_size,
+                    serial.outgoing_size,
+                )
+                .await;
             }
-            .instrument(tracing::info_span!("Hello Loop")),
-        )
-        .await;
+            ports.push(port);
+        }
+        SerialPorts::register(k, ports).await.unwrap();
+
+        machine::init_drivers(k, &config).await;
     }
     .instrument(tracing::info_span!("Initialize"));
 
-    k.initialize(initialization_future).unwrap();
-
-    //////////////////////////////////////////////////////////////////////////////
-    // TODO: Userspace doesn't really do anything yet! Simulate initialization of
-    // the userspace structures, and just periodically wake the kernel for now.
-    //////////////////////////////////////////////////////////////////////////////
+    let init = k.initialize(initialization_future).unwrap();
 
-    let rings = k.rings();
+    // Tick the kernel until initialization has actually completed, before
+    // starting userspace.
+    let mut clock = SimClock::new();
+    while !init.is_finished() {
+        let tick = clock.tick_kernel(k);
+        if !tick.has_remaining {
+            idle(k, &tick);
+        }
+    }
+    // SAFETY: this is the only time userspace is started
     unsafe {
-        let urings = mstd::executor::mailbox::Rings {
-            u2k: BBBuffer::take_framed_producer(rings.u2k.as_ptr()),
-            k2u: BBBuffer::take_framed_consumer(rings.k2u.as_ptr()),
-        };
-        mstd::executor::mailbox::MAILBOX.set_rings(urings);
-        mstd::executor::EXECUTOR.initialize(user_heap.cast(), HEAP_SIZE);
+        machine::start_userspace(k, user_heap, opts.app);
     }
+    let _ = initialized.send(());
 
-    let _userspace = spawn(|| {
-        let _span = tracing::info_span!("userspace").entered();
-        loop {
-            while KERNEL_LOCK.load(Ordering::Acquire) {
-                sleep(Duration::from_millis(10));
-            }
+    // The kernel and userspace share the simulated CPU. Userspace is run
+    // whenever the kernel says it is ready, and when neither has anything to
+    // do, the CPU sleeps until the next deadline, or until the doorbell rings.
+    let mut machine = Machine::new(k, opts.run_for.map(Duration::from_secs));
+    let start = Instant::now();
+    loop {
+        machine.before_tick(start.elapsed(), SHUTDOWN.load(Ordering::Acquire));
+        let tick = clock.tick_kernel(k);
+        if let Some(status) = machine.after_tick(start.elapsed(), &tick) {
+            return status;
+        }
 
-            mstd::executor::EXECUTOR.run();
-            KERNEL_LOCK.store(true, Ordering::Release);
+        if tick.userspace_ready && machine.userspace_alive() {
+            machine.run_userspace();
+        } else if !tick.has_remaining {
+            idle(k, &tick);
         }
+    }
+}
+
+/// Runs the kernel and userspace in virtual time, on a schedule decided by a
+/// random number generator seeded with `seed`, returning the exit status of
+/// the simulator.
+///
+/// Nothing here depends on the host's clock or its scheduler, so a run with
+/// the same seed and options always does the same thing, however long it
+/// takes. Data sent by the kernel over the serial ports is printed to stdout.
+#[tracing::instrument(name = "Kernel", level = "info", skip(opts, config))]
+fn run_deterministic(opts: MelpomeneOptions, config: Config, seed: u64) -> i32 {
+    let mut harness = Harness::new(HarnessSettings {
+        seed,
+        config,
+        start_userspace: true,
+        app: opts.app,
+        run_for: opts.run_for.map(Duration::from_secs),
+        trace_level: None,
+        faults: opts.faults,
     });
+    tracing::info!(seed, "Running in virtual time");
 
     loop {
-        while !KERNEL_LOCK.load(Ordering::Acquire) {
-            sleep(Duration::from_millis(10));
+        let status = harness.step();
+        for (port, data) in harness.drain_output() {
+            print!(
+                "[{:>10.3?} port {port}] {}",
+                harness.elapsed(),
+                String::from_utf8_lossy(&data)
+            );
         }
+        for (uart, data) in harness.drain_uart_output() {
+            print!(
+                "[{:>10.3?} uart {uart}] {}",
+                harness.elapsed(),
+                String::from_utf8_lossy(&data)
+            );
+        }
+        if let Some(status) = status {
+            return status;
+        }
+    }
+}
 
-        k.tick();
-
-        KERNEL_LOCK.store(false, Ordering::Release);
+/// Sleep until the next deadline, or until the doorbell is rung.
+fn idle(k: &'static Kernel, tick: &TickOutcome) {
+    let timeout = match tick.next_deadline {
+        Some(deadline) => (deadline - k.timer().now()).min(MAX_IDLE),
+        None => MAX_IDLE,
+    };
+    if !timeout.is_zero() {
+        tracing::trace!(?timeout, "Kernel idle");
+        KERNEL_DOORBELL.wait_timeout(timeout);
     }
 }
 
-// fn userspace_entry() {
-//     use mstd::alloc::HEAP;
-
-//     // Set up kernel rings
-//     let u2k = unsafe { BBBuffer::take_framed_producer(abi::U2K_RING.load(Ordering::Acquire)) };
-//     let k2u = unsafe { BBBuffer::take_framed_consumer(abi::K2U_RING.load(Ordering::Acquire)) };
-
-//     // Set up allocator
-//     let mut hg = HEAP.init_exclusive(
-//         abi::HEAP_PTR.load(Ordering::Acquire) as usize,
-//         abi::HEAP_LEN.load(Ordering::Acquire),
-//     ).unwrap();
-
-//     // Set up executor
-//     let terpsichore = &mstd::executor::EXECUTOR;
-
-//     // Spawn the `main` task
-//     let mtask = mstd::executor::Task::new(async move {
-//         aman().await
-//     });
-//     let hbmtask = hg.alloc_box(mtask).map_err(drop).unwrap();
-//     drop(hg);
-//     let hg2 = HEAP.lock().unwrap();
-//     drop(hg2);
-//     let _mhdl = mstd::executor::spawn_allocated(hbmtask);
-
-//     let rings = Rings {
-//         u2k,
-//         k2u,
-//     };
-//     mstd::executor::mailbox::MAILBOX.set_rings(rings);
-
-//     let start = Instant::now();
-//     loop {
-//         *mstd::executor::time::CURRENT_TIME.borrow_mut().unwrap() = start.elapsed().as_micros() as u64;
-//         terpsichore.run();
-//         KERNEL_LOCK.store(true, Ordering::Release);
-//         while KERNEL_LOCK.load(Ordering::Acquire) {
-//             sleep(Duration::from_millis(50));
-//         }
-//     }
-
-// }
-
-// async fn aman() -> Result<(), ()> {
-//     mstd::executor::spawn(async {
-//         for _ in 0..3 {
-//             println!("[ST1] Hi, I'm aman's subtask!");
-//             Sleepy::new(Duration::from_secs(3)).await;
-//         }
-//         println!("[ST1] subtask done!");
-//     }).await;
-
-//     mstd::executor::spawn(async {
-//         for _ in 0..3 {
-//             let msg = UserRequestBody::Serial(SerialRequest::Flush);
-//             println!("[ST2] Sending to kernel: {:?}", msg);
-//             let resp = MAILBOX.request(msg).await;
-//             println!("[ST2] Kernel said: {:?}", resp);
-//             Sleepy::new(Duration::from_secs(2)).await;
-//         }
-//         println!("[ST2] subtask done!");
-//     }).await;
-
-//     loop {
-//         println!("[MT] Hi, I'm aman!");
-//         Sleepy::new(Duration::from_secs(1)).await;
-//     }
-// }
+/// Feeds the real time elapsed between kernel ticks into the kernel's timer.
+struct SimClock {
+    last: Instant,
+}
+
+impl SimClock {
+    fn new() -> Self {
+        Self {
+            last: Instant::now(),
+        }
+    }
+
+    /// Report any whole timer ticks that have elapsed, then tick the kernel.
+    fn tick_kernel(&mut self, k: &'static Kernel) -> TickOutcome {
+        let granularity = k.timer().granularity();
+        let elapsed = self.last.elapsed();
+        let ticks = (elapsed.as_micros() / granularity.as_micros()) as u32;
+        if ticks > 0 {
+            k.timer().pend_ticks(ticks);
+            // Only consume whole ticks, so the remainder isn't lost
+            self.last += granularity * ticks;
+        }
+        k.tick()
+    }
+}
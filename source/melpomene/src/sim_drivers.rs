@@ -1,2 +1,6 @@
-pub mod delay;
+pub mod file_block;
+pub mod pty_serial;
+pub mod serial_ports;
 pub mod tcp_serial;
+pub mod virtual_serial;
+pub mod wav_sink;
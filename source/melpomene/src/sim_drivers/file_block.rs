@@ -0,0 +1,182 @@
+//! A block storage device backed by an image file on the host
+//!
+//! The image holds each block's bytes, one block after another, and nothing
+//! else, so it can be inspected, or prepared, with ordinary tools. A missing
+//! image is created fully erased.
+//!
+//! Reads and writes go straight to the file, and behave like flash: see
+//! [block_storage](mnemos_kernel::registry::block_storage). Everything
+//! written is in the image as soon as the kernel is told it was written.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+};
+
+use mnemos_kernel::{
+    comms::kchannel::KChannel,
+    registry::{
+        block_storage::{BlockStorage, BlockStorageError, Request, Response, StoreInfo},
+        Message,
+    },
+    Kernel,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::BlockStorageConfig;
+
+/// The value of every byte of an erased block
+pub const ERASED: u8 = 0xFF;
+
+pub struct FileBlockStorage {
+    file: File,
+    info: StoreInfo,
+}
+
+impl FileBlockStorage {
+    /// Open the image described by `config`, creating it if it doesn't
+    /// exist.
+    ///
+    /// An existing image must be exactly the size of the device.
+    pub fn open(config: &BlockStorageConfig) -> io::Result<Self> {
+        let info = StoreInfo {
+            blocks: config.blocks,
+            capacity: config.capacity,
+        };
+        let size = u64::from(info.blocks) * u64::from(info.capacity);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&config.image)?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            info!(image = %config.image.display(), size, "Creating erased block storage image");
+            let block = vec![ERASED; info.capacity as usize];
+            for i in 0..u64::from(info.blocks) {
+                file.write_all_at(&block, i * u64::from(info.capacity))?;
+            }
+        } else if len != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is {len} bytes, but {} blocks of {} bytes need {size}",
+                    config.image.display(),
+                    info.blocks,
+                    info.capacity,
+                ),
+            ));
+        }
+
+        Ok(Self { file, info })
+    }
+
+    /// Register the device as the kernel's [BlockStorage] driver.
+    pub async fn register(self, kernel: &'static Kernel) -> Result<(), ()> {
+        let (prod, cons) = KChannel::<Message<BlockStorage>>::new_async(kernel, 2)
+            .await
+            .split();
+
+        kernel
+            .spawn_named("FileBlockStorage", async move {
+                // Our request channel is closed when the kernel shuts down.
+                while let Ok(req) = cons.dequeue_async().await {
+                    let Message { msg, reply } = req;
+                    let resp = msg.reply_with_body(|body| match body {
+                        Request::StoreInfo => Ok(Response::StoreInfo(self.info)),
+                        Request::Read {
+                            block,
+                            offset,
+                            mut buf,
+                            len,
+                        } => self
+                            .read(block, offset, &mut buf, len)
+                            .map(|()| Response::Read { buf }),
+                        Request::Write {
+                            block,
+                            offset,
+                            buf,
+                            len,
+                        } => self
+                            .write(block, offset, &buf, len)
+                            .map(|()| Response::Written { buf }),
+                        Request::Erase { block } => self.erase(block).map(|()| Response::Erased),
+                    });
+                    reply.reply_konly(resp).await.map_err(drop).unwrap();
+                }
+            })
+            .await;
+
+        kernel
+            .with_registry(|reg| reg.register_konly::<BlockStorage>(&prod))
+            .await
+            .map_err(drop)
+    }
+
+    fn read(
+        &self,
+        block: u32,
+        offset: u32,
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<(), BlockStorageError> {
+        let pos = self.position(block, offset, buf, len)?;
+        debug!(block, offset, len, "Reading block");
+        self.file
+            .read_exact_at(&mut buf[..len], pos)
+            .map_err(device_error)
+    }
+
+    fn write(
+        &self,
+        block: u32,
+        offset: u32,
+        buf: &[u8],
+        len: usize,
+    ) -> Result<(), BlockStorageError> {
+        let pos = self.position(block, offset, buf, len)?;
+        debug!(block, offset, len, "Writing block");
+        // Writing can only clear bits, as on flash
+        let mut bytes = vec![0; len];
+        self.file
+            .read_exact_at(&mut bytes, pos)
+            .map_err(device_error)?;
+        for (old, new) in bytes.iter_mut().zip(&buf[..len]) {
+            *old &= new;
+        }
+        self.file.write_all_at(&bytes, pos).map_err(device_error)
+    }
+
+    fn erase(&self, block: u32) -> Result<(), BlockStorageError> {
+        let capacity = self.info.capacity as usize;
+        let pos = self.position(block, 0, &[], 0)?;
+        debug!(block, "Erasing block");
+        self.file
+            .write_all_at(&vec![ERASED; capacity], pos)
+            .map_err(device_error)
+    }
+
+    /// Where in the image `len` bytes of `buf`, `offset` bytes into `block`,
+    /// start.
+    fn position(
+        &self,
+        block: u32,
+        offset: u32,
+        buf: &[u8],
+        len: usize,
+    ) -> Result<u64, BlockStorageError> {
+        if len > buf.len() {
+            return Err(BlockStorageError::OutOfBounds);
+        }
+        self.info.check_bounds(block, offset, len)?;
+        Ok(u64::from(block) * u64::from(self.info.capacity) + u64::from(offset))
+    }
+}
+
+fn device_error(error: io::Error) -> BlockStorageError {
+    warn!(%error, "Block storage image I/O failed");
+    BlockStorageError::DeviceError
+}
@@ -0,0 +1,139 @@
+//! A simulated UART, backed by a pseudo-terminal
+//!
+//! The simulator holds the master side of a new pty, and prints the path of
+//! its slave side, like `/dev/pts/3`. That behaves like any other serial
+//! device, so `screen`, `minicom`, or host software using the `serialport`
+//! crate can talk to the kernel unchanged.
+
+use crate::doorbell::KERNEL_DOORBELL;
+use mnemos_kernel::{
+    comms::bbq::{new_bidi_channel, BidiHandle},
+    Kernel,
+};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    pty::{openpty, OpenptyResult},
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+    unistd,
+};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::{self, unix::AsyncFd};
+use tracing::{info, info_span, trace, warn, Instrument};
+
+pub struct PtySerial {
+    _inner: (),
+}
+
+impl PtySerial {
+    /// Create a pty, print the path of its slave side, and return the
+    /// kernel's end of the port.
+    pub async fn open(
+        kernel: &'static Kernel,
+        incoming_size: usize,
+        outgoing_size: usize,
+    ) -> io::Result<BidiHandle> {
+        let (a_ring, b_ring) = new_bidi_channel(kernel.heap(), incoming_size, outgoing_size).await;
+
+        let OpenptyResult { master, slave } = openpty(None, None)?;
+        // SAFETY: `openpty` just opened these, and nothing else owns them.
+        let (master, slave) =
+            unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        // Pass bytes through untouched, rather than echoing them or editing
+        // lines, as a terminal would.
+        let mut termios = tcgetattr(slave.as_raw_fd())?;
+        cfmakeraw(&mut termios);
+        tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+        let path = unistd::ttyname(slave.as_raw_fd())?;
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let master = AsyncFd::new(master)?;
+
+        // Tracing may be turned off, but the path is needed to connect at all
+        println!("Serial port available at {}", path.display());
+        info!(path = %path.display(), "PTY serial port driver ready");
+
+        tokio::spawn(
+            async move {
+                // Hold the slave side open, so that the master side isn't hung
+                // up while no client has the device open.
+                let _slave = slave;
+                process_pty(kernel, a_ring, master).await
+            }
+            .instrument(info_span!("PTY Serial", path = %path.display())),
+        );
+
+        Ok(b_ring)
+    }
+}
+
+async fn process_pty(kernel: &'static Kernel, handle: BidiHandle, master: AsyncFd<OwnedFd>) {
+    loop {
+        // Wait until either the pty has data to read, or the other end of the
+        // BBQueue has data to write.
+        tokio::select! {
+            // The kernel is shutting down. Flush anything it has already
+            // written, then close the pty.
+            _ = kernel.wait_for_shutdown() => {
+                while let Some(outmsg) = handle.consumer().read_grant_sync() {
+                    trace!(len = outmsg.len(), "Flushing outgoing message");
+                    if let Err(error) = write_all(&master, &outmsg).await {
+                        warn!(%error, "Error flushing pty");
+                        return;
+                    }
+                    let len = outmsg.len();
+                    outmsg.release(len);
+                }
+                info!("Kernel shutting down, closing pty");
+                return;
+            }
+            // The kernel wants to write something.
+            outmsg = handle.consumer().read_grant() => {
+                trace!(len = outmsg.len(), "Got outgoing message");
+                if let Err(error) = write_all(&master, &outmsg).await {
+                    warn!(%error, "Error writing to pty");
+                    return;
+                }
+                let len = outmsg.len();
+                outmsg.release(len);
+                // The kernel may be waiting for room to write more
+                KERNEL_DOORBELL.ring();
+            }
+            // The pty has more bytes to read.
+            guard = master.readable() => {
+                let mut guard = match guard {
+                    Ok(guard) => guard,
+                    Err(error) => {
+                        warn!(%error, "Error polling pty");
+                        return;
+                    }
+                };
+                let mut in_grant = handle.producer().send_grant_max(256).await;
+                match guard.try_io(|fd| Ok(unistd::read(fd.as_raw_fd(), &mut in_grant)?)) {
+                    Ok(Ok(used)) => {
+                        trace!(len = used, "Got incoming message");
+                        in_grant.commit(used);
+                        KERNEL_DOORBELL.ring();
+                    }
+                    // Other errors indicate something is actually wrong.
+                    Ok(Err(error)) => {
+                        warn!(%error, "Error reading from pty");
+                        return;
+                    }
+                    // The readiness event was spurious, just wait again.
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+}
+
+async fn write_all(master: &AsyncFd<OwnedFd>, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let mut guard = master.writable().await?;
+        if let Ok(written) = guard.try_io(|fd| Ok(unistd::write(fd.as_raw_fd(), buf)?)) {
+            buf = &buf[written?..];
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+//! The simulated board's serial ports
+//!
+//! Only one driver of each kind can be registered, so the simulated UARTs
+//! aren't registered individually. Instead, [SerialPorts] is registered as
+//! the [SimpleSerial] driver, and hands out each UART by its number.
+//!
+//! Each UART is reached from outside the simulator at a [SerialAddr], which
+//! picks the driver backing it. Drivers that know when a client connects to
+//! or disconnects from their UART also report it to the kernel, as
+//! [LinkEvent]s.
+
+use std::{
+    fmt,
+    net::{AddrParseError, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use mnemos_kernel::{
+    comms::{
+        bbq::BidiHandle,
+        kchannel::{KChannel, KConsumer},
+    },
+    registry::{
+        simple_serial::{LinkEvent, Request, Response, SimpleSerial, SimpleSerialError},
+        Message,
+    },
+    Kernel,
+};
+use serde::Deserialize;
+use tokio::io;
+use tracing::debug;
+
+use super::{pty_serial::PtySerial, tcp_serial::TcpSerial};
+use crate::config::SerialConfig;
+
+pub struct SerialPorts {
+    _inner: (),
+}
+
+/// The kernel's end of a simulated UART
+pub struct Uart {
+    pub handle: BidiHandle,
+    /// Connects and disconnects, if the UART's driver reports them
+    pub link_events: Option<KConsumer<LinkEvent>>,
+}
+
+/// Where a simulated UART is reached from outside the simulator
+///
+/// Parsed from either a socket address, like `127.0.0.1:9999`, a path
+/// prefixed with `unix:`, like `unix:/tmp/uart0.sock`, or `pty`.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SerialAddr {
+    /// A TCP socket, served by [TcpSerial]
+    Tcp(SocketAddr),
+    /// A Unix socket, served by [TcpSerial]
+    Unix(PathBuf),
+    /// A new pseudo-terminal, served by [PtySerial]
+    Pty,
+}
+
+/// Open a UART reached at `addr`, returning the kernel's end of it.
+pub async fn open_port(
+    kernel: &'static Kernel,
+    addr: SerialAddr,
+    config: &SerialConfig,
+) -> io::Result<Uart> {
+    match addr {
+        SerialAddr::Pty => Ok(Uart {
+            handle: PtySerial::open(kernel, config.incoming_size, config.outgoing_size).await?,
+            link_events: None,
+        }),
+        addr => TcpSerial::open(kernel, addr, config).await,
+    }
+}
+
+/// The address serial port `port` listens on by default: 127.0.0.1:9999 for
+/// port 0, 127.0.0.1:10000 for port 1, and so on.
+pub fn default_addr(port: u8) -> SerialAddr {
+    SerialAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 9999 + u16::from(port))))
+}
+
+// SerialPorts
+
+impl SerialPorts {
+    /// Register the driver, giving away the kernel's end of each UART, in
+    /// order, so `ports[0]` is port 0.
+    pub async fn register(kernel: &'static Kernel, ports: Vec<Uart>) -> Result<(), ()> {
+        let (prod, cons) = KChannel::<Message<SimpleSerial>>::new_async(kernel, 2)
+            .await
+            .split();
+
+        kernel
+            .spawn_named("SerialPorts", async move {
+                let mut ports: Vec<(Option<BidiHandle>, Option<KConsumer<LinkEvent>>)> = ports
+                    .into_iter()
+                    .map(|uart| (Some(uart.handle), uart.link_events))
+                    .collect();
+
+                // Give each port, and its link events, to the first request
+                // for them, and deny all others. Our request channel is
+                // closed when the kernel shuts down.
+                while let Ok(req) = cons.dequeue_async().await {
+                    let resp = match req.msg.body {
+                        Request::GetPort { port } => match ports.get_mut(usize::from(port)) {
+                            Some((slot, _)) => match slot.take() {
+                                Some(handle) => {
+                                    debug!(port, "Handing out serial port");
+                                    Ok(Response::PortHandle { handle })
+                                }
+                                None => Err(SimpleSerialError::AlreadyAssignedPort),
+                            },
+                            None => Err(SimpleSerialError::NoSuchPort),
+                        },
+                        Request::GetLinkEvents { port } => match ports.get_mut(usize::from(port)) {
+                            Some((_, slot)) => match slot.take() {
+                                Some(events) => {
+                                    debug!(port, "Handing out serial port link events");
+                                    Ok(Response::LinkEvents { events })
+                                }
+                                None => Err(SimpleSerialError::NoLinkEvents),
+                            },
+                            None => Err(SimpleSerialError::NoSuchPort),
+                        },
+                    };
+                    let resp = req.msg.reply_with(resp);
+                    req.reply.reply_konly(resp).await.map_err(drop).unwrap();
+                }
+            })
+            .await;
+
+        kernel
+            .with_registry(|reg| reg.register_konly::<SimpleSerial>(&prod))
+            .await
+            .map_err(drop)
+    }
+}
+
+// SerialAddr
+
+impl FromStr for SerialAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "pty" {
+            return Ok(SerialAddr::Pty);
+        }
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(SerialAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(SerialAddr::Tcp),
+        }
+    }
+}
+
+impl TryFrom<String> for SerialAddr {
+    type Error = AddrParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for SerialAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialAddr::Tcp(addr) => addr.fmt(f),
+            SerialAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            SerialAddr::Pty => f.write_str("pty"),
+        }
+    }
+}
@@ -1,135 +1,333 @@
+//! A simulated UART, reached over a TCP or Unix socket
+//!
+//! Clients connect to the socket, and exchange bytes with the kernel, e.g.
+//! using `crowtty`. A client that disconnects, or fails, is dropped, and the
+//! port carries on waiting for the next one. What happens to the kernel's
+//! output while no client is connected, and to clients that connect while
+//! another already is, is set by the port's [SerialConfig].
+//!
+//! The link is up while at least one client is connected. The kernel is told
+//! when it comes up or goes down with a [LinkEvent].
+
+use crate::{
+    config::SerialConfig,
+    doorbell::KERNEL_DOORBELL,
+    sim_drivers::serial_ports::{SerialAddr, Uart},
+};
 use mnemos_kernel::{
     comms::{
         bbq::{new_bidi_channel, BidiHandle},
-        kchannel::KChannel,
-    },
-    registry::{
-        simple_serial::{Request, Response, SimpleSerial, SimpleSerialError},
-        Message,
+        kchannel::{KChannel, KProducer},
     },
+    registry::simple_serial::LinkEvent,
     Kernel,
 };
-use std::net::SocketAddr;
+use serde::Deserialize;
+use std::{
+    fs,
+    future::{poll_fn, Future},
+    task::{Context, Poll},
+    time::Duration,
+};
 use tokio::{
     io::{self, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
 };
-use tracing::{info_span, trace, warn, Instrument};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+/// How many link events can wait for the kernel. Any more are dropped.
+const LINK_EVENTS: usize = 8;
+/// How long to wait before accepting clients again, after failing to
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 pub struct TcpSerial {
     _inner: (),
 }
 
+/// What a [TcpSerial] port does with the kernel's output while no client is
+/// connected
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflinePolicy {
+    /// Keep it for the next client. Once the port's buffer is full, the
+    /// kernel waits for room.
+    #[default]
+    Buffer,
+    /// Throw it away, as a UART with nothing attached would.
+    Drop,
+}
+
+/// What a [TcpSerial] port does when a client connects while another client
+/// is already connected
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientPolicy {
+    /// Disconnect the new client straight away.
+    #[default]
+    Reject,
+    /// Send the kernel's output to every client, and pass the input from
+    /// every client on to the kernel.
+    Mirror,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// The state of a port, owned by its Tokio task
+struct Session {
+    kernel: &'static Kernel,
+    handle: BidiHandle,
+    offline: OfflinePolicy,
+    client_policy: ClientPolicy,
+    clients: Vec<Client>,
+    link_events: KProducer<LinkEvent>,
+}
+
+struct Client {
+    stream: Stream,
+    /// The client's address, for logging
+    name: String,
+}
+
 impl TcpSerial {
-    pub async fn register(
+    /// Start listening on `addr`, returning the kernel's end of the port.
+    pub async fn open(
         kernel: &'static Kernel,
-        ip: SocketAddr,
-        incoming_size: usize,
-        outgoing_size: usize,
-    ) -> Result<(), ()> {
-        let (a_ring, b_ring) = new_bidi_channel(kernel.heap(), incoming_size, outgoing_size).await;
-        let (prod, cons) = KChannel::<Message<SimpleSerial>>::new_async(kernel, 2)
-            .await
-            .split();
-
-        let listener = TcpListener::bind(ip).await.unwrap();
-        tracing::info!("TCP serial port driver listening on {ip}");
-
-        kernel
-            .spawn(async move {
-                let handle = b_ring;
-
-                // Reply to the first request, giving away the serial port
-                let req = cons.dequeue_async().await.map_err(drop).unwrap();
-                let Request::GetPort = req.msg.body;
-                let resp = req.msg.reply_with(Ok(Response::PortHandle { handle }));
-
-                req.reply.reply_konly(resp).await.map_err(drop).unwrap();
-
-                // And deny all further requests after the first
-                loop {
-                    let req = cons.dequeue_async().await.map_err(drop).unwrap();
-                    let Request::GetPort = req.msg.body;
-                    let resp = req
-                        .msg
-                        .reply_with(Err(SimpleSerialError::AlreadyAssignedPort));
-                    req.reply.reply_konly(resp).await.map_err(drop).unwrap();
+        addr: SerialAddr,
+        config: &SerialConfig,
+    ) -> io::Result<Uart> {
+        let (a_ring, b_ring) =
+            new_bidi_channel(kernel.heap(), config.incoming_size, config.outgoing_size).await;
+        let (link_prod, link_cons) = KChannel::new_async(kernel, LINK_EVENTS).await.split();
+
+        let listener = Listener::bind(&addr).await?;
+        info!("TCP serial port driver listening on {addr}");
+
+        let session = Session {
+            kernel,
+            handle: a_ring,
+            offline: config.offline,
+            client_policy: config.clients,
+            clients: Vec::new(),
+            link_events: link_prod,
+        };
+        tokio::spawn(
+            session
+                .run(listener)
+                .instrument(info_span!("TCP Serial", %addr)),
+        );
+
+        Ok(Uart {
+            handle: b_ring,
+            link_events: Some(link_cons),
+        })
+    }
+}
+
+// Session
+
+impl Session {
+    async fn run(mut self, listener: Listener) {
+        loop {
+            let offline = self.clients.is_empty();
+            let drain = !offline || self.offline == OfflinePolicy::Drop;
+
+            // Wait until either a client connects, a client has data to
+            // read, or the other end of the BBQueue has data to write.
+            tokio::select! {
+                // The kernel is shutting down. Flush anything it has already
+                // written, then disconnect everyone.
+                _ = self.kernel.wait_for_shutdown() => {
+                    self.flush().await;
+                    info!("Kernel shutting down, closing listener");
+                    return;
                 }
-            })
-            .await;
-
-        let _ = tokio::spawn(
-            async move {
-                let mut handle = a_ring;
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, addr)) => {
-                            process_stream(&mut handle, stream)
-                                .instrument(info_span!("process_stream", client.addr = %addr))
-                                .await
-                        }
-                        Err(error) => {
-                            warn!(%error, "Error accepting incoming TCP connection");
-                            return;
-                        }
-                    };
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, name)) => self.connect(Client { stream, name }),
+                    // This may pass, e.g. if we have run out of file
+                    // descriptors, so back off and try again.
+                    Err(error) => {
+                        warn!(%error, "Error accepting incoming connection");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                },
+                // The kernel wants to write something.
+                outmsg = self.handle.consumer().read_grant(), if drain => {
+                    let len = outmsg.len();
+                    if offline {
+                        trace!(len, "No client connected, dropping outgoing message");
+                    } else {
+                        trace!(len, "Got outgoing message");
+                        self.broadcast(&outmsg).await;
+                    }
+                    outmsg.release(len);
+                    // The kernel may be waiting for room to write more
+                    KERNEL_DOORBELL.ring();
                 }
+                // A client has more bytes to read.
+                idx = readable(&self.clients), if !offline => self.read_from(idx).await,
             }
-            .instrument(info_span!("TCP Serial", ?ip)),
-        );
+        }
+    }
 
-        kernel
-            .with_registry(|reg| reg.register_konly::<SimpleSerial>(&prod))
-            .await
-            .map_err(drop)
+    fn connect(&mut self, client: Client) {
+        if !self.clients.is_empty() && self.client_policy == ClientPolicy::Reject {
+            // Dropping the stream closes it
+            info!(client = %client.name, "Another client is connected, rejecting");
+            return;
+        }
+
+        info!(client = %client.name, "Client connected");
+        self.clients.push(client);
+        if self.clients.len() == 1 {
+            self.link_event(LinkEvent::Connected);
+        }
+    }
+
+    fn disconnect(&mut self, idx: usize) {
+        let client = self.clients.remove(idx);
+        info!(client = %client.name, "Client disconnected");
+        if self.clients.is_empty() {
+            self.link_event(LinkEvent::Disconnected);
+        }
     }
-}
 
-pub(crate) fn default_addr() -> SocketAddr {
-    SocketAddr::from(([127, 0, 0, 1], 9999))
+    fn link_event(&self, event: LinkEvent) {
+        match self.link_events.enqueue_sync(event) {
+            Ok(()) => KERNEL_DOORBELL.ring(),
+            // Nothing in the kernel may be listening for link events
+            Err(_) => debug!(?event, "Link event queue full, dropping event"),
+        }
+    }
+
+    /// Write `data` to every client, disconnecting any that fail.
+    async fn broadcast(&mut self, data: &[u8]) {
+        let mut idx = 0;
+        while idx < self.clients.len() {
+            let client = &mut self.clients[idx];
+            match client.stream.write_all(data).await {
+                Ok(()) => idx += 1,
+                Err(error) => {
+                    warn!(%error, client = %client.name, "Error writing to client");
+                    self.disconnect(idx);
+                }
+            }
+        }
+    }
+
+    async fn read_from(&mut self, idx: usize) {
+        let mut in_grant = self.handle.producer().send_grant_max(256).await;
+
+        // Try to read data, this may still fail with `WouldBlock` if the
+        // readiness event is a false positive.
+        let client = &self.clients[idx];
+        match client.stream.try_read(&mut in_grant) {
+            Ok(0) => self.disconnect(idx),
+            Ok(used) => {
+                trace!(len = used, client = %client.name, "Got incoming message");
+                in_grant.commit(used);
+                KERNEL_DOORBELL.ring();
+            }
+            // WouldBlock here indicates that the readiness event was
+            // spurious. That's fine, just continue waiting for the sender to
+            // become ready or the socket to be readable again.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            // Other errors indicate something is actually wrong.
+            Err(error) => {
+                warn!(%error, client = %client.name, "Error reading from client");
+                self.disconnect(idx);
+            }
+        }
+    }
+
+    /// Write anything the kernel has already written to every client.
+    async fn flush(&mut self) {
+        while let Some(outmsg) = self.handle.consumer().read_grant_sync() {
+            let len = outmsg.len();
+            trace!(len, "Flushing outgoing message");
+            self.broadcast(&outmsg).await;
+            outmsg.release(len);
+        }
+    }
 }
 
-async fn process_stream(handle: &mut BidiHandle, mut stream: TcpStream) {
-    loop {
-        // Wait until either the socket has data to read, or the other end of
-        // the BBQueue has data to write.
-        tokio::select! {
-            // The kernel wants to write something.
-            outmsg = handle.consumer().read_grant() => {
-                trace!(len = outmsg.len(), "Got outgoing message",);
-                let wall = stream.write_all(&outmsg);
-                wall.await.unwrap();
-                let len = outmsg.len();
-                outmsg.release(len);
+/// Wait until one of `clients` has bytes to read, returning its index.
+fn readable(clients: &[Client]) -> impl Future<Output = usize> + '_ {
+    poll_fn(move |cx| {
+        for (idx, client) in clients.iter().enumerate() {
+            // Errors are ready too, and will be reported by the read
+            if client.stream.poll_read_ready(cx).is_ready() {
+                return Poll::Ready(idx);
             }
-            // The socket has more bytes to read.
-            _ = stream.readable() => {
-                let mut in_grant = handle.producer().send_grant_max(256).await;
-
-                // Try to read data, this may still fail with `WouldBlock`
-                // if the readiness event is a false positive.
-                match stream.try_read(&mut in_grant) {
-                    Ok(used) if used == 0 => {
-                        warn!("Empty read, socket probably closed.");
-                        return;
-                    },
-                    Ok(used) => {
-                        trace!(len = used, "Got incoming message",);
-                        in_grant.commit(used);
-                    },
-                    // WouldBlock here indicates that the `readable()` event was
-                    // spurious. That's fine, just continue waiting for the
-                    // sender to become ready or the socket to be readable again.
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        continue;
-                    },
-                    // Other errors indicate something is actually wrong.
-                    Err(error) => {
-                        warn!(%error, "Error reading from TCP stream");
-                        return;
-                    },
+        }
+        Poll::Pending
+    })
+}
+
+// Listener
+
+impl Listener {
+    async fn bind(addr: &SerialAddr) -> io::Result<Self> {
+        match addr {
+            SerialAddr::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            SerialAddr::Unix(path) => {
+                // Clean up the socket left behind by a previous run
+                match fs::remove_file(path) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
                 }
+                UnixListener::bind(path).map(Listener::Unix)
             }
+            SerialAddr::Pty => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a pseudo-terminal is not a socket",
+            )),
+        }
+    }
+
+    /// Accept a client, returning its stream and a description of it
+    async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr.to_string()))
+            }
+            Listener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Unix(stream), format!("{addr:?}")))
+            }
+        }
+    }
+}
+
+// Stream
+
+impl Stream {
+    fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Stream::Tcp(stream) => stream.poll_read_ready(cx),
+            Stream::Unix(stream) => stream.poll_read_ready(cx),
+        }
+    }
+
+    fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.try_read(buf),
+            Stream::Unix(stream) => stream.try_read(buf),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.write_all(buf).await,
+            Stream::Unix(stream) => stream.write_all(buf).await,
         }
     }
 }
@@ -0,0 +1,270 @@
+//! A simulated serial port, driven entirely by the simulator's main loop
+//!
+//! Unlike [TcpSerial](super::tcp_serial::TcpSerial), this uses no threads, no
+//! Tokio tasks and no real time. Bytes only move between the kernel and the
+//! outside world when the simulator calls [VirtualSerialPort::poll], and how
+//! many bytes move is decided by the random number generator it is given.
+//! With a seeded generator, the same inputs always produce the same schedule.
+//!
+//! A port carrying the [SerialMux](mnemos_kernel::drivers::serial_mux::SerialMux)
+//! is framed, so [VirtualSerialPort::send] and [VirtualSerialPort::recv] deal
+//! in whole messages for a mux port. Other ports deal in raw bytes, using
+//! [VirtualSerialPort::send_raw] and [VirtualSerialPort::recv_raw].
+//!
+//! Faults can be injected into the port with [VirtualSerialPort::with_faults],
+//! which are timed by the virtual time passed to [VirtualSerialPort::poll].
+
+use std::{collections::VecDeque, time::Duration};
+
+use mnemos_kernel::{
+    comms::bbq::{new_bidi_channel, BidiHandle},
+    Kernel,
+};
+use rand::Rng;
+use tracing::{trace, warn};
+
+use crate::sim_faults::{FaultConfig, FaultyLink};
+
+/// The most bytes moved in each direction by one call to [VirtualSerialPort::poll]
+const MAX_CHUNK: usize = 64;
+/// The most data sent in a single mux frame
+const MAX_FRAME_DATA: usize = 256;
+
+pub struct VirtualSerial {
+    _inner: (),
+}
+
+/// The simulator's end of a [VirtualSerial] port
+pub struct VirtualSerialPort {
+    handle: BidiHandle,
+    /// Decode the bytes from the kernel as mux frames
+    framed: bool,
+    /// Bytes waiting to be sent to the kernel
+    queued: VecDeque<u8>,
+    /// Bytes that have been sent, waiting to be delivered to the kernel
+    incoming: VecDeque<u8>,
+    /// The link from the simulator to the kernel, if it is faulty
+    to_kernel: Option<FaultyLink>,
+    /// The link from the kernel to the simulator, if it is faulty
+    from_kernel: Option<FaultyLink>,
+    /// Bytes from the kernel that are not yet a whole frame, or if the port
+    /// isn't framed, that are waiting for [VirtualSerialPort::recv_raw]
+    partial: Vec<u8>,
+    /// Decoded frames from the kernel, waiting for [VirtualSerialPort::recv]
+    outgoing: VecDeque<(u16, Vec<u8>)>,
+}
+
+impl VirtualSerial {
+    /// Create a port, returning the kernel's end, and the simulator's end.
+    ///
+    /// If `framed` is set, the bytes sent by the kernel are decoded as mux
+    /// frames.
+    pub async fn open(
+        kernel: &'static Kernel,
+        incoming_size: usize,
+        outgoing_size: usize,
+        framed: bool,
+    ) -> (BidiHandle, VirtualSerialPort) {
+        let (a_ring, b_ring) = new_bidi_channel(kernel.heap(), incoming_size, outgoing_size).await;
+        let port = VirtualSerialPort {
+            handle: a_ring,
+            framed,
+            queued: VecDeque::new(),
+            incoming: VecDeque::new(),
+            to_kernel: None,
+            from_kernel: None,
+            partial: Vec::new(),
+            outgoing: VecDeque::new(),
+        };
+        (b_ring, port)
+    }
+}
+
+impl VirtualSerialPort {
+    /// Inject the faults in `config` into both directions of the port.
+    ///
+    /// `port` is the port's number, and picks the sequence of faults for
+    /// each direction.
+    pub fn with_faults(self, config: &FaultConfig, port: u8) -> Self {
+        Self {
+            to_kernel: Some(FaultyLink::new(config, u64::from(port) * 2)),
+            from_kernel: Some(FaultyLink::new(config, u64::from(port) * 2 + 1)),
+            ..self
+        }
+    }
+
+    /// Queue data to be sent to the given mux port.
+    ///
+    /// Nothing is sent until [VirtualSerialPort::poll] is called.
+    pub fn send(&mut self, port: u16, data: &[u8]) {
+        for chunk in data.chunks(MAX_FRAME_DATA) {
+            let mut frame = Vec::with_capacity(chunk.len() + 2);
+            frame.extend_from_slice(&port.to_le_bytes());
+            frame.extend_from_slice(chunk);
+
+            self.queued.extend(cobs::encode_vec(&frame));
+            self.queued.push_back(0);
+        }
+    }
+
+    /// Queue raw bytes to be sent to the kernel, without any framing.
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.queued.extend(bytes);
+    }
+
+    /// Take the next whole frame received from the kernel, as the mux port it
+    /// was sent from, and its data.
+    pub fn recv(&mut self) -> Option<(u16, Vec<u8>)> {
+        self.outgoing.pop_front()
+    }
+
+    /// Take all the raw bytes received from the kernel so far, if the port
+    /// isn't framed.
+    pub fn recv_raw(&mut self) -> Vec<u8> {
+        if self.framed {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.partial)
+    }
+
+    /// Is there data that can be moved in either direction at `now`?
+    pub fn is_busy(&self, now: Duration) -> bool {
+        let is_due = |link: &Option<FaultyLink>| matches!(link, Some(link) if link.is_due(now));
+        (!self.queued.is_empty() && can_send(&self.to_kernel, now))
+            || !self.incoming.is_empty()
+            || (can_send(&self.from_kernel, now)
+                && self.handle.consumer().read_grant_sync().is_some())
+            || is_due(&self.to_kernel)
+            || is_due(&self.from_kernel)
+    }
+
+    /// When something next happens on a faulty link, after `now`, if
+    /// anything is waiting to.
+    pub fn next_event(&self, now: Duration) -> Option<Duration> {
+        let next = |link: &Option<FaultyLink>| link.as_ref().and_then(|l| l.next_event(now));
+        match (next(&self.to_kernel), next(&self.from_kernel)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Move some bytes in each direction, at `now`.
+    ///
+    /// The number of bytes moved, which may be zero, is chosen by `rng`.
+    /// Returns `true` if any bytes were moved.
+    pub fn poll(&mut self, now: Duration, rng: &mut impl Rng) -> bool {
+        let wired = self.poll_wire(now);
+        let sent = self.poll_incoming(rng);
+        let received = self.poll_outgoing(now, rng);
+        wired || sent || received
+    }
+
+    /// Put queued bytes on the link to the kernel, and take the ones that
+    /// have arrived.
+    fn poll_wire(&mut self, now: Duration) -> bool {
+        let link = match self.to_kernel.as_mut() {
+            Some(link) => link,
+            None => {
+                let moved = !self.queued.is_empty();
+                self.incoming.extend(self.queued.drain(..));
+                return moved;
+            }
+        };
+
+        let mut moved = false;
+        if !self.queued.is_empty() && link.can_send(now) {
+            let len = self.queued.len().min(MAX_CHUNK);
+            let chunk: Vec<u8> = self.queued.drain(..len).collect();
+            link.send(now, &chunk);
+            moved = true;
+        }
+        let mut arrived = Vec::new();
+        link.recv(now, &mut arrived);
+        moved |= !arrived.is_empty();
+        self.incoming.extend(arrived);
+        moved
+    }
+
+    fn poll_incoming(&mut self, rng: &mut impl Rng) -> bool {
+        if self.incoming.is_empty() {
+            return false;
+        }
+        let len = rng.gen_range(0..=MAX_CHUNK).min(self.incoming.len());
+        if len == 0 {
+            return false;
+        }
+        let mut wgr = match self.handle.producer().send_grant_max_sync(len) {
+            Some(wgr) => wgr,
+            None => return false,
+        };
+        let len = wgr.len();
+        for (dst, src) in wgr.iter_mut().zip(self.incoming.drain(..len)) {
+            *dst = src;
+        }
+        wgr.commit(len);
+        trace!(len, "Sent bytes to the kernel");
+        true
+    }
+
+    fn poll_outgoing(&mut self, now: Duration, rng: &mut impl Rng) -> bool {
+        let mut moved = false;
+        if can_send(&self.from_kernel, now) {
+            if let Some(rgr) = self.handle.consumer().read_grant_sync() {
+                let len = rng.gen_range(0..=MAX_CHUNK).min(rgr.len());
+                if len > 0 {
+                    match self.from_kernel.as_mut() {
+                        Some(link) => link.send(now, &rgr[..len]),
+                        None => self.received(&rgr[..len]),
+                    }
+                    rgr.release(len);
+                    trace!(len, "Received bytes from the kernel");
+                    moved = true;
+                }
+            }
+        }
+
+        if let Some(link) = self.from_kernel.as_mut() {
+            let mut arrived = Vec::new();
+            link.recv(now, &mut arrived);
+            if !arrived.is_empty() {
+                self.received(&arrived);
+                moved = true;
+            }
+        }
+        moved
+    }
+
+    /// Handle bytes that have arrived from the kernel.
+    fn received(&mut self, bytes: &[u8]) {
+        if !self.framed {
+            self.partial.extend_from_slice(bytes);
+            return;
+        }
+
+        for &byte in bytes {
+            if byte != 0 {
+                self.partial.push(byte);
+                continue;
+            }
+            let mut frame = std::mem::take(&mut self.partial);
+            match cobs::decode_in_place(&mut frame) {
+                Ok(used) if used >= 2 => {
+                    let port = u16::from_le_bytes([frame[0], frame[1]]);
+                    self.outgoing.push_back((port, frame[2..used].to_vec()));
+                }
+                _ => warn!(
+                    len = frame.len(),
+                    "Discarded malformed frame from the kernel"
+                ),
+            }
+        }
+    }
+}
+
+/// Can bytes be put on `link` at `now`? A link without faults is always free.
+fn can_send(link: &Option<FaultyLink>, now: Duration) -> bool {
+    match link {
+        Some(link) => link.can_send(now),
+        None => true,
+    }
+}
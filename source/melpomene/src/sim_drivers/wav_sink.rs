@@ -0,0 +1,220 @@
+//! An audio output device that records everything played to a WAV file on
+//! the host
+//!
+//! The file holds 16-bit PCM in the device's format, and its header is
+//! updated after every buffer, so it can be played while the simulator is
+//! still running. Each buffer takes as long to play as it would on real
+//! hardware, measured with the kernel's timer, so a stream is paced the same
+//! in real and virtual time.
+//!
+//! Only what is played is recorded: gaps between buffers, such as when the
+//! device runs out of samples, are left out of the file.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use mnemos_kernel::{
+    comms::kchannel::{KChannel, KConsumer},
+    registry::{
+        pcm_sink::{PcmFormat, PcmSink, PcmSinkError, Request, Response, MAX_QUEUED},
+        Message,
+    },
+    Kernel,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::PcmSinkConfig;
+
+/// The length of the RIFF and format headers, before the samples
+pub const HEADER_LEN: u64 = 44;
+
+/// A WAV file of 16-bit PCM, written as samples arrive
+pub struct WavFile {
+    file: File,
+    /// The length of the samples written so far, in bytes
+    data_len: u32,
+}
+
+pub struct WavSink {
+    wav: WavFile,
+    format: PcmFormat,
+}
+
+// WavFile
+
+impl WavFile {
+    /// Create a WAV file holding no samples yet, replacing any file at
+    /// `path`.
+    pub fn create(path: &Path, format: PcmFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let channels = u16::from(format.channels);
+        let block_align = channels * 2;
+        let byte_rate = format.sample_rate * u32::from(block_align);
+
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&36u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // Uncompressed PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&format.sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all_at(&header, 0)?;
+
+        Ok(Self { file, data_len: 0 })
+    }
+
+    /// Append `samples` to the file, and update the header to cover them.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let data_len = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|len| len.checked_add(36).is_some())
+            .ok_or_else(|| io::Error::other("WAV file is full"))?;
+
+        self.file
+            .write_all_at(&bytes, HEADER_LEN + u64::from(self.data_len))?;
+        self.file.write_all_at(&(36 + data_len).to_le_bytes(), 4)?;
+        self.file.write_all_at(&data_len.to_le_bytes(), 40)?;
+        self.data_len = data_len;
+        Ok(())
+    }
+}
+
+// WavSink
+
+impl WavSink {
+    /// Create the WAV file described by `config`.
+    pub fn create(config: &PcmSinkConfig) -> io::Result<Self> {
+        let format = PcmFormat {
+            sample_rate: config.sample_rate,
+            channels: config.channels,
+        };
+        let wav = WavFile::create(&config.wav, format)?;
+        info!(
+            wav = %config.wav.display(),
+            sample_rate = format.sample_rate,
+            channels = format.channels,
+            "Recording audio output",
+        );
+        Ok(Self { wav, format })
+    }
+
+    /// Register the device as the kernel's [PcmSink] driver.
+    pub async fn register(self, kernel: &'static Kernel) -> Result<(), ()> {
+        let (prod, cons) = KChannel::<Message<PcmSink>>::new_async(kernel, 2)
+            .await
+            .split();
+        let (play_prod, play_cons) = KChannel::<Message<PcmSink>>::new_async(kernel, MAX_QUEUED)
+            .await
+            .split();
+        // Buffers accepted, and not yet handed back
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let WavSink { wav, format } = self;
+        kernel
+            .spawn_named(
+                "WavSinkPlayer",
+                play(kernel, wav, format, play_cons, queued.clone()),
+            )
+            .await;
+
+        kernel
+            .spawn_named("WavSink", async move {
+                let mut enabled = false;
+                // Our request channel is closed when the kernel shuts down.
+                while let Ok(req) = cons.dequeue_async().await {
+                    let Message { msg, reply } = req;
+                    let resp = match msg.body {
+                        Request::Format => msg.reply_with(Ok(Response::Format(format))),
+                        Request::Enable => {
+                            enabled = true;
+                            msg.reply_with(Ok(Response::Enabled))
+                        }
+                        Request::Disable => {
+                            enabled = false;
+                            msg.reply_with(Ok(Response::Disabled))
+                        }
+                        Request::Play { ref samples, len } => {
+                            let error = if !enabled {
+                                PcmSinkError::NotEnabled
+                            } else if len > samples.len() || len % usize::from(format.channels) != 0
+                            {
+                                PcmSinkError::InvalidLength
+                            } else if queued.load(Ordering::Acquire) >= MAX_QUEUED {
+                                PcmSinkError::Busy
+                            } else {
+                                queued.fetch_add(1, Ordering::AcqRel);
+                                play_prod
+                                    .enqueue_sync(Message { msg, reply })
+                                    .map_err(drop)
+                                    .unwrap();
+                                continue;
+                            };
+                            msg.reply_with(Err(error))
+                        }
+                    };
+                    // The client may have stopped waiting for the answer
+                    let _ = reply.reply_konly(resp).await;
+                }
+                play_prod.close();
+            })
+            .await;
+
+        kernel
+            .with_registry(|reg| reg.register_konly::<PcmSink>(&prod))
+            .await
+            .map_err(drop)
+    }
+}
+
+/// Record each buffer accepted by the device, then hand it back once it
+/// would have finished playing.
+async fn play(
+    kernel: &'static Kernel,
+    mut wav: WavFile,
+    format: PcmFormat,
+    buffers: KConsumer<Message<PcmSink>>,
+    queued: Arc<AtomicUsize>,
+) {
+    while let Ok(req) = buffers.dequeue_async().await {
+        let Message { msg, reply } = req;
+        // Only buffers to play are passed on
+        if let Request::Play { samples, len } = &msg.body {
+            let frames = len / usize::from(format.channels);
+            debug!(frames, "Playing buffer");
+            if let Err(error) = wav.write(&samples[..*len]) {
+                warn!(%error, "Failed to record audio output");
+            }
+            let nanos = frames as u64 * 1_000_000_000 / u64::from(format.sample_rate);
+            kernel.timer().sleep(Duration::from_nanos(nanos)).await;
+        }
+        let resp = msg.reply_with_body(|body| match body {
+            Request::Play { samples, .. } => Ok(Response::Played { samples }),
+            _ => Err(PcmSinkError::NoResponse),
+        });
+        queued.fetch_sub(1, Ordering::AcqRel);
+        // The stream may have been dropped while its buffer played
+        let _ = reply.reply_konly(resp).await;
+    }
+}
@@ -0,0 +1,311 @@
+//! Fault injection for simulated links
+//!
+//! Real serial links lose bytes, flip bits, and only go so fast. A
+//! [FaultyLink] carries bytes one way across a simulated link, and does the
+//! same to them, as set by a [FaultConfig]: each byte may be dropped, have a
+//! bit flipped, or be delivered twice, arrives after a fixed latency, and
+//! with a baud rate set, takes as long to send as it would on a real UART.
+//!
+//! Faults are chosen by a random number generator seeded from the config, so
+//! the same bytes sent the same way always suffer the same faults. A
+//! [FaultyLink] doesn't keep time itself, so it works just as well in real
+//! time, with [inject], as in virtual time, with
+//! [VirtualSerialPort](crate::sim_drivers::virtual_serial::VirtualSerialPort).
+
+use std::{collections::VecDeque, time::Duration};
+
+use mnemos_kernel::{
+    comms::bbq::{new_bidi_channel, BidiHandle},
+    Kernel,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tokio::time::{self, Instant};
+use tracing::{info_span, trace, Instrument};
+
+use crate::{doorbell::KERNEL_DOORBELL, sim_drivers::serial_ports::Uart};
+
+/// The most bytes put on the wire at once. A link with a baud rate set only
+/// takes more once these have been sent.
+const MAX_BURST: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+#[clap(
+    next_help_heading = "FAULT INJECTION OPTIONS",
+    group = clap::ArgGroup::new("fault-opts")
+)]
+pub struct FaultConfig {
+    /// The chance, from 0 to 1, that each byte sent over a serial link is
+    /// lost.
+    #[clap(long = "fault-drop", default_value_t = 0.0, parse(try_from_str = parse_probability))]
+    pub drop: f64,
+
+    /// The chance, from 0 to 1, that one bit of each byte sent over a serial
+    /// link is flipped.
+    #[clap(long = "fault-flip", default_value_t = 0.0, parse(try_from_str = parse_probability))]
+    pub flip: f64,
+
+    /// The chance, from 0 to 1, that each byte sent over a serial link is
+    /// delivered twice.
+    #[clap(long = "fault-duplicate", default_value_t = 0.0, parse(try_from_str = parse_probability))]
+    pub duplicate: f64,
+
+    /// Delay every byte sent over a serial link by this many milliseconds.
+    #[clap(long = "fault-latency-ms", default_value_t = 0)]
+    pub latency_ms: u64,
+
+    /// Limit serial links to the speed of a UART at this baud rate, sending
+    /// 10 bits per byte.
+    #[clap(long)]
+    pub baud: Option<u32>,
+
+    /// Seed for the random number generator choosing the faults.
+    #[clap(
+        id = "fault-seed",
+        long = "fault-seed",
+        value_name = "SEED",
+        default_value_t = 0
+    )]
+    pub seed: u64,
+}
+
+/// One direction of a simulated link, and the bytes in flight over it
+///
+/// Times passed in are measured from any fixed starting point, which must be
+/// the same for every call.
+pub struct FaultyLink {
+    config: FaultConfig,
+    rng: ChaCha8Rng,
+    /// When the last byte put on the wire will have been sent
+    wire_free: Duration,
+    /// Bytes on their way, and when each arrives, in order
+    in_flight: VecDeque<(Duration, u8)>,
+}
+
+// FaultConfig
+
+impl FaultConfig {
+    /// Are any faults injected at all?
+    pub fn is_enabled(&self) -> bool {
+        self.drop > 0.0
+            || self.flip > 0.0
+            || self.duplicate > 0.0
+            || self.latency_ms > 0
+            || self.baud.is_some()
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms)
+    }
+
+    /// How long a byte takes to send, with a start and stop bit
+    pub fn byte_time(&self) -> Duration {
+        match self.baud {
+            Some(baud) => Duration::from_nanos(10_000_000_000 / u64::from(baud.max(1))),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            flip: 0.0,
+            duplicate: 0.0,
+            latency_ms: 0,
+            baud: None,
+            seed: 0,
+        }
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=1.0).contains(&p) {
+        Ok(p)
+    } else {
+        Err(format!("{p} is not between 0 and 1"))
+    }
+}
+
+// FaultyLink
+
+impl FaultyLink {
+    /// Create a link suffering the faults in `config`.
+    ///
+    /// Each `stream` gets its own sequence of faults from the config's seed,
+    /// so links, and their two directions, should each use a different one.
+    pub fn new(config: &FaultConfig, stream: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+        rng.set_stream(stream);
+        Self {
+            config: config.clone(),
+            rng,
+            wire_free: Duration::ZERO,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Can more bytes be put on the wire at `now`?
+    pub fn can_send(&self, now: Duration) -> bool {
+        self.wire_free <= now
+    }
+
+    /// Put `bytes` on the wire at `now`.
+    ///
+    /// The bytes are sent one after another, once the wire is free, and any
+    /// faults are chosen as they are sent.
+    pub fn send(&mut self, now: Duration, bytes: &[u8]) {
+        let byte_time = self.config.byte_time();
+        let latency = self.config.latency();
+        let mut sent = self.wire_free.max(now);
+        for &byte in bytes {
+            sent += byte_time;
+            if self.rng.gen_bool(self.config.drop) {
+                trace!(byte, "Dropped byte");
+                continue;
+            }
+
+            let mut byte = byte;
+            if self.rng.gen_bool(self.config.flip) {
+                let bit = self.rng.gen_range(0..8);
+                trace!(byte, bit, "Flipped bit");
+                byte ^= 1 << bit;
+            }
+            self.in_flight.push_back((sent + latency, byte));
+
+            if self.rng.gen_bool(self.config.duplicate) {
+                trace!(byte, "Duplicated byte");
+                sent += byte_time;
+                self.in_flight.push_back((sent + latency, byte));
+            }
+        }
+        self.wire_free = sent;
+    }
+
+    /// Take the bytes that have arrived by `now`, appending them to `out`.
+    pub fn recv(&mut self, now: Duration, out: &mut Vec<u8>) {
+        while let Some(&(arrival, byte)) = self.in_flight.front() {
+            if arrival > now {
+                break;
+            }
+            out.push(byte);
+            self.in_flight.pop_front();
+        }
+    }
+
+    /// Has a byte arrived by `now`, waiting to be taken?
+    pub fn is_due(&self, now: Duration) -> bool {
+        matches!(self.in_flight.front(), Some(&(arrival, _)) if arrival <= now)
+    }
+
+    /// When something next happens on the link, after `now`: either a byte
+    /// arrives, or the wire becomes free.
+    pub fn next_event(&self, now: Duration) -> Option<Duration> {
+        let arrival = self.in_flight.front().map(|&(arrival, _)| arrival);
+        let free = Some(self.wire_free).filter(|&free| free > now);
+        match (arrival, free) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Put a faulty link between a UART's driver and the kernel, returning the
+/// kernel's new end of the UART.
+///
+/// The faults are injected in real time, by a Tokio task. `port` is the
+/// UART's number, and picks the sequence of faults for each direction.
+pub async fn inject(
+    kernel: &'static Kernel,
+    uart: Uart,
+    config: &FaultConfig,
+    port: u8,
+    incoming_size: usize,
+    outgoing_size: usize,
+) -> Uart {
+    let (relay_end, kernel_end) =
+        new_bidi_channel(kernel.heap(), incoming_size, outgoing_size).await;
+    let to_kernel = FaultyLink::new(config, u64::from(port) * 2);
+    let to_link = FaultyLink::new(config, u64::from(port) * 2 + 1);
+
+    tokio::spawn(
+        relay(kernel, uart.handle, relay_end, to_kernel, to_link)
+            .instrument(info_span!("Fault Injection", port)),
+    );
+
+    Uart {
+        handle: kernel_end,
+        link_events: uart.link_events,
+    }
+}
+
+/// Move bytes between the driver's end of the UART, `link`, and the
+/// kernel's, through `to_kernel` and `to_link`.
+async fn relay(
+    kernel: &'static Kernel,
+    link: BidiHandle,
+    kernel_end: BidiHandle,
+    mut to_kernel: FaultyLink,
+    mut to_link: FaultyLink,
+) {
+    let start = Instant::now();
+    let mut arrived = Vec::new();
+    loop {
+        let now = start.elapsed();
+        let next = match (to_kernel.next_event(now), to_link.next_event(now)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        tokio::select! {
+            // Let anything in flight from the kernel through straight away,
+            // so the driver can flush it.
+            _ = kernel.wait_for_shutdown() => {
+                to_link.recv(Duration::MAX, &mut arrived);
+                deliver(&link, &mut arrived).await;
+                return;
+            }
+            rgr = link.consumer().read_grant(), if to_kernel.can_send(now) => {
+                let len = rgr.len().min(MAX_BURST);
+                to_kernel.send(now, &rgr[..len]);
+                rgr.release(len);
+            }
+            rgr = kernel_end.consumer().read_grant(), if to_link.can_send(now) => {
+                let len = rgr.len().min(MAX_BURST);
+                to_link.send(now, &rgr[..len]);
+                rgr.release(len);
+                // The kernel may be waiting for room to write more
+                KERNEL_DOORBELL.ring();
+            }
+            _ = time::sleep_until(start + next.unwrap_or_default()), if next.is_some() => {}
+        }
+
+        let now = start.elapsed();
+        to_kernel.recv(now, &mut arrived);
+        if deliver(&kernel_end, &mut arrived).await {
+            KERNEL_DOORBELL.ring();
+        }
+        to_link.recv(now, &mut arrived);
+        deliver(&link, &mut arrived).await;
+    }
+}
+
+/// Write all of `bytes` to `dest`, leaving `bytes` empty. Returns whether
+/// there was anything to write.
+async fn deliver(dest: &BidiHandle, bytes: &mut Vec<u8>) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    let mut remaining = &bytes[..];
+    while !remaining.is_empty() {
+        let mut wgr = dest.producer().send_grant_max(remaining.len()).await;
+        let len = wgr.len();
+        wgr.copy_from_slice(&remaining[..len]);
+        wgr.commit(len);
+        remaining = &remaining[len..];
+    }
+    bytes.clear();
+    true
+}
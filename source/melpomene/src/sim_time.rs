@@ -0,0 +1,55 @@
+//! Virtual time, for deterministic simulation
+//!
+//! In deterministic mode, the kernel's timer is driven by a [VirtualClock]
+//! rather than the host's clock. Time only moves when the simulator says so:
+//! a little at a time while there is work to do, and straight to the next
+//! deadline when there isn't. A run takes as long as the host needs to
+//! simulate it, but the kernel and userspace always see the same times.
+
+use std::time::Duration;
+
+use mnemos_kernel::{timer::Instant, Kernel};
+
+pub struct VirtualClock {
+    /// The number of ticks reported to the kernel so far
+    ticks: u64,
+    granularity: Duration,
+}
+
+impl VirtualClock {
+    /// Create a clock for a kernel with the given timer granularity.
+    pub fn new(granularity: Duration) -> Self {
+        Self {
+            ticks: 0,
+            granularity,
+        }
+    }
+
+    /// The virtual time elapsed since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.granularity
+            .saturating_mul(self.ticks.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// Move time forward by the given number of ticks.
+    ///
+    /// Like a hardware timer, this only reports the ticks. They are applied by
+    /// the next call to [Kernel::tick].
+    pub fn advance(&mut self, k: &'static Kernel, ticks: u32) {
+        if ticks > 0 {
+            k.timer().pend_ticks(ticks);
+            self.ticks += u64::from(ticks);
+        }
+    }
+
+    /// Move time forward until (at least) the given deadline.
+    ///
+    /// Time always moves by at least one tick, so that a simulator waiting
+    /// for a deadline that has already passed still makes progress.
+    pub fn advance_to(&mut self, k: &'static Kernel, deadline: Instant) {
+        let tick_micros = self.granularity.as_micros().max(1) as u64;
+        let target = deadline.as_micros().div_ceil(tick_micros);
+        let ticks = target.saturating_sub(self.ticks).max(1);
+        self.advance(k, ticks.try_into().unwrap_or(u32::MAX));
+    }
+}
@@ -0,0 +1,175 @@
+//! Tests of the collections in `mstd::alloc`, on the userspace heap
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use melpomene::harness::{Harness, HarnessSettings};
+use mstd::{
+    alloc::{AllocError, Arc, Box, String, Vec},
+    runtime,
+};
+
+/// The number of [Tracked] values dropped so far
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts how many times it is dropped
+#[derive(Debug)]
+struct Tracked(u32);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn drops() -> usize {
+    DROPS.load(Ordering::SeqCst)
+}
+
+fn ids(vec: &Vec<Tracked>) -> std::vec::Vec<u32> {
+    vec.iter().map(|t| t.0).collect()
+}
+
+async fn vec() {
+    let mut vec = Vec::new();
+    assert_eq!(vec.capacity(), 0);
+    // Growing moves the items, without dropping them
+    for i in 0..10 {
+        vec.push(Tracked(i)).await;
+    }
+    assert_eq!(vec.len(), 10);
+    assert!(vec.capacity() >= 10);
+    assert_eq!(ids(&vec), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(drops(), 0);
+
+    // Removed items are handed back, not dropped
+    let removed = vec.remove(3);
+    assert_eq!(removed.0, 3);
+    assert_eq!(ids(&vec), [0, 1, 2, 4, 5, 6, 7, 8, 9]);
+    assert_eq!(drops(), 0);
+    drop(removed);
+    assert_eq!(drops(), 1);
+
+    let popped = vec.pop().unwrap();
+    assert_eq!(popped.0, 9);
+    drop(popped);
+    assert_eq!(drops(), 2);
+
+    // Truncating drops everything past the new length, exactly once
+    vec.truncate(4);
+    assert_eq!(ids(&vec), [0, 1, 2, 4]);
+    assert_eq!(drops(), 6);
+    vec.truncate(10);
+    assert_eq!(vec.len(), 4);
+    assert_eq!(drops(), 6);
+
+    vec.try_push(Tracked(10)).unwrap();
+    assert_eq!(ids(&vec), [0, 1, 2, 4, 10]);
+
+    // Growing past what could ever fit fails, rather than panicking
+    assert_eq!(vec.try_reserve(usize::MAX), Err(AllocError));
+    assert_eq!(vec.try_reserve(isize::MAX as usize), Err(AllocError));
+    assert_eq!(ids(&vec), [0, 1, 2, 4, 10]);
+
+    // Dropping the vec drops what's left
+    drop(vec);
+    assert_eq!(drops(), 11);
+
+    let mut empty = Vec::<Tracked>::new();
+    assert!(empty.pop().is_none());
+
+    let mut bytes = Vec::with_capacity(8).await;
+    assert!(bytes.capacity() >= 8);
+    bytes.extend_from_slice(b"hello").await;
+    bytes.try_extend_from_slice(b", world").unwrap();
+    assert_eq!(&bytes[..], b"hello, world");
+    assert_eq!(bytes.try_reserve(usize::MAX - 4), Err(AllocError));
+    bytes.clear();
+    assert!(bytes.is_empty());
+}
+
+async fn boxes() {
+    let before = drops();
+
+    let mut boxed = Box::new(Tracked(20)).await;
+    boxed.0 += 1;
+    // Moving the value out doesn't drop it
+    let item = Box::into_inner(boxed);
+    assert_eq!(item.0, 21);
+    assert_eq!(drops(), before);
+    drop(item);
+    assert_eq!(drops(), before + 1);
+
+    let boxed = Box::try_new(Tracked(22)).unwrap();
+    assert_eq!(boxed.0, 22);
+    drop(boxed);
+    assert_eq!(drops(), before + 2);
+}
+
+async fn arcs() {
+    let before = drops();
+
+    let first = Arc::new(Tracked(30)).await;
+    let second = first.clone();
+    // The value is only dropped with the last reference
+    drop(first);
+    assert_eq!(drops(), before);
+    assert_eq!(second.0, 30);
+    drop(second);
+    assert_eq!(drops(), before + 1);
+
+    let arc = Arc::try_new(Tracked(31)).unwrap();
+    let clones = [arc.clone(), arc.clone()];
+    drop(arc);
+    drop(clones);
+    assert_eq!(drops(), before + 2);
+}
+
+async fn strings() {
+    let mut s = String::new();
+    assert!(s.is_empty());
+    write!(s, "{}-{}", 1, 2.5).unwrap();
+    assert_eq!(s, "1-2.5");
+
+    s.push('é').await;
+    s.push_str("!").await;
+    assert_eq!(s, "1-2.5é!");
+    assert_eq!(s.pop(), Some('!'));
+    assert_eq!(s.pop(), Some('é'));
+    s.truncate(1);
+    assert_eq!(s.as_str(), "1");
+
+    // Writing grows the string as needed
+    let mut long = String::try_with_capacity(2).unwrap();
+    for i in 0..100 {
+        write!(long, "{i},").unwrap();
+    }
+    assert!(long.capacity() >= long.len());
+    assert!(long.starts_with("0,1,2,"));
+    assert!(long.ends_with("98,99,"));
+}
+
+#[test]
+fn collections() {
+    let mut harness = Harness::new(HarnessSettings {
+        start_userspace: true,
+        ..HarnessSettings::default()
+    });
+
+    runtime::spawn_main(async {
+        vec().await;
+        boxes().await;
+        arcs().await;
+        strings().await;
+    });
+
+    let done = harness.run_until(Duration::from_secs(5), |h| h.exit_status().is_some());
+    assert!(done, "userspace did not finish");
+    assert_eq!(harness.exit_status(), Some(0));
+}
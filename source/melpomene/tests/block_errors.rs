@@ -0,0 +1,199 @@
+//! Tests of the errors reported for bad block storage requests, and that
+//! every buffer lent with a failed request is given back
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::{fs, time::Duration};
+
+use melpomene::{
+    config::{BlockStorageConfig, Config},
+    harness::{Harness, HarnessSettings},
+};
+use mstd::{
+    abi::syscall::{block::BlockRequest, ByteBoxWire, KernelResponseBody, UserRequestBody},
+    block::{self, Block, BlockError, BlockKind, MAX_NAME_LEN},
+    executor::mailbox::MAILBOX,
+    runtime,
+};
+
+/// Not valid UTF-8
+static BAD_NAME: [u8; 4] = [b'b', 0xFF, b'a', b'd'];
+static LONG_NAME: [u8; MAX_NAME_LEN + 1] = [b'n'; MAX_NAME_LEN + 1];
+static SHORT_BUF: [u8; 2] = [0; 2];
+
+/// Lend the kernel a buffer that isn't on the userspace heap. The kernel
+/// doesn't write to it on the error paths tested here, and userspace only
+/// frees buffers it could have lent itself, so it is ignored once given back.
+fn lend(buf: &'static [u8]) -> ByteBoxWire {
+    ByteBoxWire {
+        ptr: buf.as_ptr() as usize,
+        len: buf.len(),
+    }
+}
+
+/// Send a request that `mstd::block` can't make, returning its error.
+async fn raw_error(req: BlockRequest) -> BlockError {
+    match MAILBOX.request(UserRequestBody::Block(req)).await {
+        Ok(KernelResponseBody::Block(Err(error))) => error,
+        other => panic!("expected an error, got {other:?}"),
+    }
+}
+
+fn lent_buffers() -> u32 {
+    MAILBOX.stats().lent_buffers
+}
+
+async fn errors() {
+    let info = block::store_info().await.unwrap();
+    assert_eq!(info.blocks, 3);
+    let capacity = info.capacity;
+
+    assert_eq!(
+        Block::open(info.blocks).await.err(),
+        Some(BlockError::NoSuchBlock)
+    );
+    assert_eq!(
+        block::block_info(info.blocks).await.err(),
+        Some(BlockError::NoSuchBlock)
+    );
+
+    // A `Block` can only be used while it is open, so ask the kernel directly
+    let read = BlockRequest::Read {
+        block: 0,
+        offset: 0,
+        buffer: lend(&SHORT_BUF),
+        len: SHORT_BUF.len(),
+    };
+    assert_eq!(raw_error(read).await, BlockError::NotOpen);
+    let write = BlockRequest::Write {
+        block: 0,
+        offset: 0,
+        buffer: lend(&SHORT_BUF),
+        used: SHORT_BUF.len(),
+    };
+    assert_eq!(raw_error(write).await, BlockError::NotOpen);
+    let close = BlockRequest::Close {
+        block: 0,
+        name: lend(&SHORT_BUF),
+        name_len: 0,
+        len: 0,
+        kind: BlockKind::Storage,
+    };
+    assert_eq!(raw_error(close).await, BlockError::NotOpen);
+
+    let mut block = Block::open(0).await.unwrap();
+    assert_eq!(Block::open(0).await.err(), Some(BlockError::AlreadyOpen));
+
+    let before = lent_buffers();
+    let mut buf = [0u8; 8];
+    let mut big = [0u8; 512];
+    // Offsets must be aligned
+    assert_eq!(block.read(2, &mut buf).await, Err(BlockError::Unaligned));
+    assert_eq!(block.write(2, b"data").await, Err(BlockError::Unaligned));
+    // Reads may stop at the end of the block, writes must fit
+    assert_eq!(block.read(capacity, &mut buf).await, Ok(0));
+    assert_eq!(
+        block.read(capacity + 4, &mut buf).await,
+        Err(BlockError::OutOfBounds)
+    );
+    assert_eq!(
+        block.write(capacity - 4, b"too long").await,
+        Err(BlockError::OutOfBounds)
+    );
+    // Offsets near the end of the address space don't wrap
+    assert_eq!(
+        block.read(u32::MAX - 3, &mut big).await,
+        Err(BlockError::OutOfBounds)
+    );
+    assert_eq!(
+        block.write(u32::MAX - 3, &big).await,
+        Err(BlockError::OutOfBounds)
+    );
+    assert_eq!(lent_buffers(), before);
+
+    block.write(0, b"data").await.unwrap();
+    block.close("named", 4, BlockKind::Storage).await.unwrap();
+    let info = block::block_info(0).await.unwrap();
+    assert_eq!(info.name.as_deref(), Some("named"));
+    // The name doesn't fit in the buffer
+    let block_info = BlockRequest::BlockInfo {
+        block: 0,
+        name_buf: lend(&SHORT_BUF),
+    };
+    assert_eq!(raw_error(block_info).await, BlockError::OutOfBounds);
+
+    // Names must be UTF-8, and at most MAX_NAME_LEN bytes
+    let block = Block::open(1).await.unwrap();
+    let close = BlockRequest::Close {
+        block: 1,
+        name: lend(&BAD_NAME),
+        name_len: BAD_NAME.len(),
+        len: 0,
+        kind: BlockKind::Storage,
+    };
+    assert_eq!(raw_error(close).await, BlockError::InvalidName);
+    let close = BlockRequest::Close {
+        block: 1,
+        name: lend(&LONG_NAME),
+        name_len: LONG_NAME.len(),
+        len: 0,
+        kind: BlockKind::Storage,
+    };
+    assert_eq!(raw_error(close).await, BlockError::InvalidName);
+    let close = BlockRequest::Close {
+        block: 1,
+        name: lend(&SHORT_BUF),
+        name_len: SHORT_BUF.len() + 1,
+        len: 0,
+        kind: BlockKind::Storage,
+    };
+    assert_eq!(raw_error(close).await, BlockError::InvalidName);
+    let long_name = "n".repeat(MAX_NAME_LEN + 1);
+    assert_eq!(
+        block.close(&long_name, 0, BlockKind::Storage).await,
+        Err(BlockError::InvalidName)
+    );
+    // A block that failed to close is left open
+    assert_eq!(Block::open(1).await.err(), Some(BlockError::AlreadyOpen));
+
+    // The length must fit in the block
+    let before = lent_buffers();
+    let block = Block::open(2).await.unwrap();
+    assert_eq!(
+        block.close("big", capacity + 1, BlockKind::Storage).await,
+        Err(BlockError::OutOfBounds)
+    );
+    assert_eq!(lent_buffers(), before);
+
+    // Nothing above was mistaken for a response to another request
+    let stats = MAILBOX.stats();
+    assert_eq!(stats.unknown_responses, 0);
+    assert_eq!(stats.malformed_messages, 0);
+}
+
+#[test]
+fn bad_requests_are_rejected() {
+    let image =
+        std::env::temp_dir().join(format!("melpomene-block-errors-{}.img", std::process::id()));
+    let _ = fs::remove_file(&image);
+    let mut harness = Harness::new(HarnessSettings {
+        config: Config {
+            block_storage: Some(BlockStorageConfig {
+                image: image.clone(),
+                blocks: 4,
+                capacity: 4096,
+            }),
+            ..Config::default()
+        },
+        start_userspace: true,
+        ..HarnessSettings::default()
+    });
+
+    runtime::spawn_main(errors());
+
+    let done = harness.run_until(Duration::from_secs(10), |h| h.exit_status().is_some());
+    assert!(done, "userspace did not finish");
+    assert_eq!(harness.exit_status(), Some(0));
+    fs::remove_file(&image).unwrap();
+}
@@ -0,0 +1,113 @@
+//! Tests of the simulated block storage device
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use melpomene::{
+    config::{BlockStorageConfig, Config},
+    harness::{Harness, HarnessSettings},
+    sim_drivers::file_block::{FileBlockStorage, ERASED},
+};
+use mnemos_kernel::registry::block_storage::{BlockStorage, BlockStorageError, StoreInfo};
+
+/// A device of `blocks` blocks of 256 bytes, backed by a fresh image named
+/// after the test.
+fn device(name: &str, blocks: u32) -> BlockStorageConfig {
+    let image: PathBuf =
+        std::env::temp_dir().join(format!("melpomene-{name}-{}.img", std::process::id()));
+    let _ = fs::remove_file(&image);
+    BlockStorageConfig {
+        image,
+        blocks,
+        capacity: 256,
+    }
+}
+
+#[test]
+fn images_are_created_erased() {
+    let config = device("created", 4);
+    FileBlockStorage::open(&config).unwrap();
+    let image = fs::read(&config.image).unwrap();
+    assert_eq!(image.len(), 4 * 256);
+    assert!(image.iter().all(|&b| b == ERASED));
+
+    // An image of the wrong size is refused
+    let smaller = BlockStorageConfig {
+        blocks: 2,
+        ..config.clone()
+    };
+    assert!(FileBlockStorage::open(&smaller).is_err());
+
+    fs::remove_file(&config.image).unwrap();
+}
+
+#[test]
+fn blocks_are_read_written_and_erased() {
+    let storage = device("driver", 4);
+    let mut harness = Harness::new(HarnessSettings {
+        config: Config {
+            block_storage: Some(storage.clone()),
+            ..Config::default()
+        },
+        ..HarnessSettings::default()
+    });
+    assert!(harness.is_registered::<BlockStorage>());
+
+    let k = harness.kernel();
+    let test = k
+        .initialize(async move {
+            let mut dev = BlockStorage::from_registry(k).await.unwrap();
+            assert_eq!(
+                dev.store_info().await.unwrap(),
+                StoreInfo {
+                    blocks: 4,
+                    capacity: 256
+                }
+            );
+
+            let mut buf = k.heap().allocate_array_with(|| 0, 8).await;
+            buf.copy_from_slice(b"mnemos!!");
+            let buf = dev.write(1, 16, buf, 8).await.unwrap();
+            let buf = dev.read(1, 16, buf, 8).await.unwrap();
+            assert_eq!(&buf[..], b"mnemos!!");
+
+            // Writing only clears bits, until the block is erased
+            let mut buf = buf;
+            buf.copy_from_slice(&[0xF0; 8]);
+            let buf = dev.write(1, 16, buf, 4).await.unwrap();
+            let buf = dev.read(1, 16, buf, 8).await.unwrap();
+            assert_eq!(&buf[..], b"````os!!");
+            dev.erase(1).await.unwrap();
+            let buf = dev.read(1, 16, buf, 8).await.unwrap();
+            assert_eq!(&buf[..], &[ERASED; 8]);
+
+            let buf = dev.write(3, 248, buf, 8).await.unwrap();
+            assert_eq!(
+                dev.read(4, 0, buf, 8).await.err(),
+                Some(BlockStorageError::NoSuchBlock)
+            );
+            let buf = k.heap().allocate_array_with(|| 0, 8).await;
+            assert_eq!(
+                dev.read(3, 252, buf, 8).await.err(),
+                Some(BlockStorageError::OutOfBounds)
+            );
+            let buf = k.heap().allocate_array_with(|| 0, 8).await;
+            assert_eq!(
+                dev.read(3, 0, buf, 16).await.err(),
+                Some(BlockStorageError::OutOfBounds)
+            );
+
+            let mut buf = k.heap().allocate_array_with(|| 0, 8).await;
+            buf.copy_from_slice(b"saved!!!");
+            dev.write(2, 0, buf, 8).await.unwrap();
+        })
+        .unwrap();
+
+    assert!(harness.run_until(Duration::from_secs(1), |_| test.is_finished()));
+    assert_eq!(harness.shutdown(), 0);
+
+    // Everything written is in the image
+    let image = fs::read(&storage.image).unwrap();
+    assert_eq!(&image[2 * 256..][..8], b"saved!!!");
+    assert!(image[256..2 * 256].iter().all(|&b| b == ERASED));
+    fs::remove_file(&storage.image).unwrap();
+}
@@ -0,0 +1,55 @@
+//! Runs the `blocks` app on a fresh block storage image
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::{fs, time::Duration};
+
+use melpomene::{
+    apps::{App, BOOTS_BLOCK},
+    config::{BlockStorageConfig, Config},
+    harness::{Harness, HarnessSettings},
+};
+use mnemos_kernel::drivers::user_block::ENTRY_SIZE;
+
+#[test]
+fn blocks_app_counts_runs() {
+    let image = std::env::temp_dir().join(format!("melpomene-blocks-{}.img", std::process::id()));
+    let _ = fs::remove_file(&image);
+    let storage = BlockStorageConfig {
+        image: image.clone(),
+        blocks: 4,
+        capacity: 4096,
+    };
+
+    let mut harness = Harness::new(HarnessSettings {
+        seed: 5,
+        config: Config {
+            block_storage: Some(storage),
+            ..Config::default()
+        },
+        start_userspace: true,
+        app: Some(App::Blocks),
+        ..HarnessSettings::default()
+    });
+
+    let exited = harness.run_until(Duration::from_secs(10), |h| h.exit_status().is_some());
+    assert!(exited, "the app did not exit");
+    assert_eq!(harness.exit_status(), Some(0));
+
+    let stdout = String::from_utf8(harness.take_output(0)).unwrap();
+    assert!(stdout.contains("3 blocks of 4096 bytes"), "got {stdout:?}");
+    assert!(stdout.contains("Run number 1"), "got {stdout:?}");
+
+    // The first block holds the table, and the count went in the first
+    // unused block
+    let image_data = fs::read(&image).unwrap();
+    let entry = &image_data[..ENTRY_SIZE];
+    assert_eq!(entry[0], 2, "not a storage block");
+    assert_eq!(usize::from(entry[1]), BOOTS_BLOCK.len());
+    assert_eq!(&entry[4..8], &4u32.to_le_bytes());
+    assert_eq!(&entry[8..][..BOOTS_BLOCK.len()], BOOTS_BLOCK.as_bytes());
+    assert!(image_data[ENTRY_SIZE..4096].iter().all(|&b| b == 0xFF));
+    assert_eq!(&image_data[4096..][..4], &1u32.to_le_bytes());
+    fs::remove_file(&image).unwrap();
+}
@@ -0,0 +1,102 @@
+//! Tests of the board configs shipped in `configs/`
+
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use melpomene::{
+    config::{BlockStorageConfig, Config, ConfigError, PcmSinkConfig},
+    harness::{Harness, HarnessSettings},
+    sim_drivers::serial_ports::SerialAddr,
+};
+
+fn load(name: &str) -> Result<Config, ConfigError> {
+    Config::load(
+        &Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("configs")
+            .join(name),
+    )
+}
+
+#[test]
+fn default_config_matches_defaults() {
+    let mut config = load("default.toml").unwrap();
+    config.serial[0].addr = None;
+    assert_eq!(config, Config::default());
+}
+
+#[test]
+fn minimal_board_boots() {
+    let config = load("minimal.json").unwrap();
+    assert_eq!(config.kernel.k2u_size, Config::default().kernel.k2u_size);
+
+    let mut harness = Harness::new(HarnessSettings {
+        config,
+        ..HarnessSettings::default()
+    });
+    harness.send(2, b"ping");
+    assert!(harness.run_until(Duration::from_secs(1), |h| h.output(2) == b"ping"));
+    assert!(harness.output(1).is_empty());
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn invalid_configs_are_rejected() {
+    let mut config = Config::default();
+    config.ports.push(config.ports[0].clone());
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::DuplicatePort(1))
+    ));
+
+    let mut config = Config::default();
+    config.serial_mux.max_ports = 3;
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::TooManyPorts {
+            needed: 4,
+            max_ports: 3
+        })
+    ));
+
+    let mut config = Config::default();
+    config.serial.clear();
+    assert!(matches!(config.validate(), Err(ConfigError::NoSerialPorts)));
+
+    let mut config = Config::default();
+    let mut storage = BlockStorageConfig::new("flash.img".into());
+    storage.blocks = 0;
+    config.block_storage = Some(storage);
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::EmptyBlockStorage)
+    ));
+
+    let mut config = Config::default();
+    let mut sink = PcmSinkConfig::new("out.wav".into());
+    sink.channels = 0;
+    config.pcm_sink = Some(sink);
+    assert!(matches!(
+        config.validate(),
+        Err(ConfigError::InvalidPcmFormat)
+    ));
+}
+
+#[test]
+fn serial_addrs_round_trip() {
+    let addrs = [
+        (
+            "127.0.0.1:9999",
+            SerialAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 9999))),
+        ),
+        (
+            "unix:/tmp/uart0.sock",
+            SerialAddr::Unix("/tmp/uart0.sock".into()),
+        ),
+        ("pty", SerialAddr::Pty),
+    ];
+    for (text, addr) in addrs {
+        assert_eq!(text.parse::<SerialAddr>().unwrap(), addr);
+        assert_eq!(addr.to_string(), text);
+    }
+    assert!("tty".parse::<SerialAddr>().is_err());
+}
@@ -0,0 +1,38 @@
+//! Runs the `echo` app
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::time::Duration;
+
+use melpomene::{
+    apps::{App, ECHO_PORT},
+    harness::{Harness, HarnessSettings},
+};
+
+#[test]
+fn echo_app_echoes() {
+    let mut harness = Harness::new(HarnessSettings {
+        seed: 3,
+        start_userspace: true,
+        app: Some(App::Echo),
+        ..HarnessSettings::default()
+    });
+
+    let started = harness.run_until(Duration::from_secs(1), |h| !h.output(0).is_empty());
+    assert!(started, "the app did not start");
+
+    let message = b"The quick brown fox jumps over the lazy dog. ".repeat(10);
+    harness.send(ECHO_PORT, &message);
+    let echoed = harness.run_until(Duration::from_secs(5), |h| {
+        h.output(ECHO_PORT).len() >= message.len()
+    });
+    assert!(
+        echoed,
+        "only echoed {} bytes",
+        harness.output(ECHO_PORT).len()
+    );
+    assert_eq!(harness.output(ECHO_PORT), &message[..]);
+
+    assert_eq!(harness.shutdown(), 0);
+}
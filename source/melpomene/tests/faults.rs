@@ -0,0 +1,127 @@
+//! Tests of fault injection on simulated links
+
+use std::time::Duration;
+
+use melpomene::{
+    harness::{Harness, HarnessSettings},
+    sim_faults::{FaultConfig, FaultyLink},
+};
+
+/// Send `bytes` over a link at time zero, and take everything that arrives.
+fn transmit(config: &FaultConfig, bytes: &[u8]) -> Vec<u8> {
+    let mut link = FaultyLink::new(config, 0);
+    link.send(Duration::ZERO, bytes);
+    let mut out = Vec::new();
+    link.recv(Duration::MAX, &mut out);
+    out
+}
+
+#[test]
+fn faults_are_injected() {
+    let bytes: Vec<u8> = (0..=255).collect();
+
+    let dropped = FaultConfig {
+        drop: 1.0,
+        ..FaultConfig::default()
+    };
+    assert!(transmit(&dropped, &bytes).is_empty());
+
+    let flipped = FaultConfig {
+        flip: 1.0,
+        ..FaultConfig::default()
+    };
+    for (sent, got) in bytes.iter().zip(transmit(&flipped, &bytes)) {
+        assert_eq!((sent ^ got).count_ones(), 1);
+    }
+
+    let duplicated = FaultConfig {
+        duplicate: 1.0,
+        ..FaultConfig::default()
+    };
+    let expected: Vec<u8> = bytes.iter().flat_map(|&b| [b, b]).collect();
+    assert_eq!(transmit(&duplicated, &bytes), expected);
+}
+
+#[test]
+fn faults_follow_the_seed() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    let config = FaultConfig {
+        drop: 0.5,
+        seed: 1,
+        ..FaultConfig::default()
+    };
+    assert_eq!(transmit(&config, &bytes), transmit(&config, &bytes));
+
+    let other = FaultConfig { seed: 2, ..config };
+    assert_ne!(transmit(&config, &bytes), transmit(&other, &bytes));
+}
+
+#[test]
+fn bytes_take_time_to_arrive() {
+    // 1ms per byte, plus 5ms
+    let config = FaultConfig {
+        latency_ms: 5,
+        baud: Some(10_000),
+        ..FaultConfig::default()
+    };
+    let mut link = FaultyLink::new(&config, 0);
+    link.send(Duration::ZERO, b"0123456789");
+    assert!(!link.can_send(Duration::from_millis(9)));
+    assert!(link.can_send(Duration::from_millis(10)));
+
+    let mut out = Vec::new();
+    link.recv(Duration::from_millis(5), &mut out);
+    assert!(out.is_empty());
+    link.recv(Duration::from_millis(8), &mut out);
+    assert_eq!(out, b"012");
+    assert_eq!(
+        link.next_event(Duration::from_millis(8)),
+        Some(Duration::from_millis(9))
+    );
+    link.recv(Duration::from_millis(15), &mut out);
+    assert_eq!(out, b"0123456789");
+}
+
+#[test]
+fn loopback_is_slowed_down() {
+    let mut harness = Harness::new(HarnessSettings {
+        faults: FaultConfig {
+            latency_ms: 50,
+            baud: Some(9600),
+            ..FaultConfig::default()
+        },
+        ..HarnessSettings::default()
+    });
+
+    let start = harness.elapsed();
+    harness.send(2, b"ping");
+    assert!(harness.run_until(Duration::from_secs(1), |h| h.output(2) == b"ping"));
+    // There and back again
+    assert!(harness.elapsed() - start >= Duration::from_millis(100));
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn mux_survives_a_noisy_link() {
+    let mut harness = Harness::new(HarnessSettings {
+        seed: 7,
+        faults: FaultConfig {
+            drop: 0.01,
+            flip: 0.01,
+            duplicate: 0.01,
+            seed: 7,
+            ..FaultConfig::default()
+        },
+        ..HarnessSettings::default()
+    });
+
+    for _ in 0..100 {
+        harness.send(2, b"ping");
+    }
+    assert_eq!(harness.run_for(Duration::from_secs(2)), None);
+    // Some frames are lost or mangled, but the rest get through
+    assert!(!harness.output(2).is_empty());
+
+    assert_eq!(harness.shutdown(), 0);
+}
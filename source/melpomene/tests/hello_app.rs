@@ -0,0 +1,34 @@
+//! Runs the `hello` app to completion
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::time::Duration;
+
+use melpomene::{
+    apps::App,
+    harness::{Harness, HarnessSettings},
+};
+
+#[test]
+fn hello_app_runs_to_completion() {
+    let mut harness = Harness::new(HarnessSettings {
+        seed: 7,
+        start_userspace: true,
+        app: Some(App::Hello),
+        ..HarnessSettings::default()
+    });
+
+    let exited = harness.run_until(Duration::from_secs(30), |h| h.exit_status().is_some());
+    assert!(exited, "the app did not exit");
+    assert_eq!(harness.exit_status(), Some(0));
+
+    let stdout = String::from_utf8(harness.take_output(0)).unwrap();
+    for i in 0..5 {
+        assert!(stdout.contains(&format!("[main] Hello from userspace! ({i})")));
+    }
+    assert!(stdout.contains("[subtask] Done!"));
+    assert!(stdout.contains("[main] Goodbye!"), "got {stdout:?}");
+
+    assert!(harness.has_event(|e| e.message == "Userspace halted" && e.field("code") == Some("0")));
+}
@@ -0,0 +1,229 @@
+//! Tests of the kernel and its drivers, without userspace
+
+use std::time::Duration;
+
+use melpomene::{
+    config::{Config, SerialConfig},
+    harness::{Harness, HarnessSettings},
+};
+use mnemos_kernel::{
+    drivers::serial_mux::SerialMux, registry::simple_serial::SimpleSerial, MessageStats,
+};
+use mstd::abi::{
+    bbqueue_ipc::{framed::FrameProducer, BBBuffer},
+    syscall::{
+        block::BlockRequest, KernelMsg, KernelResponseBody, UserRequest, UserRequestBody,
+        UserRequestHeader,
+    },
+};
+use tracing::Level;
+
+#[test]
+fn loopback_echoes_data() {
+    let mut harness = Harness::new(HarnessSettings::default());
+
+    harness.send(2, b"ping");
+    let echoed = harness.run_until(Duration::from_secs(1), |h| h.output(2) == b"ping");
+    assert!(
+        echoed,
+        "got {:?}",
+        String::from_utf8_lossy(harness.output(2))
+    );
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn hello_loop_sends_every_second() {
+    let mut harness = Harness::new(HarnessSettings::default());
+
+    assert_eq!(harness.run_for(Duration::from_millis(3500)), None);
+    let output = String::from_utf8(harness.take_output(1)).unwrap();
+    assert!(output.matches("hello\r\n").count() >= 3, "got {output:?}");
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn drivers_are_registered() {
+    let mut config = Config::default();
+    config.user_serial.enabled = false;
+    config.ports.clear();
+    let harness = Harness::new(HarnessSettings {
+        config,
+        ..HarnessSettings::default()
+    });
+
+    assert!(harness.is_registered::<SimpleSerial>());
+    assert!(harness.is_registered::<SerialMux>());
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn extra_serial_ports_are_handed_out() {
+    let mut config = Config::default();
+    config.serial.push(SerialConfig::default());
+    let mut harness = Harness::new(HarnessSettings {
+        config,
+        ..HarnessSettings::default()
+    });
+
+    // Echo everything received on port 1
+    let k = harness.kernel();
+    k.initialize(async move {
+        let mut serial = SimpleSerial::from_registry(k).await.unwrap();
+        // Port 0 is already held by the serial mux, and there is no port 2
+        assert!(serial.get_port_num(0).await.is_none());
+        assert!(serial.get_port_num(2).await.is_none());
+        // The harness's ports have nothing to connect or disconnect
+        assert!(serial.link_events(1).await.is_none());
+        let port = serial.get_port_num(1).await.unwrap();
+        while let Some(rgr) = k.until_shutdown(port.consumer().read_grant()).await {
+            let len = rgr.len();
+            let mut wgr = port.producer().send_grant_exact(len).await;
+            wgr.copy_from_slice(&rgr);
+            wgr.commit(len);
+            rgr.release(len);
+        }
+    })
+    .unwrap();
+
+    harness.send_uart(1, b"ping");
+    let echoed = harness.run_until(Duration::from_secs(1), |h| h.uart_output(1) == b"ping");
+    assert!(
+        echoed,
+        "got {:?}",
+        String::from_utf8_lossy(harness.uart_output(1))
+    );
+    // Nothing leaks onto the mux
+    assert!(harness.drain_output().is_empty());
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn malformed_frames_are_traced() {
+    let mut harness = Harness::new(HarnessSettings {
+        trace_level: Some(Level::WARN),
+        ..HarnessSettings::default()
+    });
+
+    // A frame too short to hold a port number
+    harness.send_raw(&[0x02, 0x01, 0x00]);
+    let traced = harness.run_until(Duration::from_secs(1), |h| {
+        h.has_event(|e| e.level == Level::WARN && e.message.starts_with("Cobs decode"))
+    });
+    assert!(traced, "got {:#?}", harness.events());
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+/// Write a raw frame into the u2k ring, as userspace would.
+fn send_frame(u2k: &FrameProducer<'static>, bytes: &[u8]) {
+    let mut wgr = u2k.grant(bytes.len()).unwrap();
+    wgr[..bytes.len()].copy_from_slice(bytes);
+    wgr.commit(bytes.len());
+}
+
+#[test]
+fn malformed_requests_are_rejected() {
+    let mut harness = Harness::new(HarnessSettings {
+        trace_level: Some(Level::WARN),
+        ..HarnessSettings::default()
+    });
+    let k = harness.kernel();
+    assert_eq!(k.message_stats(), MessageStats::default());
+
+    // Play userspace's part, without starting its runtime
+    let rings = k.rings();
+    // SAFETY: userspace isn't started, so nothing else takes these
+    let (u2k, k2u) = unsafe {
+        (
+            BBBuffer::take_framed_producer(rings.u2k.as_ptr()),
+            BBBuffer::take_framed_consumer(rings.k2u.as_ptr()),
+        )
+    };
+
+    // Not even a nonce can be read from this
+    send_frame(&u2k, &[0xFF; 5]);
+    // A request cut short, whose header is still intact
+    let req = UserRequest {
+        header: UserRequestHeader { nonce: 42 },
+        body: UserRequestBody::Block(BlockRequest::Open { block: 1 }),
+    };
+    let mut buf = [0; 16];
+    let bytes = postcard::to_slice(&req, &mut buf).unwrap();
+    send_frame(&u2k, &bytes[..bytes.len() - 1]);
+
+    let mut responses = Vec::new();
+    let answered = harness.run_until(Duration::from_secs(1), |_| {
+        while let Some(frame) = k2u.read() {
+            if let Ok(KernelMsg::Response(resp)) = postcard::from_bytes::<KernelMsg>(&frame) {
+                responses.push(resp);
+            }
+            frame.release();
+        }
+        !responses.is_empty()
+    });
+    assert!(answered);
+
+    // Only the request with a readable nonce is answered
+    assert_eq!(responses.len(), 1);
+    assert_eq!(responses[0].header.nonce, 42);
+    assert!(matches!(
+        responses[0].body,
+        KernelResponseBody::MalformedRequest
+    ));
+    assert_eq!(
+        k.message_stats(),
+        MessageStats {
+            malformed_requests: 2,
+            error_replies: 1,
+            dropped_replies: 0,
+            unrouted_requests: 0,
+        }
+    );
+    let warnings = harness
+        .events()
+        .into_iter()
+        .filter(|e| e.message == "Dropped malformed request from userspace")
+        .count();
+    assert_eq!(warnings, 2);
+
+    assert_eq!(harness.shutdown(), 0);
+}
+
+#[test]
+fn runs_are_reproducible() {
+    fn run(seed: u64) -> (Vec<u8>, Vec<u8>, Duration) {
+        let mut harness = Harness::new(HarnessSettings {
+            seed,
+            trace_level: None,
+            ..HarnessSettings::default()
+        });
+        for i in 0..20u8 {
+            harness.send(2, &[i; 100]);
+            harness.run_for(Duration::from_millis(7));
+        }
+        harness.run_for(Duration::from_secs(2));
+        let output = (harness.take_output(1), harness.take_output(2));
+        (output.0, output.1, harness.elapsed())
+    }
+
+    let first = run(42);
+    assert_eq!(first, run(42));
+    assert_eq!(first.1.len(), 20 * 100);
+}
+
+#[test]
+fn shutdown_is_clean() {
+    let mut harness = Harness::new(HarnessSettings::default());
+    assert_eq!(harness.run_for(Duration::from_secs(1)), None);
+
+    harness.kernel().shutdown();
+    let stopped = harness.run_until(Duration::from_secs(1), |h| h.exit_status().is_some());
+    assert!(stopped);
+    assert_eq!(harness.exit_status(), Some(0));
+    assert!(harness.has_event(|e| e.message == "Kernel shut down cleanly."));
+}
@@ -0,0 +1,121 @@
+//! Tests of userspace's mailbox, with requests that are abandoned before
+//! their responses arrive
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+    time::Duration,
+};
+
+use melpomene::harness::{Harness, HarnessSettings};
+use mstd::{
+    abi::syscall::{
+        serial::{SerialRequest, SerialResponse},
+        KernelResponseBody, UserRequestBody,
+    },
+    executor::{
+        mailbox::{MailboxError, MailboxStats, MAILBOX},
+        time::sleep,
+    },
+    runtime,
+    serial::SerialPort,
+};
+use tracing::Level;
+
+/// A serial mux port that nothing else opens
+const PORT: u16 = 5;
+
+#[test]
+fn timed_out_requests_are_abandoned() {
+    let mut harness = Harness::new(HarnessSettings {
+        start_userspace: true,
+        trace_level: Some(Level::DEBUG),
+        ..HarnessSettings::default()
+    });
+
+    runtime::spawn_main(async {
+        MAILBOX.set_cancel_notifications(true);
+        let before = MAILBOX.stats();
+
+        // The request is sent, but the alarm goes off before the kernel can
+        // answer
+        let resp = MAILBOX
+            .request_with_timeout(
+                UserRequestBody::Serial(SerialRequest::OpenPort { port: PORT }),
+                Duration::ZERO,
+            )
+            .await;
+        assert_eq!(resp.err(), Some(MailboxError::TimedOut));
+        assert_eq!(
+            MAILBOX.stats(),
+            MailboxStats {
+                abandoned_requests: before.abandoned_requests + 1,
+                ..before
+            }
+        );
+
+        // The response still arrives, and is recognized as late, rather than
+        // unknown
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            MAILBOX.stats(),
+            MailboxStats {
+                abandoned_requests: before.abandoned_requests + 1,
+                late_responses: before.late_responses + 1,
+                ..before
+            }
+        );
+
+        // Nothing is left waiting on the abandoned request, so the next one
+        // is answered as usual. The port was opened all the same.
+        let resp = MAILBOX
+            .request_with_timeout(
+                UserRequestBody::Serial(SerialRequest::ClosePort { port: PORT }),
+                Duration::from_secs(1),
+            )
+            .await;
+        assert!(matches!(
+            resp,
+            Ok(KernelResponseBody::Serial(Ok(SerialResponse::ClosePort {
+                port: PORT
+            })))
+        ));
+        assert_eq!(MAILBOX.stats().late_responses, before.late_responses + 1);
+        assert_eq!(MAILBOX.stats().unknown_responses, before.unknown_responses);
+
+        // A response that was delivered, but never picked up, is discarded
+        // with its request, and the buffer it carries is freed
+        let mut port = SerialPort::open(PORT).await.unwrap();
+        let before = MAILBOX.stats();
+        {
+            let mut write = pin!(port.write(b"late"));
+            // Poll until the buffer has been lent to the kernel, with the request
+            while MAILBOX.stats().lent_buffers == before.lent_buffers {
+                let polled = poll_fn(|cx| Poll::Ready(write.as_mut().poll(cx))).await;
+                assert!(polled.is_pending());
+                sleep(Duration::from_millis(1)).await;
+            }
+            // The response arrives while nobody is polling the write
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            MAILBOX.stats(),
+            MailboxStats {
+                abandoned_requests: before.abandoned_requests + 1,
+                late_responses: before.late_responses + 1,
+                ..before
+            }
+        );
+        port.close().await.unwrap();
+    });
+
+    let done = harness.run_until(Duration::from_secs(5), |h| h.exit_status().is_some());
+    assert!(done, "userspace did not finish");
+    assert_eq!(harness.exit_status(), Some(0));
+    // The kernel was told the request was abandoned
+    assert!(harness.has_event(|e| e.message == "Userspace cancelled request"));
+}
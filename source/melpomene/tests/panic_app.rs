@@ -0,0 +1,38 @@
+//! Panics in userspace before anything is printed, and checks that the panic
+//! message still reaches stdout
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::time::Duration;
+
+use melpomene::harness::{Harness, HarnessSettings};
+use mstd::{runtime, stdio::STDIO_PORT, PANIC_EXIT_CODE};
+
+/// Nothing opens the stdio port before the panic
+async fn app() {
+    panic!("nothing was printed first");
+}
+
+#[test]
+fn panic_message_is_printed() {
+    let mut harness = Harness::new(HarnessSettings {
+        start_userspace: true,
+        ..HarnessSettings::default()
+    });
+
+    runtime::spawn_main(app());
+
+    let exited = harness.run_until(Duration::from_secs(5), |h| h.exit_status().is_some());
+    assert!(exited, "userspace did not finish");
+    assert_eq!(harness.exit_status(), Some(PANIC_EXIT_CODE));
+
+    let stdout = String::from_utf8(harness.take_output(STDIO_PORT)).unwrap();
+    assert!(
+        stdout.contains("panicked: nothing was printed first\r\n"),
+        "got {stdout:?}"
+    );
+    assert!(harness.has_event(
+        |e| e.message == "Userspace halted with an error" && e.field("code") == Some("101")
+    ));
+}
@@ -0,0 +1,175 @@
+//! Tests of the simulated audio output device
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use melpomene::{
+    config::{Config, PcmSinkConfig},
+    harness::{Harness, HarnessSettings},
+    sim_drivers::wav_sink::{WavFile, HEADER_LEN},
+};
+use mnemos_kernel::registry::pcm_sink::{PcmFormat, PcmSink, PcmSinkError};
+
+/// A WAV file named after the test
+fn wav_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("melpomene-{name}-{}.wav", std::process::id()))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..][..4].try_into().unwrap())
+}
+
+/// The samples in a WAV file written by [WavFile]
+fn samples(wav: &[u8]) -> Vec<i16> {
+    wav[HEADER_LEN as usize..]
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect()
+}
+
+#[test]
+fn wav_files_are_kept_valid() {
+    let path = wav_path("file");
+    let format = PcmFormat {
+        sample_rate: 44_100,
+        channels: 2,
+    };
+    let mut wav = WavFile::create(&path, format).unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    assert_eq!(bytes.len(), HEADER_LEN as usize);
+    assert_eq!(&bytes[..4], b"RIFF");
+    assert_eq!(u32_at(&bytes, 4), 36);
+    assert_eq!(&bytes[8..16], b"WAVEfmt ");
+    assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+    assert_eq!(u32_at(&bytes, 24), 44_100);
+    assert_eq!(u32_at(&bytes, 28), 44_100 * 4);
+    assert_eq!(&bytes[36..40], b"data");
+    assert_eq!(u32_at(&bytes, 40), 0);
+
+    wav.write(&[1, -1, 2, -2]).unwrap();
+    wav.write(&[i16::MAX, i16::MIN]).unwrap();
+    let bytes = fs::read(&path).unwrap();
+    assert_eq!(u32_at(&bytes, 4), 36 + 12);
+    assert_eq!(u32_at(&bytes, 40), 12);
+    assert_eq!(samples(&bytes), [1, -1, 2, -2, i16::MAX, i16::MIN]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refused_buffers_are_reported() {
+    const FRAMES: usize = 80;
+
+    let wav = wav_path("refused");
+    let mut harness = Harness::new(HarnessSettings {
+        config: Config {
+            pcm_sink: Some(PcmSinkConfig {
+                wav: wav.clone(),
+                sample_rate: 8000,
+                channels: 1,
+            }),
+            ..Config::default()
+        },
+        ..HarnessSettings::default()
+    });
+
+    let k = harness.kernel();
+    let test = k
+        .initialize(async move {
+            let mut sink = PcmSink::from_registry(k).await.unwrap();
+            let mut stream = sink.open_stream(FRAMES).await.unwrap();
+
+            // The device is disabled, so the buffer is refused, which the
+            // next buffer taken reports
+            let samples = stream.buffer().await.unwrap();
+            stream.submit(samples, FRAMES).await.unwrap();
+            k.timer().sleep(Duration::from_millis(10)).await;
+            assert_eq!(stream.buffer().await.err(), Some(PcmSinkError::NotEnabled));
+            // The refusal was only reported once, and nothing is left waiting
+            assert_eq!(stream.flush().await, Ok(()));
+
+            // A partial frame is refused too
+            sink.enable().await.unwrap();
+            let samples = stream.buffer().await.unwrap();
+            stream.submit(samples, FRAMES + 1).await.unwrap();
+            k.timer().sleep(Duration::from_millis(10)).await;
+            assert_eq!(
+                stream.buffer().await.err(),
+                Some(PcmSinkError::InvalidLength)
+            );
+
+            // The stream carries on as usual
+            let samples = stream.buffer().await.unwrap();
+            stream.submit(samples, FRAMES).await.unwrap();
+            stream.flush().await.unwrap();
+            sink.disable().await.unwrap();
+        })
+        .unwrap();
+
+    assert!(harness.run_until(Duration::from_secs(2), |_| test.is_finished()));
+    assert_eq!(harness.shutdown(), 0);
+    assert_eq!(samples(&fs::read(&wav).unwrap()).len(), FRAMES);
+    fs::remove_file(&wav).unwrap();
+}
+
+#[test]
+fn streams_are_played_in_order_and_paced() {
+    const FRAMES: usize = 800;
+    const BUFFERS: i16 = 5;
+
+    let wav = wav_path("stream");
+    let mut harness = Harness::new(HarnessSettings {
+        config: Config {
+            pcm_sink: Some(PcmSinkConfig {
+                wav: wav.clone(),
+                sample_rate: 8000,
+                channels: 1,
+            }),
+            ..Config::default()
+        },
+        ..HarnessSettings::default()
+    });
+    assert!(harness.is_registered::<PcmSink>());
+
+    let k = harness.kernel();
+    let test = k
+        .initialize(async move {
+            let mut sink = PcmSink::from_registry(k).await.unwrap();
+            assert_eq!(
+                sink.format().await.unwrap(),
+                PcmFormat {
+                    sample_rate: 8000,
+                    channels: 1
+                }
+            );
+
+            // Nothing is played until the device is enabled
+            let mut stream = sink.open_stream(FRAMES).await.unwrap();
+            assert_eq!(stream.buffer_len(), FRAMES);
+            let samples = stream.buffer().await.unwrap();
+            stream.submit(samples, FRAMES).await.unwrap();
+            assert_eq!(stream.flush().await, Err(PcmSinkError::NotEnabled));
+
+            sink.enable().await.unwrap();
+            for i in 0..BUFFERS {
+                let mut samples = stream.buffer().await.unwrap();
+                samples.iter_mut().for_each(|sample| *sample = i);
+                stream.submit(samples, FRAMES).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+            sink.disable().await.unwrap();
+        })
+        .unwrap();
+
+    assert!(harness.run_until(Duration::from_secs(2), |_| test.is_finished()));
+    // Each buffer plays for 100ms
+    assert!(harness.elapsed() >= Duration::from_millis(500));
+    assert_eq!(harness.shutdown(), 0);
+
+    let samples = samples(&fs::read(&wav).unwrap());
+    assert_eq!(samples.len(), FRAMES * BUFFERS as usize);
+    for (i, buffer) in samples.chunks(FRAMES).enumerate() {
+        assert!(buffer.iter().all(|&sample| sample == i as i16));
+    }
+    fs::remove_file(&wav).unwrap();
+}
@@ -0,0 +1,67 @@
+//! Tests of the events published to userspace by its serial ports
+//!
+//! Userspace can only be started once per process, so this is its own test
+//! binary.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use melpomene::harness::{Harness, HarnessSettings};
+use mstd::{
+    abi::syscall::{
+        event::{EventKind, EventService, KernelEvent},
+        serial::SerialEvent,
+    },
+    executor::mailbox::MAILBOX,
+    runtime,
+    serial::SerialPort,
+};
+
+/// A serial mux port that nothing else opens
+const PORT: u16 = 4;
+
+/// Set once userspace is listening for events on [PORT]
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+#[test]
+fn data_available_is_published() {
+    let mut harness = Harness::new(HarnessSettings {
+        start_userspace: true,
+        ..HarnessSettings::default()
+    });
+
+    runtime::spawn_main(async {
+        let mut events = MAILBOX
+            .subscribe(EventService::Serial, Some(EventKind::SerialDataAvailable))
+            .unwrap();
+        let mut port = SerialPort::open(PORT).await.unwrap();
+        LISTENING.store(true, Ordering::Release);
+
+        // Nothing is waiting to read from the port, so the kernel says
+        // there's data, and keeps it until a buffer is provided
+        let event = events.next().await;
+        assert!(
+            matches!(
+                event,
+                KernelEvent::Serial(SerialEvent::DataAvailable { port: PORT, len: 4 })
+            ),
+            "got {event:?}"
+        );
+        let mut buf = [0; 16];
+        let len = port.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert!(events.try_next().is_none());
+    });
+
+    let listening = harness.run_until(Duration::from_secs(1), |_| {
+        LISTENING.load(Ordering::Acquire)
+    });
+    assert!(listening, "userspace did not open the port");
+    harness.send(PORT, b"ping");
+
+    let done = harness.run_until(Duration::from_secs(5), |h| h.exit_status().is_some());
+    assert!(done, "userspace did not receive the data");
+    assert_eq!(harness.exit_status(), Some(0));
+}
@@ -0,0 +1,79 @@
+//! Procedural macros for `mstd`
+//!
+//! These are re-exported by `mstd`, and should be used from there.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Error, ItemFn};
+
+/// Marks the `async fn main` of a userspace application.
+///
+/// This generates the `entry` function that `mstd` calls at boot, which runs
+/// `main` using [`mstd::runtime::start`]. `main` may return `()`, or a
+/// `Result<(), E>` where `E: Debug`.
+///
+/// ```rust,ignore
+/// #[mstd::main]
+/// async fn main() {
+///     mstd::println!("Hello, world!");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = proc_macro2::TokenStream::from(args);
+    let main = parse_macro_input!(item as ItemFn);
+
+    match expand_main(args, main) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_main(
+    args: proc_macro2::TokenStream,
+    main: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if !args.is_empty() {
+        return Err(Error::new(
+            args.span(),
+            "`#[mstd::main]` takes no arguments",
+        ));
+    }
+
+    let sig = &main.sig;
+    if sig.ident != "main" {
+        return Err(Error::new(
+            sig.ident.span(),
+            "`#[mstd::main]` can only be used on `fn main`",
+        ));
+    }
+    if sig.asyncness.is_none() {
+        return Err(Error::new(
+            sig.fn_token.span(),
+            "`#[mstd::main]` must be used on an `async fn`",
+        ));
+    }
+    if !sig.inputs.is_empty() {
+        return Err(Error::new(
+            sig.inputs.span(),
+            "`main` must not take any arguments",
+        ));
+    }
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "`main` must not be generic",
+        ));
+    }
+
+    Ok(quote! {
+        #main
+
+        #[doc(hidden)]
+        #[no_mangle]
+        fn entry() -> ! {
+            // SAFETY: `mstd` calls `entry` exactly once, at boot
+            unsafe { ::mstd::runtime::start(main()) }
+        }
+    })
+}
@@ -0,0 +1,150 @@
+//! Collections allocated on the userspace heap
+//!
+//! Allocating may have to wait for the heap to become available, so every
+//! type here has two ways to allocate:
+//!
+//! * An `async` method, e.g. [Box::new] or [Vec::push], that waits until the
+//!   allocation succeeds
+//! * A `try_` method, e.g. [Box::try_new] or [Vec::try_push], that fails
+//!   instead of waiting, if the heap is busy or full
+//!
+//! The `try_` methods can be used where waiting isn't possible, for example
+//! when implementing [core::fmt::Write] for [String].
+
+mod string;
+mod vec;
+
+use core::{
+    fmt,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+use mnemos_alloc::containers::{HeapArc, HeapBox};
+
+use crate::executor::EXECUTOR;
+
+pub use self::{string::String, vec::Vec};
+
+/// The error returned when an allocation can't be made without waiting.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AllocError;
+
+/// A pointer to a single value on the userspace heap
+pub struct Box<T> {
+    inner: HeapBox<T>,
+}
+
+/// A reference counted pointer to a value on the userspace heap
+pub struct Arc<T> {
+    inner: HeapArc<T>,
+}
+
+// Box
+
+impl<T> Box<T> {
+    /// Move `item` onto the heap, waiting for room if needed.
+    pub async fn new(item: T) -> Self {
+        Self {
+            inner: EXECUTOR.get_alloc().allocate(item).await,
+        }
+    }
+
+    /// Move `item` onto the heap without waiting, handing it back on failure.
+    pub fn try_new(item: T) -> Result<Self, T> {
+        let mut guard = match EXECUTOR.get_alloc().lock() {
+            Ok(guard) => guard,
+            Err(()) => return Err(item),
+        };
+        let inner = guard.alloc_box(item)?;
+        Ok(Self { inner })
+    }
+
+    /// Move the value back off the heap, freeing the allocation.
+    pub fn into_inner(this: Self) -> T {
+        let ptr = this.inner.leak();
+        // SAFETY: The value is moved out exactly once, and the allocation is
+        // then freed as `MaybeUninit`, which does not drop it again.
+        unsafe {
+            let item = ptr::read(ptr.as_ptr());
+            let _freed = HeapBox::from_leaked(ptr.cast::<MaybeUninit<T>>());
+            item
+        }
+    }
+}
+
+impl<T> Deref for Box<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Box<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Box<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Box<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// Arc
+
+impl<T> Arc<T> {
+    /// Move `item` onto the heap, waiting for room if needed.
+    pub async fn new(item: T) -> Self {
+        Self {
+            inner: EXECUTOR.get_alloc().allocate_arc(item).await,
+        }
+    }
+
+    /// Move `item` onto the heap without waiting, handing it back on failure.
+    pub fn try_new(item: T) -> Result<Self, T> {
+        let mut guard = match EXECUTOR.get_alloc().lock() {
+            Ok(guard) => guard,
+            Err(()) => return Err(item),
+        };
+        let inner = guard.alloc_arc(item)?;
+        Ok(Self { inner })
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,156 @@
+use core::{fmt, ops::Deref, str};
+
+use super::{AllocError, Vec};
+
+/// A growable UTF-8 string on the userspace heap
+///
+/// [fmt::Write] can't wait, so writing grows the string with
+/// [String::try_push_str], and fails if the heap is busy or full. Use
+/// [String::with_capacity] to make room up front.
+#[derive(Default)]
+pub struct String {
+    vec: Vec<u8>,
+}
+
+impl String {
+    /// Create an empty string. This does not allocate.
+    pub const fn new() -> Self {
+        Self { vec: Vec::new() }
+    }
+
+    /// Create an empty string with room for `capacity` bytes, waiting for
+    /// room on the heap if needed.
+    pub async fn with_capacity(capacity: usize) -> Self {
+        Self {
+            vec: Vec::with_capacity(capacity).await,
+        }
+    }
+
+    /// Create an empty string with room for `capacity` bytes, without waiting.
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, AllocError> {
+        Ok(Self {
+            vec: Vec::try_with_capacity(capacity)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// The number of bytes the string can hold without growing
+    pub fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    /// Make room for at least `additional` more bytes, waiting for room on the
+    /// heap if needed.
+    pub async fn reserve(&mut self, additional: usize) {
+        self.vec.reserve(additional).await
+    }
+
+    /// Make room for at least `additional` more bytes, without waiting.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.vec.try_reserve(additional)
+    }
+
+    /// Append a string slice, growing the string if needed.
+    pub async fn push_str(&mut self, s: &str) {
+        self.vec.extend_from_slice(s.as_bytes()).await
+    }
+
+    /// Append a string slice without waiting. Nothing is appended if the
+    /// string can't grow enough.
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), AllocError> {
+        self.vec.try_extend_from_slice(s.as_bytes())
+    }
+
+    /// Append a character, growing the string if needed.
+    pub async fn push(&mut self, ch: char) {
+        self.push_str(ch.encode_utf8(&mut [0; 4])).await
+    }
+
+    /// Append a character without waiting.
+    pub fn try_push(&mut self, ch: char) -> Result<(), AllocError> {
+        self.try_push_str(ch.encode_utf8(&mut [0; 4]))
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let ch = self.as_str().chars().next_back()?;
+        self.vec.truncate(self.len() - ch.len_utf8());
+        Some(ch)
+    }
+
+    /// Shorten the string to `len` bytes.
+    ///
+    /// Panics if `len` is not on a character boundary.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            assert!(self.as_str().is_char_boundary(len), "not a char boundary");
+            self.vec.truncate(len);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.vec.clear();
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: Only valid UTF-8 is ever appended, and only ever truncated
+        // on a character boundary.
+        unsafe { str::from_utf8_unchecked(&self.vec) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.vec
+    }
+}
+
+impl Deref for String {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Write for String {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.try_push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for String {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl PartialEq for String {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for String {}
+
+impl PartialEq<str> for String {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for String {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
@@ -0,0 +1,262 @@
+use core::{
+    fmt,
+    mem::{self, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+
+use mnemos_alloc::containers::HeapArray;
+
+use super::AllocError;
+use crate::executor::EXECUTOR;
+
+/// The capacity allocated the first time a [Vec] grows
+const MIN_CAPACITY: usize = 4;
+
+/// A growable array on the userspace heap
+pub struct Vec<T> {
+    /// `None` until the first allocation
+    buf: Option<HeapArray<MaybeUninit<T>>>,
+    len: usize,
+}
+
+impl<T> Vec<T> {
+    /// Create an empty vec. This does not allocate.
+    pub const fn new() -> Self {
+        Self { buf: None, len: 0 }
+    }
+
+    /// Create an empty vec with room for `capacity` items, waiting for room
+    /// on the heap if needed.
+    pub async fn with_capacity(capacity: usize) -> Self {
+        let mut vec = Self::new();
+        vec.reserve(capacity).await;
+        vec
+    }
+
+    /// Create an empty vec with room for `capacity` items, without waiting.
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, AllocError> {
+        let mut vec = Self::new();
+        vec.try_reserve(capacity)?;
+        Ok(vec)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of items the vec can hold without growing
+    pub fn capacity(&self) -> usize {
+        self.buf.as_ref().map(|buf| buf.len()).unwrap_or(0)
+    }
+
+    /// Make room for at least `additional` more items, waiting for room on the
+    /// heap if needed.
+    ///
+    /// Panics if that many items could never fit in memory.
+    pub async fn reserve(&mut self, additional: usize) {
+        if let Some(capacity) = self.grow_to(additional).expect("capacity overflow") {
+            let new = EXECUTOR
+                .get_alloc()
+                .allocate_array_with(MaybeUninit::uninit, capacity)
+                .await;
+            self.replace_buf(new);
+        }
+    }
+
+    /// Make room for at least `additional` more items, without waiting.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        if let Some(capacity) = self.grow_to(additional)? {
+            let new = EXECUTOR
+                .get_alloc()
+                .lock()
+                .map_err(|_| AllocError)?
+                .alloc_box_array_with(MaybeUninit::uninit, capacity)
+                .map_err(|_| AllocError)?;
+            self.replace_buf(new);
+        }
+        Ok(())
+    }
+
+    /// Append an item, growing the vec if needed.
+    pub async fn push(&mut self, item: T) {
+        self.reserve(1).await;
+        self.push_within_capacity(item);
+    }
+
+    /// Append an item without waiting, handing it back if the vec is full
+    /// and can't grow.
+    pub fn try_push(&mut self, item: T) -> Result<(), T> {
+        if self.try_reserve(1).is_err() {
+            return Err(item);
+        }
+        self.push_within_capacity(item);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        // SAFETY: The item was initialized, and is no longer part of the vec
+        Some(unsafe { ptr::read(self.as_mut_ptr().add(self.len)) })
+    }
+
+    /// Remove the item at `index`, moving every later item down by one.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "index out of bounds");
+        // SAFETY: `index` is in bounds, and the items after it are moved down
+        // to fill the gap.
+        unsafe {
+            let ptr = self.as_mut_ptr().add(index);
+            let item = ptr::read(ptr);
+            ptr::copy(ptr.add(1), ptr, self.len - index - 1);
+            self.len -= 1;
+            item
+        }
+    }
+
+    /// Drop every item past the first `len`.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        let tail = self.len - len;
+        self.len = len;
+        // SAFETY: These items were initialized, and are no longer part of the vec
+        unsafe {
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                self.as_mut_ptr().add(len),
+                tail,
+            ));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: The first `len` items are initialized
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: The first `len` items are initialized
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+
+    fn as_ptr(&self) -> *const T {
+        match self.buf.as_ref() {
+            Some(buf) => buf.as_ptr().cast(),
+            None => ptr::NonNull::dangling().as_ptr(),
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut T {
+        match self.buf.as_mut() {
+            Some(buf) => buf.as_mut_ptr().cast(),
+            None => ptr::NonNull::dangling().as_ptr(),
+        }
+    }
+
+    /// The capacity to grow to, if `additional` more items don't fit.
+    ///
+    /// Fails if that many items could never fit in memory.
+    fn grow_to(&self, additional: usize) -> Result<Option<usize>, AllocError> {
+        let needed = self.len.checked_add(additional).ok_or(AllocError)?;
+        let capacity = self.capacity();
+        if needed <= capacity {
+            return Ok(None);
+        }
+        let max = isize::MAX as usize / mem::size_of::<T>().max(1);
+        if needed > max {
+            return Err(AllocError);
+        }
+        Ok(Some(
+            needed
+                .max(capacity.saturating_mul(2))
+                .max(MIN_CAPACITY)
+                .min(max),
+        ))
+    }
+
+    /// Move the items into a new, larger, buffer, freeing the old one.
+    fn replace_buf(&mut self, mut new: HeapArray<MaybeUninit<T>>) {
+        debug_assert!(new.len() >= self.len);
+        if let Some(old) = self.buf.as_ref() {
+            // SAFETY: The buffers don't overlap, and the old buffer is freed as
+            // `MaybeUninit`, which won't drop the moved items.
+            unsafe {
+                ptr::copy_nonoverlapping(old.as_ptr(), new.as_mut_ptr(), self.len);
+            }
+        }
+        self.buf = Some(new);
+    }
+
+    fn push_within_capacity(&mut self, item: T) {
+        debug_assert!(self.len < self.capacity());
+        // SAFETY: There is room for the item
+        unsafe { self.as_mut_ptr().add(self.len).write(item) };
+        self.len += 1;
+    }
+}
+
+impl<T: Clone> Vec<T> {
+    /// Append clones of every item in `items`, growing the vec if needed.
+    pub async fn extend_from_slice(&mut self, items: &[T]) {
+        self.reserve(items.len()).await;
+        items
+            .iter()
+            .for_each(|item| self.push_within_capacity(item.clone()));
+    }
+
+    /// Append clones of every item in `items` without waiting. Nothing is
+    /// appended if the vec can't grow enough.
+    pub fn try_extend_from_slice(&mut self, items: &[T]) -> Result<(), AllocError> {
+        self.try_reserve(items.len())?;
+        items
+            .iter()
+            .for_each(|item| self.push_within_capacity(item.clone()));
+        Ok(())
+    }
+}
+
+impl<T> Default for Vec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Vec<T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T> Deref for Vec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T> DerefMut for Vec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Vec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_slice(), f)
+    }
+}
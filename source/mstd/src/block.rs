@@ -0,0 +1,217 @@
+//! Named blocks on the block storage device
+//!
+//! Each block holds a single file, with a name, a length and a [BlockKind].
+//! A block is opened as a [Block], read and written, then closed, which
+//! records its name, length and kind. The first write after opening erases
+//! the whole block, so a block is always rewritten from scratch.
+
+use crate::executor::{
+    mailbox::MAILBOX,
+    wire::{self, WireBuf, WIRE_BUF_SIZE},
+};
+use abi::syscall::{
+    block::{BlockRequest, BlockResponse},
+    KernelResponseBody, UserRequestBody,
+};
+
+pub use abi::syscall::block::{BlockError, BlockKind, BlockStatus, StoreInfo, MAX_NAME_LEN};
+
+/// Information about a single block
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockInfo {
+    /// The used length of the block, in bytes
+    pub length: u32,
+    /// The capacity of the block, in bytes
+    pub capacity: u32,
+    pub kind: BlockKind,
+    pub status: BlockStatus,
+    /// The block's name. Names that aren't valid UTF-8 are reported as no
+    /// name.
+    pub name: Option<heapless::String<MAX_NAME_LEN>>,
+}
+
+/// An open block.
+///
+/// Dropping a `Block` without calling [Block::close] leaves the block open,
+/// and it can not be opened again.
+pub struct Block {
+    block: u32,
+}
+
+/// Get the number and capacity of the blocks on the device.
+pub async fn store_info() -> Result<StoreInfo, BlockError> {
+    match request(BlockRequest::StoreInfo).await? {
+        BlockResponse::StoreInfo(info) => Ok(info),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Get information about a block.
+pub async fn block_info(req_block: u32) -> Result<BlockInfo, BlockError> {
+    let req = BlockRequest::BlockInfo {
+        block: req_block,
+        name_buf: WireBuf::new().await.into_wire(),
+    };
+    let (info, name_buf) = match request(req).await? {
+        BlockResponse::BlockInfo {
+            block,
+            info,
+            name_buf,
+        } if block == req_block => (info, name_buf),
+        other => return Err(unexpected(other)),
+    };
+    // SAFETY: The kernel gives back the buffer we lent it
+    let name_buf = unsafe { WireBuf::from_wire(name_buf) }.ok_or(BlockError::Unknown)?;
+
+    let name = info
+        .name_len
+        .and_then(|len| name_buf.get(..len))
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .and_then(|name| {
+            let mut string = heapless::String::new();
+            string.push_str(name).ok()?;
+            Some(string)
+        });
+    Ok(BlockInfo {
+        length: info.length,
+        capacity: info.capacity,
+        kind: info.kind,
+        status: info.status,
+        name,
+    })
+}
+
+impl Block {
+    pub async fn open(req_block: u32) -> Result<Self, BlockError> {
+        match request(BlockRequest::Open { block: req_block }).await? {
+            BlockResponse::Opened { block } if block == req_block => Ok(Block { block }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn block(&self) -> u32 {
+        self.block
+    }
+
+    /// Read from the block into `buf`, starting `offset` bytes in, which
+    /// must be 4-byte aligned.
+    ///
+    /// Returns the number of bytes read, which is less than `buf.len()` only
+    /// if the end of the block is reached.
+    pub async fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, BlockError> {
+        let mut spare = None;
+        let mut read = 0;
+
+        for chunk in buf.chunks_mut(WIRE_BUF_SIZE) {
+            let chunk_offset = offset_by(offset, read)?;
+            let wire = match spare.take() {
+                Some(wire) => wire,
+                None => WireBuf::new().await,
+            };
+            let req = BlockRequest::Read {
+                block: self.block,
+                offset: chunk_offset,
+                buffer: wire.into_wire(),
+                len: chunk.len(),
+            };
+            let (buffer, used) = match request(req).await? {
+                BlockResponse::Read {
+                    block,
+                    buffer,
+                    used,
+                } if block == self.block => (buffer, used.min(chunk.len())),
+                other => return Err(unexpected(other)),
+            };
+            // SAFETY: The kernel gives back the buffer we lent it
+            let wire = unsafe { WireBuf::from_wire(buffer) }.ok_or(BlockError::Unknown)?;
+            chunk[..used].copy_from_slice(&wire[..used]);
+            read += used;
+            if used < chunk.len() {
+                break;
+            }
+            spare = Some(wire);
+        }
+
+        Ok(read)
+    }
+
+    /// Write all of `data` to the block, starting `offset` bytes in, which
+    /// must be 4-byte aligned.
+    ///
+    /// The first write after opening the block erases all of it.
+    pub async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), BlockError> {
+        let mut spare = None;
+
+        for (i, chunk) in data.chunks(WIRE_BUF_SIZE).enumerate() {
+            let chunk_offset = offset_by(offset, i * WIRE_BUF_SIZE)?;
+            let mut wire = match spare.take() {
+                Some(wire) => wire,
+                None => WireBuf::new().await,
+            };
+            wire[..chunk.len()].copy_from_slice(chunk);
+            let req = BlockRequest::Write {
+                block: self.block,
+                offset: chunk_offset,
+                buffer: wire.into_wire(),
+                used: chunk.len(),
+            };
+            let buffer = match request(req).await? {
+                BlockResponse::Written { block, buffer } if block == self.block => buffer,
+                other => return Err(unexpected(other)),
+            };
+            // SAFETY: The kernel gives back the buffer we lent it
+            spare = Some(unsafe { WireBuf::from_wire(buffer) }.ok_or(BlockError::Unknown)?);
+        }
+
+        Ok(())
+    }
+
+    /// Close the block, recording its name, the length of its data, and its
+    /// kind.
+    ///
+    /// The name may be any UTF-8 string of up to [MAX_NAME_LEN] bytes. An
+    /// empty name leaves the block unnamed.
+    pub async fn close(self, name: &str, len: u32, kind: BlockKind) -> Result<(), BlockError> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(BlockError::InvalidName);
+        }
+        let mut wire = WireBuf::new().await;
+        wire[..name.len()].copy_from_slice(name.as_bytes());
+
+        let req = BlockRequest::Close {
+            block: self.block,
+            name: wire.into_wire(),
+            name_len: name.len(),
+            len,
+            kind,
+        };
+        match request(req).await? {
+            BlockResponse::Closed { block, name } if block == self.block => {
+                wire::reclaim(name);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+/// The offset `advance` bytes past `offset`, failing if it doesn't fit.
+fn offset_by(offset: u32, advance: usize) -> Result<u32, BlockError> {
+    u32::try_from(advance)
+        .ok()
+        .and_then(|advance| offset.checked_add(advance))
+        .ok_or(BlockError::OutOfBounds)
+}
+
+async fn request(req: BlockRequest) -> Result<BlockResponse, BlockError> {
+    match MAILBOX.request(UserRequestBody::Block(req)).await {
+        Ok(KernelResponseBody::Block(resp)) => resp,
+        _ => Err(BlockError::Unknown),
+    }
+}
+
+/// Handle a response that doesn't match the request, freeing any buffer it carries.
+fn unexpected(resp: BlockResponse) -> BlockError {
+    wire::reclaim_response(KernelResponseBody::Block(Ok(resp)));
+    BlockError::Unknown
+}
@@ -0,0 +1,189 @@
+//! Subscriptions to unsolicited events from the kernel
+//!
+//! Events arrive from the kernel without a nonce, so they can't be matched to
+//! a waiting request. Instead, tasks subscribe to the events they are
+//! interested in with [MailBox::subscribe](super::mailbox::MailBox::subscribe),
+//! and each matching event is copied into the queue of every subscription.
+
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use abi::syscall::event::{EventKind, EventService, KernelEvent};
+use futures_util::{future::poll_fn, Stream};
+use heapless::{Deque, Vec};
+
+use crate::utils::ArfCell;
+
+/// The maximum number of live subscriptions
+const MAX_SUBSCRIPTIONS: usize = 16;
+/// The number of events buffered for each subscription. When the queue is
+/// full, the oldest event is dropped.
+const EVENT_QUEUE_DEPTH: usize = 8;
+
+/// A stream of events from the kernel, matching a single service, and
+/// optionally a single kind of event.
+///
+/// Dropping the subscription unsubscribes.
+pub struct Subscription {
+    subs: &'static Subscriptions,
+    id: u32,
+}
+
+pub(crate) struct Subscriptions {
+    slots: ArfCell<Vec<Slot, MAX_SUBSCRIPTIONS>>,
+    next_id: AtomicU32,
+    /// Events that no subscription was interested in
+    pub(crate) unclaimed: AtomicU32,
+    /// Events dropped because a subscription's queue was full
+    pub(crate) lagged: AtomicU32,
+}
+
+struct Slot {
+    id: u32,
+    service: EventService,
+    kind: Option<EventKind>,
+    queue: Deque<KernelEvent, EVENT_QUEUE_DEPTH>,
+    waker: Option<Waker>,
+}
+
+// Subscriptions
+
+impl Subscriptions {
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: ArfCell::new(Vec::new()),
+            next_id: AtomicU32::new(0),
+            unclaimed: AtomicU32::new(0),
+            lagged: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns `None` if there are already too many subscriptions.
+    pub(crate) fn subscribe(
+        &'static self,
+        service: EventService,
+        kind: Option<EventKind>,
+    ) -> Option<Subscription> {
+        let mut slots = self.slots.borrow_mut().ok()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        slots
+            .push(Slot {
+                id,
+                service,
+                kind,
+                queue: Deque::new(),
+                waker: None,
+            })
+            .ok()?;
+        Some(Subscription { subs: self, id })
+    }
+
+    /// Copy an event into the queue of every matching subscription
+    pub(crate) fn dispatch(&self, event: KernelEvent) {
+        let mut slots = match self.slots.borrow_mut() {
+            Ok(slots) => slots,
+            Err(_) => {
+                self.unclaimed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let service = event.service();
+        let kind = event.kind();
+        let mut claimed = false;
+        for slot in slots.iter_mut() {
+            if !slot.matches(service, kind) {
+                continue;
+            }
+            claimed = true;
+
+            if slot.queue.is_full() {
+                slot.queue.pop_front();
+                self.lagged.fetch_add(1, Ordering::Relaxed);
+            }
+            let _ = slot.queue.push_back(event.clone());
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+
+        if !claimed {
+            self.unclaimed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Slot
+
+impl Slot {
+    fn matches(&self, service: EventService, kind: EventKind) -> bool {
+        match self.kind {
+            Some(k) => self.service == service && k == kind,
+            None => self.service == service,
+        }
+    }
+}
+
+// Subscription
+
+impl Subscription {
+    /// Wait for the next matching event
+    pub async fn next(&mut self) -> KernelEvent {
+        poll_fn(|cx| self.poll_event(cx)).await
+    }
+
+    /// Take the next matching event, if one has already arrived
+    pub fn try_next(&mut self) -> Option<KernelEvent> {
+        let mut slots = self.subs.slots.borrow_mut().ok()?;
+        slots
+            .iter_mut()
+            .find(|s| s.id == self.id)?
+            .queue
+            .pop_front()
+    }
+
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<KernelEvent> {
+        let mut slots = match self.subs.slots.borrow_mut() {
+            Ok(slots) => slots,
+            Err(_) => {
+                // The mailbox is dispatching, try again shortly
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+        };
+
+        // Our slot is only removed when we are dropped
+        let slot = match slots.iter_mut().find(|s| s.id == self.id) {
+            Some(slot) => slot,
+            None => return Poll::Pending,
+        };
+        match slot.queue.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Stream for Subscription {
+    type Item = KernelEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KernelEvent>> {
+        self.get_mut().poll_event(cx).map(Some)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut slots) = self.subs.slots.borrow_mut() {
+            if let Some(idx) = slots.iter().position(|s| s.id == self.id) {
+                slots.swap_remove(idx);
+            }
+        }
+    }
+}
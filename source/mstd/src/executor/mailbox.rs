@@ -36,29 +36,124 @@
 
 use core::{
     cell::UnsafeCell,
+    future::Future,
     mem::MaybeUninit,
+    pin::Pin,
     sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::{Context, Poll},
 };
 
 use abi::{
     bbqueue_ipc::framed::{FrameConsumer, FrameProducer},
     syscall::{
-        KernelMsg, KernelResponse, KernelResponseBody, UserRequest, UserRequestBody,
-        UserRequestHeader,
+        event::{EventKind, EventService},
+        KernelMsg, KernelResponse, KernelResponseBody, KernelResponseHeader, UserRequest,
+        UserRequestBody, UserRequestHeader,
     },
 };
-use futures_util::pin_mut;
-use maitake::wait::{WaitMap, WaitQueue};
+use futures_util::{
+    future::{select, Either},
+    pin_mut,
+    task::noop_waker_ref,
+};
+use heapless::Vec;
+use maitake::wait::{
+    map::{Wait, WakeOutcome},
+    WaitMap, WaitQueue,
+};
+
+use crate::{
+    executor::{
+        events::{Subscription, Subscriptions},
+        time::{Alarm, Duration},
+        wire,
+    },
+    utils::ArfCell,
+};
+use postcard::experimental::max_size::MaxSize;
 
 pub static MAILBOX: MailBox = MailBox::new();
 
+#[derive(Debug, Eq, PartialEq)]
+pub enum MailboxError {
+    /// The mailbox has been closed
+    Closed,
+    /// There is no room in the u2k ring right now
+    Full,
+    /// The kernel could not decode our request
+    MalformedRequest,
+    /// We could not decode the kernel's response to our request
+    MalformedResponse,
+    /// The request could not be serialized
+    Serialization,
+    /// The serialized request is too large to ever fit in the u2k ring
+    Oversized { max_size: usize },
+    /// No response was received before the timeout elapsed
+    TimedOut,
+    /// There are already too many live event subscriptions
+    TooManySubscriptions,
+    /// The kernel has no driver that can handle the request
+    NoDriver,
+}
+
+/// Counters for messages from the kernel that userspace could not handle
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MailboxStats {
+    /// Messages that could not be decoded, and were dropped
+    pub malformed_messages: u32,
+    /// Requests that the kernel reported as malformed
+    pub rejected_requests: u32,
+    /// Requests that were dropped or timed out before their response was
+    /// picked up
+    pub abandoned_requests: u32,
+    /// Responses to abandoned requests
+    pub late_responses: u32,
+    /// Responses that did not match any request
+    pub unknown_responses: u32,
+    /// Events that no subscription was interested in
+    pub unclaimed_events: u32,
+    /// Events dropped because a subscriber wasn't keeping up
+    pub lagged_events: u32,
+    /// Buffers lent to the kernel, and not yet given back
+    pub lent_buffers: u32,
+}
+
+/// The number of abandoned requests that are remembered, so that their late
+/// responses can be recognized. Beyond this, the oldest are forgotten.
+const MAX_ABANDONED: usize = 32;
+
 // TODO: There's a bit of mutexing going on here. `send_wait` and `recv_wait` BOTH have
 pub struct MailBox {
     nonce: AtomicU32,
     inhibit_send: AtomicBool,
     send_wait: WaitQueue,
-    recv_wait: WaitMap<u32, KernelResponseBody>,
+    recv_wait: WaitMap<u32, Response>,
     rings: OnceRings,
+    /// The number of messages read from the k2u ring (wrapping)
+    received: AtomicU32,
+    malformed_messages: AtomicU32,
+    rejected_requests: AtomicU32,
+    abandoned_requests: AtomicU32,
+    late_responses: AtomicU32,
+    unknown_responses: AtomicU32,
+    /// Nonces of requests that were sent, but dropped before their response arrived
+    abandoned: ArfCell<Vec<u32, MAX_ABANDONED>>,
+    /// Should the kernel be told when a request is abandoned?
+    notify_cancel: AtomicBool,
+    events: Subscriptions,
+}
+
+type Response = Result<KernelResponseBody, MailboxError>;
+
+/// Marks a request as abandoned if it is dropped before its response arrives,
+/// or frees its response if it is dropped before picking it up.
+struct RequestGuard<'a> {
+    mailbox: &'static MailBox,
+    nonce: u32,
+    /// Where the response is delivered
+    rx: Pin<&'a mut Wait<'static, u32, Response>>,
+    sent: bool,
+    complete: bool,
 }
 
 impl MailBox {
@@ -69,6 +164,15 @@ impl MailBox {
             send_wait: WaitQueue::new(),
             recv_wait: WaitMap::new(),
             rings: OnceRings::new(),
+            received: AtomicU32::new(0),
+            malformed_messages: AtomicU32::new(0),
+            rejected_requests: AtomicU32::new(0),
+            abandoned_requests: AtomicU32::new(0),
+            late_responses: AtomicU32::new(0),
+            unknown_responses: AtomicU32::new(0),
+            abandoned: ArfCell::new(Vec::new()),
+            notify_cancel: AtomicBool::new(false),
+            events: Subscriptions::new(),
         }
     }
 
@@ -80,41 +184,193 @@ impl MailBox {
         let rings = self.rings.get();
 
         while let Some(msg) = rings.k2u.read() {
+            self.received.fetch_add(1, Ordering::AcqRel);
             match postcard::from_bytes::<KernelMsg>(&msg) {
+                Ok(KernelMsg::Response(KernelResponse {
+                    header,
+                    body: KernelResponseBody::MalformedRequest,
+                })) => {
+                    self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+                    self.deliver(header.nonce, Err(MailboxError::MalformedRequest));
+                }
+                Ok(KernelMsg::Response(KernelResponse {
+                    header,
+                    body: KernelResponseBody::NoDriver,
+                })) => {
+                    self.deliver(header.nonce, Err(MailboxError::NoDriver));
+                }
                 Ok(KernelMsg::Response(KernelResponse { header, body })) => {
-                    // Attempt to wake a relevant waiting task, OR drop the response
-                    self.recv_wait.wake(&header.nonce, body);
+                    self.deliver(header.nonce, Ok(body));
                 }
-                Ok(_) => todo!(),
+                Ok(KernelMsg::Timestamp(timestamp)) => {
+                    crate::executor::time::update_time(timestamp);
+                }
+                Ok(KernelMsg::Event(event)) => self.events.dispatch(event),
+                Ok(KernelMsg::Dealloc(wire)) => wire::reclaim(wire),
                 Err(_) => {
-                    // todo: print something? Relax this panic later with a graceful
-                    // warning
-                    panic!("Decoded bad message from kernel?");
+                    // Drop the message. If it was a response, let the waiting
+                    // task know, rather than leaving it waiting forever.
+                    self.malformed_messages.fetch_add(1, Ordering::Relaxed);
+                    if let Some(header) = KernelResponseHeader::recover(&msg) {
+                        self.deliver(header.nonce, Err(MailboxError::MalformedResponse));
+                    }
                 }
             }
 
             msg.release();
         }
 
-        if self.inhibit_send.load(Ordering::Acquire) && rings.u2k.grant(128).is_ok() {
+        if self.inhibit_send.load(Ordering::Acquire)
+            && rings.u2k.grant(Self::grant_size(rings)).is_ok()
+        {
             self.inhibit_send.store(false, Ordering::Release);
             self.send_wait.wake_all();
         }
     }
 
-    async fn send_inner(&'static self, nonce: u32, msg: UserRequestBody) -> Result<(), ()> {
+    /// The number of messages read from the kernel so far (wrapping), see
+    /// [UserRequestBody::Wait].
+    pub(crate) fn received(&self) -> u32 {
+        self.received.load(Ordering::Acquire)
+    }
+
+    /// Are there messages from the kernel that have not been read yet?
+    pub(crate) fn has_messages(&self) -> bool {
+        // Dropping the grant without releasing it leaves the message in place
+        self.rings.get().k2u.read().is_some()
+    }
+
+    /// Counters for messages from the kernel that could not be handled.
+    pub fn stats(&self) -> MailboxStats {
+        MailboxStats {
+            malformed_messages: self.malformed_messages.load(Ordering::Relaxed),
+            rejected_requests: self.rejected_requests.load(Ordering::Relaxed),
+            abandoned_requests: self.abandoned_requests.load(Ordering::Relaxed),
+            late_responses: self.late_responses.load(Ordering::Relaxed),
+            unknown_responses: self.unknown_responses.load(Ordering::Relaxed),
+            unclaimed_events: self.events.unclaimed.load(Ordering::Relaxed),
+            lagged_events: self.events.lagged.load(Ordering::Relaxed),
+            lent_buffers: wire::lent(),
+        }
+    }
+
+    /// Subscribe to unsolicited events from the given kernel service.
+    ///
+    /// If `kind` is `None`, every event from the service is received. Events
+    /// that arrived before subscribing are not received.
+    pub fn subscribe(
+        &'static self,
+        service: EventService,
+        kind: Option<EventKind>,
+    ) -> Result<Subscription, MailboxError> {
+        self.events
+            .subscribe(service, kind)
+            .ok_or(MailboxError::TooManySubscriptions)
+    }
+
+    /// Should the kernel be sent a cancel message when a request is dropped
+    /// before its response arrives? This is disabled by default.
+    ///
+    /// Cancel messages are sent on a best-effort basis, and are skipped if
+    /// there is no room in the u2k ring.
+    pub fn set_cancel_notifications(&self, enabled: bool) {
+        self.notify_cancel.store(enabled, Ordering::Release);
+    }
+
+    /// Attempt to wake the task waiting for a response, OR drop the response
+    fn deliver(&self, nonce: u32, resp: Response) {
+        match self.recv_wait.wake(&nonce, resp) {
+            WakeOutcome::Woke => {}
+            WakeOutcome::NoMatch(resp) | WakeOutcome::Closed(resp) => {
+                if let Ok(body) = resp {
+                    wire::reclaim_response(body);
+                }
+                if self.take_abandoned(nonce) {
+                    self.late_responses.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.unknown_responses.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn abandon(&'static self, nonce: u32) {
+        self.abandoned_requests.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut abandoned) = self.abandoned.borrow_mut() {
+            if abandoned.is_full() {
+                abandoned.remove(0);
+            }
+            let _ = abandoned.push(nonce);
+        }
+
+        if self.notify_cancel.load(Ordering::Acquire) {
+            let _ = self.try_send(UserRequestBody::Cancel { nonce });
+        }
+    }
+
+    fn take_abandoned(&self, nonce: u32) -> bool {
+        let mut abandoned = match self.abandoned.borrow_mut() {
+            Ok(abandoned) => abandoned,
+            Err(_) => return false,
+        };
+        match abandoned.iter().position(|n| *n == nonce) {
+            Some(idx) => {
+                abandoned.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send a message without waiting, failing with [MailboxError::Full] if
+    /// there is no room in the ring.
+    pub(crate) fn try_send(&'static self, msg: UserRequestBody) -> Result<(), MailboxError> {
+        let rings = self.rings.get();
+        let outgoing = UserRequest {
+            header: UserRequestHeader {
+                nonce: self.nonce.fetch_add(1, Ordering::AcqRel),
+            },
+            body: msg,
+        };
+        let mut wgr = rings
+            .u2k
+            .grant(Self::grant_size(rings))
+            .map_err(|_| MailboxError::Full)?;
+        let used = postcard::to_slice(&outgoing, &mut wgr)
+            .map_err(|_| MailboxError::Serialization)?
+            .len();
+        wgr.commit(used);
+        Ok(())
+    }
+
+    async fn send_inner(
+        &'static self,
+        nonce: u32,
+        msg: UserRequestBody,
+    ) -> Result<(), MailboxError> {
         let rings = self.rings.get();
         let outgoing = UserRequest {
             header: UserRequestHeader { nonce },
             body: msg,
         };
 
+        let grant_size = Self::grant_size(rings);
+
         // Wait for a successful send
         loop {
             if !self.inhibit_send.load(Ordering::Acquire) {
-                // TODO: Max Size
-                if let Ok(mut wgr) = rings.u2k.grant(128) {
-                    let used = postcard::to_slice(&outgoing, &mut wgr).map_err(drop)?.len();
+                if let Ok(mut wgr) = rings.u2k.grant(grant_size) {
+                    let used = match postcard::to_slice(&outgoing, &mut wgr) {
+                        Ok(used) => used.len(),
+                        // The grant was limited by the size of the ring, and
+                        // this message doesn't fit.
+                        Err(postcard::Error::SerializeBufferFull) => {
+                            return Err(MailboxError::Oversized {
+                                max_size: grant_size,
+                            })
+                        }
+                        Err(_) => return Err(MailboxError::Serialization),
+                    };
                     wgr.commit(used);
                     break;
                 } else {
@@ -123,29 +379,106 @@ impl MailBox {
                     self.inhibit_send.store(true, Ordering::Release);
                 }
             }
-            self.send_wait.wait().await.map_err(drop)?;
+            self.send_wait
+                .wait()
+                .await
+                .map_err(|_| MailboxError::Closed)?;
         }
 
         Ok(())
     }
 
+    /// The size of the grant used to serialize a request.
+    ///
+    /// This is the largest possible size of a serialized request, unless that
+    /// would never fit in the u2k ring.
+    fn grant_size(rings: &Rings) -> usize {
+        UserRequest::POSTCARD_MAX_SIZE.min(rings.u2k.max_frame_size())
+    }
+
     /// Send a message to the kernel without waiting for a response
-    pub async fn send(&'static self, msg: UserRequestBody) -> Result<(), ()> {
+    pub async fn send(&'static self, msg: UserRequestBody) -> Result<(), MailboxError> {
         let nonce = self.nonce.fetch_add(1, Ordering::AcqRel);
         self.send_inner(nonce, msg).await
     }
 
     /// Send a message to the kernel, waiting for a response
-    pub async fn request(&'static self, msg: UserRequestBody) -> Result<KernelResponseBody, ()> {
+    ///
+    /// This is cancellation safe. If the returned future is dropped after the
+    /// request was sent, a late response will be recognized and discarded, and
+    /// the kernel may optionally be told, see [MailBox::set_cancel_notifications].
+    /// If the response had already arrived, it is discarded right away. Either
+    /// way, any buffers it carries are freed.
+    pub async fn request(
+        &'static self,
+        msg: UserRequestBody,
+    ) -> Result<KernelResponseBody, MailboxError> {
         let nonce = self.nonce.fetch_add(1, Ordering::AcqRel);
 
         // Start listening for the response BEFORE we send the request
-        let rx: maitake::wait::map::Wait<u32, KernelResponseBody> = MAILBOX.recv_wait.wait(nonce);
+        let rx = self.recv_wait.wait(nonce);
         pin_mut!(rx);
-        rx.as_mut().enqueue().await.map_err(drop)?;
+        let mut guard = RequestGuard {
+            mailbox: self,
+            nonce,
+            rx,
+            sent: false,
+            complete: false,
+        };
+        guard
+            .rx
+            .as_mut()
+            .enqueue()
+            .await
+            .map_err(|_| MailboxError::Closed)?;
         self.send_inner(nonce, msg).await?;
+        guard.sent = true;
+
+        let resp = guard.rx.as_mut().await;
+        guard.complete = true;
+        resp.map_err(|_| MailboxError::Closed)?
+    }
+
+    /// Send a message to the kernel, waiting up to `timeout` for a response
+    pub async fn request_with_timeout(
+        &'static self,
+        msg: UserRequestBody,
+        timeout: Duration,
+    ) -> Result<KernelResponseBody, MailboxError> {
+        let req = self.request(msg);
+        let alarm = Alarm::after(timeout);
+        pin_mut!(req);
 
-        rx.await.map_err(drop)
+        match select(req, alarm).await {
+            Either::Left((resp, _)) => resp,
+            // Dropping the request future abandons the request
+            Either::Right(_) => Err(MailboxError::TimedOut),
+        }
+    }
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        if !self.sent || self.complete {
+            return;
+        }
+        // A delivered response is handed over on the next poll, without
+        // waiting. Otherwise, it is still on its way.
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match self.rx.as_mut().poll(&mut cx) {
+            Poll::Pending => self.mailbox.abandon(self.nonce),
+            Poll::Ready(Ok(resp)) => {
+                self.mailbox
+                    .abandoned_requests
+                    .fetch_add(1, Ordering::Relaxed);
+                self.mailbox.late_responses.fetch_add(1, Ordering::Relaxed);
+                if let Ok(body) = resp {
+                    wire::reclaim_response(body);
+                }
+            }
+            // The mailbox was closed, nothing is coming
+            Poll::Ready(Err(_)) => {}
+        }
     }
 }
 
@@ -2,8 +2,10 @@
 //!
 //! [mycelium]: https://github.com/hawkw/mycelium
 
+pub mod events;
 pub mod mailbox;
 pub mod time;
+pub(crate) mod wire;
 
 use maitake::task::Task as MaitakeTask;
 use maitake::{
@@ -12,10 +14,11 @@ use maitake::{
     task::Storage,
 };
 
+use abi::syscall::UserRequestBody;
 use core::{
     future::Future,
     ptr::NonNull,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
 };
 use mnemos_alloc::{containers::HeapBox, heap::AHeap};
 
@@ -31,12 +34,16 @@ impl<F: Future + 'static> Task<F> {
 pub struct Terpsichore {
     pub(crate) scheduler: StaticScheduler,
     heap_ptr: AtomicPtr<AHeap>,
+    /// Has the kernel been told that we are waiting, with nothing happening
+    /// since?
+    wait_sent: AtomicBool,
 }
 
 static TASK_STUB: TaskStub = TaskStub::new();
 pub static EXECUTOR: Terpsichore = Terpsichore {
     scheduler: unsafe { StaticScheduler::new_with_static_stub(&TASK_STUB) },
     heap_ptr: AtomicPtr::new(core::ptr::null_mut()),
+    wait_sent: AtomicBool::new(false),
 };
 
 struct HBStorage;
@@ -64,17 +71,52 @@ impl Terpsichore {
         EXECUTOR.heap_ptr.store(hptr.as_ptr(), Ordering::Release);
     }
 
-    pub fn run(&'static self) {
+    /// Run every task that is ready to run.
+    ///
+    /// If, afterwards, no tasks are ready, and there are no messages from the
+    /// kernel, userspace is idle. The kernel is then told that userspace is
+    /// waiting, until it sends a message or the next alarm is due, see
+    /// [UserRequestBody::Wait].
+    ///
+    /// Returns `true` if userspace is idle.
+    pub fn run(&'static self) -> bool {
+        let mailbox = &crate::executor::mailbox::MAILBOX;
+        let received = mailbox.received();
+
+        // Process messages. This also updates the current time, so do it
+        // before processing timers.
+        mailbox.poll();
+
         // Process timers
         crate::executor::time::CHRONOS.poll();
 
-        // Process messages
-        crate::executor::mailbox::MAILBOX.poll();
-
         // Process heap allocations
         self.get_alloc().poll();
 
-        self.scheduler.tick();
+        let tick = self.scheduler.tick();
+
+        if tick.has_remaining || mailbox.has_messages() {
+            self.wait_sent.store(false, Ordering::Release);
+            return false;
+        }
+
+        // If anything happened since we last waited, the kernel may think
+        // we're running, so wait again.
+        if tick.polled > 0 || mailbox.received() != received {
+            self.wait_sent.store(false, Ordering::Release);
+        }
+        if !self.wait_sent.load(Ordering::Acquire) {
+            let wait = UserRequestBody::Wait {
+                until: crate::executor::time::CHRONOS.next_due(),
+                received: mailbox.received(),
+            };
+            // If there's no room in the ring, try again next time
+            if mailbox.try_send(wait).is_err() {
+                return false;
+            }
+            self.wait_sent.store(true, Ordering::Release);
+        }
+        true
     }
 
     pub fn get_alloc(&'static self) -> &'static AHeap {
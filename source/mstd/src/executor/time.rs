@@ -1,10 +1,12 @@
-use heapless::Vec;
+use mnemos_alloc::containers::HeapArray;
 
+use super::EXECUTOR;
 use crate::utils::ArfCell;
 pub use core::time::Duration;
 use core::{
+    cmp::Ordering,
     future::Future,
-    ops::{Add, DerefMut, Sub},
+    ops::{Add, Sub},
     pin::Pin,
     task::{Context, Poll, Waker},
 };
@@ -12,21 +14,43 @@ use core::{
 // TODO: This is an `ArfCell` and not just a plain atomic in order to
 // support 32-bit targets. It might be worth specializing this to make it
 // more efficient on 64-bit targets
+//
+// This is updated by the mailbox, whenever the kernel sends a timestamp.
 pub static CURRENT_TIME: ArfCell<u64> = ArfCell::new(0);
 const TICKS_PER_SEC: u64 = 1_000_000;
 pub(crate) static CHRONOS: Chronos = Chronos {
-    inner: ArfCell::new(ChronosInner { shorts: Vec::new() }),
+    inner: ArfCell::new(ChronosInner {
+        entries: None,
+        next_due: u64::MAX,
+        next_id: 0,
+    }),
 };
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
+/// A future that completes once a point in time has been reached.
+///
+/// Dropping an alarm before it completes cancels it.
 pub struct Alarm {
     tick: u64,
+    key: Option<AlarmKey>,
+}
+
+/// Sleep for (at least) the given duration.
+pub async fn sleep(dur: Duration) {
+    Alarm::after(dur).await
 }
 
 impl Alarm {
+    /// An alarm that goes off after the given duration. Durations too long to
+    /// represent will never go off.
     pub fn after(dur: Duration) -> Self {
+        Self::at(Instant::now().add(dur))
+    }
+
+    /// An alarm that goes off at the given instant.
+    pub fn at(instant: Instant) -> Self {
         Self {
-            tick: Instant::now().add(dur).tick,
+            tick: instant.tick,
+            key: None,
         }
     }
 
@@ -35,7 +59,10 @@ impl Alarm {
     }
 
     pub const fn never() -> Self {
-        Self { tick: u64::MAX }
+        Self {
+            tick: u64::MAX,
+            key: None,
+        }
     }
 }
 
@@ -43,81 +70,170 @@ impl Future for Alarm {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.is_expired() {
+        let this = self.get_mut();
+        if this.is_expired() {
+            if let Some(key) = this.key.take() {
+                CHRONOS.cancel(key);
+            }
             Poll::Ready(())
         } else {
-            CHRONOS.register(&self, cx.waker());
+            CHRONOS.register(&mut this.key, this.tick, cx.waker());
             Poll::Pending
         }
     }
 }
 
-#[derive(Copy, Clone)]
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            CHRONOS.cancel(key);
+        }
+    }
+}
+
+/// Clones are not registered with the timer until they are polled
+impl Clone for Alarm {
+    fn clone(&self) -> Self {
+        Self {
+            tick: self.tick,
+            key: None,
+        }
+    }
+}
+
+impl PartialEq for Alarm {
+    fn eq(&self, other: &Self) -> bool {
+        self.tick == other.tick
+    }
+}
+
+impl Eq for Alarm {}
+
+impl PartialOrd for Alarm {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Alarm {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.tick.cmp(&other.tick)
+    }
+}
+
+/// A point in time, as reported by the kernel, in microseconds since boot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Instant {
     tick: u64,
 }
 
 impl Instant {
     pub fn now() -> Self {
-        Self {
-            tick: *CURRENT_TIME.borrow().unwrap(),
+        // The time is only ever locked briefly by the mailbox, so just wait
+        // for it to be released
+        loop {
+            if let Ok(tick) = CURRENT_TIME.borrow() {
+                return Self { tick: *tick };
+            }
+            core::hint::spin_loop();
         }
     }
 
     pub fn elapsed(&self) -> Duration {
-        let now = Instant::now();
-        now - *self
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    /// Returns `None` if `earlier` is later than `self`.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.tick
+            .checked_sub(earlier.tick)
+            .map(Duration::from_micros)
+    }
+
+    /// Returns a zero duration if `earlier` is later than `self`.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Returns `None` if the result would overflow.
+    pub fn checked_add(&self, dur: Duration) -> Option<Instant> {
+        let tick = self.tick.checked_add(duration_to_ticks(dur)?)?;
+        Some(Self { tick })
+    }
+
+    /// Returns `None` if the result would be before the kernel booted.
+    pub fn checked_sub(&self, dur: Duration) -> Option<Instant> {
+        let tick = self.tick.checked_sub(duration_to_ticks(dur)?)?;
+        Some(Self { tick })
     }
 }
 
 impl Sub for Instant {
     type Output = Duration;
 
+    /// Saturates at zero, see [Instant::saturating_duration_since]
     fn sub(self, rhs: Self) -> Self::Output {
-        let delta = self.tick.checked_sub(rhs.tick).unwrap();
-        Duration::from_micros(delta)
+        self.saturating_duration_since(rhs)
     }
 }
 
 impl Add<Duration> for Instant {
     type Output = Instant;
 
-    fn add(mut self, other: Duration) -> Self::Output {
-        // Do this to avoid u128s
-        let dur = other.as_secs() * TICKS_PER_SEC;
-        let dur = dur + other.subsec_micros() as u64;
-        self.tick += dur;
-        self
+    /// Saturates at the largest representable instant
+    fn add(self, other: Duration) -> Self::Output {
+        self.checked_add(other)
+            .unwrap_or(Instant { tick: u64::MAX })
     }
 }
 
-impl PartialEq for Alarmed {
-    fn eq(&self, other: &Self) -> bool {
-        self.alarm.eq(&other.alarm)
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at the instant the kernel booted
+    fn sub(self, other: Duration) -> Self::Output {
+        self.checked_sub(other).unwrap_or(Instant { tick: 0 })
     }
 }
 
-impl Eq for Alarmed {}
-
-impl PartialOrd for Alarmed {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        self.alarm.partial_cmp(&other.alarm)
+/// Advance the current time to the given kernel timestamp.
+///
+/// Timestamps that would move time backwards are ignored.
+pub(crate) fn update_time(timestamp: u64) {
+    if let Ok(mut now) = CURRENT_TIME.borrow_mut() {
+        *now = (*now).max(timestamp);
     }
 }
 
-impl Ord for Alarmed {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.alarm.cmp(&other.alarm)
-    }
+fn duration_to_ticks(dur: Duration) -> Option<u64> {
+    // Do this to avoid u128s
+    dur.as_secs()
+        .checked_mul(TICKS_PER_SEC)?
+        .checked_add(dur.subsec_micros().into())
 }
 
-struct Alarmed {
+struct Entry {
+    id: u32,
+    tick: u64,
     waker: Waker,
-    alarm: Alarm,
+}
+
+/// The location of an alarm's entry in [Chronos].
+///
+/// Entries are removed when they are woken, and their slot may then be reused
+/// by another alarm, so the `id` is checked before using the slot.
+#[derive(Copy, Clone)]
+struct AlarmKey {
+    slot: usize,
+    id: u32,
 }
 
 struct ChronosInner {
-    shorts: Vec<Alarmed, 32>,
+    /// Registered alarms, allocated on the userspace heap and grown on demand
+    entries: Option<HeapArray<Option<Entry>>>,
+    /// The earliest tick of any registered alarm
+    next_due: u64,
+    next_id: u32,
 }
 
 pub(crate) struct Chronos {
@@ -125,61 +241,142 @@ pub(crate) struct Chronos {
 }
 
 impl Chronos {
+    /// The number of entries allocated the first time an alarm is registered
+    const INITIAL_CAPACITY: usize = 8;
+
     pub(crate) fn poll(&self) {
-        let curr_tick = *CURRENT_TIME.borrow().unwrap();
+        let curr_tick = match CURRENT_TIME.borrow() {
+            Ok(t) => *t,
+            Err(_) => return,
+        };
 
-        let mut inner = self.inner.borrow_mut().unwrap();
-        if let Some(alm) = inner.shorts.get(0) {
-            if alm.alarm.tick > curr_tick {
-                // Nothing to wake, we're done
-                return;
-            }
-        } else {
-            // No items, we're done
+        let mut inner = match self.inner.borrow_mut() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+        if inner.next_due > curr_tick {
+            // Nothing to wake, we're done
             return;
         }
 
-        let mut new = Vec::new();
-        core::mem::swap(&mut new, &mut inner.shorts);
-        for almd in new.into_iter() {
-            if almd.alarm.tick <= curr_tick {
-                almd.waker.wake();
-            } else {
-                inner.shorts.push(almd).ok();
+        let mut next_due = u64::MAX;
+        for slot in inner.entries.iter_mut().flat_map(|e| e.iter_mut()) {
+            match slot {
+                Some(entry) if entry.tick <= curr_tick => {
+                    if let Some(entry) = slot.take() {
+                        entry.waker.wake();
+                    }
+                }
+                Some(entry) => next_due = next_due.min(entry.tick),
+                None => {}
             }
         }
+        inner.next_due = next_due;
     }
 
-    fn register(&self, alarm: &Alarm, waker: &Waker) {
-        let mut inner = self.inner.borrow_mut().unwrap();
-        let almd = Alarmed {
-            alarm: alarm.clone(),
-            waker: waker.clone(),
-        };
+    /// The earliest tick of any registered alarm.
+    ///
+    /// This may be earlier than the real next deadline, e.g. if an alarm was
+    /// cancelled, but never later.
+    pub(crate) fn next_due(&self) -> Option<u64> {
+        match self.inner.borrow() {
+            Ok(inner) if inner.next_due != u64::MAX => Some(inner.next_due),
+            Ok(_) => None,
+            // Don't sleep past an alarm we couldn't check
+            Err(_) => Some(0),
+        }
+    }
 
-        let _almd = match inner.shorts.push(almd) {
-            Ok(()) => {
-                inner.shorts.deref_mut().sort_unstable();
+    /// Register (or update) the waker for an alarm.
+    ///
+    /// Each alarm has at most one entry, so polling an alarm repeatedly only
+    /// replaces its waker. If the entry can't be stored right now, because the
+    /// heap is busy or full, the task is woken so that it tries again on the
+    /// next poll.
+    fn register(&self, key: &mut Option<AlarmKey>, tick: u64, waker: &Waker) {
+        let mut inner = match self.inner.borrow_mut() {
+            Ok(inner) => inner,
+            Err(_) => {
+                waker.wake_by_ref();
                 return;
             }
-            Err(almd) => almd,
         };
+        inner.next_due = inner.next_due.min(tick);
+
+        // Is this alarm already registered?
+        if let Some(entry) = key.and_then(|k| inner.get_mut(k)) {
+            entry.tick = tick;
+            if !entry.waker.will_wake(waker) {
+                entry.waker = waker.clone();
+            }
+            return;
+        }
 
-        panic!("Timer overflow!");
-
-        // The list is full. See if the new item is sooner than any on the list
-        // let worked = if let Some(last) = inner.shorts.last_mut() {
-        //     if last < &mut almd {
-        //         core::mem::swap(last, &mut almd);
-        //         true
-        //     } else {
-        //         false
-        //     }
-        // } else {
-        //     false
-        // };
-
-        // We now need to put whatever is in `almd` into the purgatory queue
-        // self.purgatory.enqueue(todo!("HOW DO I A GET A `HEADER`???"));
+        let slot = match inner.free_slot() {
+            Some(slot) => slot,
+            None => match inner.grow() {
+                Ok(slot) => slot,
+                Err(()) => {
+                    *key = None;
+                    waker.wake_by_ref();
+                    return;
+                }
+            },
+        };
+
+        let id = inner.next_id;
+        inner.next_id = inner.next_id.wrapping_add(1);
+        if let Some(entries) = inner.entries.as_mut() {
+            entries[slot] = Some(Entry {
+                id,
+                tick,
+                waker: waker.clone(),
+            });
+        }
+        *key = Some(AlarmKey { slot, id });
+    }
+
+    /// Remove an alarm's entry, if it hasn't already been woken.
+    fn cancel(&self, key: AlarmKey) {
+        if let Ok(mut inner) = self.inner.borrow_mut() {
+            if let Some(entries) = inner.entries.as_mut() {
+                if matches!(&entries[key.slot], Some(e) if e.id == key.id) {
+                    entries[key.slot] = None;
+                }
+            }
+        }
+    }
+}
+
+impl ChronosInner {
+    fn get_mut(&mut self, key: AlarmKey) -> Option<&mut Entry> {
+        self.entries
+            .as_mut()?
+            .get_mut(key.slot)?
+            .as_mut()
+            .filter(|e| e.id == key.id)
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.entries.as_ref()?.iter().position(Option::is_none)
+    }
+
+    /// Double the capacity of the entry storage, returning the index of a free slot.
+    fn grow(&mut self) -> Result<usize, ()> {
+        let old_len = self.entries.as_ref().map(|e| e.len()).unwrap_or(0);
+        let new_len = (old_len * 2).max(Chronos::INITIAL_CAPACITY);
+
+        let mut new = {
+            let mut guard = EXECUTOR.get_alloc().lock()?;
+            guard.alloc_box_array_with(|| None, new_len)?
+        };
+
+        if let Some(mut old) = self.entries.take() {
+            for (new, old) in new.iter_mut().zip(old.iter_mut()) {
+                *new = old.take();
+            }
+        }
+        self.entries = Some(new);
+        Ok(old_len)
     }
 }
@@ -0,0 +1,116 @@
+//! Buffers lent to the kernel
+//!
+//! Requests that carry data pass ownership of a buffer to the kernel as a
+//! [ByteBoxWire]. The kernel always gives the buffer back, either in its
+//! response, or with a [KernelMsg::Dealloc](abi::syscall::KernelMsg::Dealloc).
+//!
+//! For now, every buffer has the same size, so that a [ByteBoxWire] can be
+//! turned back into the allocation it came from.
+
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use abi::syscall::{block::BlockResponse, serial::SerialResponse, ByteBoxWire, KernelResponseBody};
+use mnemos_alloc::containers::HeapBox;
+
+use crate::executor::EXECUTOR;
+
+/// The size of every buffer lent to the kernel
+pub const WIRE_BUF_SIZE: usize = 256;
+
+/// The number of buffers lent to the kernel, and not yet taken back
+static LENT: AtomicU32 = AtomicU32::new(0);
+
+/// A buffer that can be lent to the kernel
+pub(crate) struct WireBuf {
+    buf: HeapBox<[u8; WIRE_BUF_SIZE]>,
+}
+
+impl WireBuf {
+    pub(crate) async fn new() -> Self {
+        Self {
+            buf: EXECUTOR.get_alloc().allocate([0u8; WIRE_BUF_SIZE]).await,
+        }
+    }
+
+    /// Allocate a buffer without waiting, failing if the heap is busy or full.
+    pub(crate) fn try_new() -> Option<Self> {
+        let mut guard = EXECUTOR.get_alloc().lock().ok()?;
+        let buf = guard.alloc_box([0u8; WIRE_BUF_SIZE]).ok()?;
+        Some(Self { buf })
+    }
+
+    /// Lend the buffer to the kernel.
+    pub(crate) fn into_wire(self) -> ByteBoxWire {
+        LENT.fetch_add(1, Ordering::Relaxed);
+        ByteBoxWire {
+            ptr: self.buf.leak().as_ptr() as usize,
+            len: WIRE_BUF_SIZE,
+        }
+    }
+
+    /// Take back a buffer that was lent to the kernel.
+    ///
+    /// Returns `None` if the buffer could not have come from [WireBuf::into_wire].
+    ///
+    /// # Safety
+    ///
+    /// The buffer must have been created with [WireBuf::into_wire], and must
+    /// only be taken back once.
+    pub(crate) unsafe fn from_wire(wire: ByteBoxWire) -> Option<Self> {
+        if wire.len != WIRE_BUF_SIZE {
+            return None;
+        }
+        let ptr = NonNull::new(wire.ptr as *mut [u8; WIRE_BUF_SIZE])?;
+        LENT.fetch_sub(1, Ordering::Relaxed);
+        Some(Self {
+            buf: HeapBox::from_leaked(ptr),
+        })
+    }
+}
+
+impl Deref for WireBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..]
+    }
+}
+
+impl DerefMut for WireBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+}
+
+/// The number of buffers lent to the kernel, and not yet taken back.
+pub(crate) fn lent() -> u32 {
+    LENT.load(Ordering::Relaxed)
+}
+
+/// Free a buffer given back by the kernel, that nobody is waiting for.
+pub(crate) fn reclaim(wire: ByteBoxWire) {
+    // SAFETY: The kernel only gives back buffers that we lent it, once.
+    // Dropping the buffer frees it.
+    let _buf = unsafe { WireBuf::from_wire(wire) };
+}
+
+/// Free any buffers carried by a response that nobody is waiting for.
+pub(crate) fn reclaim_response(body: KernelResponseBody) {
+    match body {
+        KernelResponseBody::Serial(Ok(SerialResponse::ReceiveData { buffer, .. }))
+        | KernelResponseBody::Serial(Ok(SerialResponse::SendComplete { buffer, .. }))
+        | KernelResponseBody::Block(Ok(BlockResponse::BlockInfo {
+            name_buf: buffer, ..
+        }))
+        | KernelResponseBody::Block(Ok(BlockResponse::Read { buffer, .. }))
+        | KernelResponseBody::Block(Ok(BlockResponse::Written { buffer, .. }))
+        | KernelResponseBody::Block(Ok(BlockResponse::Closed { name: buffer, .. })) => {
+            reclaim(buffer)
+        }
+        _ => {}
+    }
+}
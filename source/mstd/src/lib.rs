@@ -4,41 +4,62 @@
 /// Common between the Kernel and Userspace
 pub use abi;
 
+pub mod alloc;
+pub mod block;
 pub mod executor;
+pub mod runtime;
 pub mod serial;
+pub mod stdio;
 pub mod utils;
 
-// The user must provide a `no_mangle` entrypoint.
+pub use mstd_macros::main;
+
+// The user must provide a `no_mangle` entrypoint. This is usually generated
+// by `#[mstd::main]`. Hosted platforms, like the simulator, start the runtime
+// themselves, and don't need one.
+#[cfg(target_os = "none")]
 extern "Rust" {
     fn entry() -> !;
 }
 
+#[cfg(target_os = "none")]
 #[link_section = ".anachro_table.entry_point"]
 #[no_mangle]
 #[used]
 #[doc(hidden)]
 pub static __ENTRY_POINT: unsafe fn() -> ! = entry;
 
-// Provide a basic panic handler. In the future, this will probably
-// change to one or both of:
-//
-// * Being behind a feature, so you can provide your own panic handler
-// * Attempt to print the panic to the stdout (e.g. serial port 0),
-//     then triggering a "halt" or "reboot" system call.
+/// The code passed to [halt] after a panic
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+/// Tell the kernel that userspace has stopped, then wait forever.
+///
+/// A non-zero `code` means that userspace stopped because of an error.
+pub fn halt(code: i32) -> ! {
+    use abi::syscall::UserRequestBody;
+
+    // If there is no room in the ring, there is nothing else we can do
+    let _ = executor::mailbox::MAILBOX.try_send(UserRequestBody::Halt { code });
+    stop()
+}
+
+/// Wait forever, once the kernel has been told that userspace has stopped.
+pub(crate) fn stop() -> ! {
+    loop {
+        abi::WAIT_FOR_EVENT.wait();
+    }
+}
+
+// Provide a basic panic handler, which prints the panic to stdout, then
+// halts. In the future, this will probably be behind a feature, so you can
+// provide your own panic handler.
 #[cfg(all(feature = "panic-handler", target_os = "none"))]
 mod panic_handler {
     use core::panic::PanicInfo;
-    use core::sync::atomic::{compiler_fence, Ordering};
 
     #[panic_handler]
-    fn panic(_info: &PanicInfo) -> ! {
-        // let mut sop = StdOut;
-        // if let Some(location) = info.location() {
-        //     writeln!(&mut sop, "Panicked at {}", location).ok();
-        // }
-        // abi::porcelain::system::panic()
-        loop {
-            compiler_fence(Ordering::SeqCst);
-        }
+    fn panic(info: &PanicInfo) -> ! {
+        crate::runtime::report_panic(info);
+        crate::stop()
     }
 }
@@ -1,10 +1,6 @@
 #![no_std]
 #![no_main]
 
-use mstd as _; // Panic handler
-
 // I'm just here so the program can link.
-#[no_mangle]
-fn entry() -> ! {
-    panic!();
-}
+#[mstd::main]
+async fn main() {}
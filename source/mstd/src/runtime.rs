@@ -0,0 +1,191 @@
+//! The userspace runtime
+//!
+//! Applications normally don't use this module directly. Instead, they mark
+//! their async `main` function with [`#[mstd::main]`](crate::main), which
+//! generates an entry point that calls [start]:
+//!
+//! ```rust,ignore
+//! #[mstd::main]
+//! async fn main() {
+//!     mstd::println!("Hello, world!");
+//! }
+//! ```
+//!
+//! Booting userspace takes a few steps:
+//!
+//! 1. [init] finds the syscall rings and the userspace heap, which the kernel
+//!    publishes in [abi::SYSCALL_RINGS], [abi::HEAP_PTR] and [abi::HEAP_LEN]
+//! 2. [spawn_main] spawns the `main` task onto the executor
+//! 3. The executor is run until `main` completes, then the kernel is told
+//!    that userspace has halted
+//!
+//! Whenever userspace is idle, the kernel is told that it is waiting, and
+//! [start] sleeps in the platform's [abi::WAIT_FOR_EVENT] hook until the
+//! kernel sends it a message. Without a hook, it spins instead.
+//!
+//! [start] does all of this. Platforms that need to interleave userspace
+//! with something else, like the simulator, can instead call [init] and
+//! [spawn_main] themselves, then call [run_once] until it returns an exit
+//! code. The kernel tells those platforms when userspace is ready to run
+//! again.
+
+use core::{
+    fmt::{Debug, Display},
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+
+use abi::{bbqueue_ipc::BBBuffer, syscall::UserRequestBody};
+
+use crate::{
+    executor::{
+        mailbox::{Rings, MAILBOX},
+        Task, EXECUTOR,
+    },
+    stdio,
+};
+
+/// Set when the `main` task has completed
+static MAIN_DONE: AtomicBool = AtomicBool::new(false);
+static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
+/// The return type of an application's `main` function.
+///
+/// This decides the exit code sent to the kernel once `main` completes, see
+/// [halt](crate::halt).
+pub trait Termination {
+    fn report(self) -> i32;
+}
+
+impl Termination for () {
+    fn report(self) -> i32 {
+        0
+    }
+}
+
+impl<E: Debug> Termination for Result<(), E> {
+    fn report(self) -> i32 {
+        match self {
+            Ok(()) => 0,
+            Err(e) => {
+                crate::eprintln!("Error: {:?}", e);
+                1
+            }
+        }
+    }
+}
+
+/// Boot userspace, run `main` to completion, then halt.
+///
+/// This is called by the entry point generated by [`#[mstd::main]`](crate::main).
+///
+/// # Safety
+///
+/// This must only be called once, after the kernel has published the
+/// locations of the syscall rings and the userspace heap.
+pub unsafe fn start<F>(main: F) -> !
+where
+    F: Future + 'static,
+    F::Output: Termination,
+{
+    init();
+    spawn_main(main);
+    loop {
+        let idle = EXECUTOR.run();
+        if exit_code().is_some() {
+            crate::stop();
+        }
+        if idle {
+            while !MAILBOX.has_messages() {
+                abi::WAIT_FOR_EVENT.wait();
+            }
+        }
+    }
+}
+
+/// Set up the mailbox and the executor, using the locations published by the
+/// kernel.
+///
+/// Panics if the kernel has not published them yet.
+///
+/// # Safety
+///
+/// This must only be called once.
+pub unsafe fn init() {
+    let u2k = abi::SYSCALL_RINGS.user_to_kernel.load(Ordering::Acquire);
+    let k2u = abi::SYSCALL_RINGS.kernel_to_user.load(Ordering::Acquire);
+    let heap_ptr = abi::HEAP_PTR.load(Ordering::Acquire);
+    let heap_len = abi::HEAP_LEN.load(Ordering::Acquire);
+    assert!(
+        !u2k.is_null() && !k2u.is_null() && !heap_ptr.is_null(),
+        "the kernel has not published the userspace ABI"
+    );
+
+    MAILBOX.set_rings(Rings {
+        u2k: BBBuffer::take_framed_producer(u2k),
+        k2u: BBBuffer::take_framed_consumer(k2u),
+    });
+    EXECUTOR.initialize(heap_ptr, heap_len);
+}
+
+/// Spawn the `main` task.
+///
+/// Once `main` completes, and all of its output has been sent, the kernel is
+/// told that userspace has halted, and [run_once] returns the exit code.
+///
+/// Panics if the heap is busy or full. This is only expected to be called
+/// right after [init].
+pub fn spawn_main<F>(main: F)
+where
+    F: Future + 'static,
+    F::Output: Termination,
+{
+    let task = Task::new(async move {
+        let code = main.await.report();
+        stdio::flush().await;
+        // The only error is the mailbox closing, and then nobody is listening
+        let _ = MAILBOX.send(UserRequestBody::Halt { code }).await;
+        EXIT_CODE.store(code, Ordering::Release);
+        MAIN_DONE.store(true, Ordering::Release);
+    });
+    let task = EXECUTOR
+        .get_alloc()
+        .lock()
+        .ok()
+        .and_then(|mut guard| guard.alloc_box(task).ok())
+        .expect("failed to allocate the main task");
+    EXECUTOR.spawn_allocated(task);
+}
+
+/// Run the executor once, returning the exit code of `main` once it has
+/// completed.
+///
+/// If userspace is left idle, the kernel is told that it is waiting, see
+/// [Terpsichore::run](crate::executor::Terpsichore::run).
+pub fn run_once() -> Option<i32> {
+    EXECUTOR.run();
+    exit_code()
+}
+
+/// Print the message of a panic straight to stdout, then tell the kernel that
+/// userspace has halted with [PANIC_EXIT_CODE](crate::PANIC_EXIT_CODE).
+///
+/// This is what the panic handler does before it stops. Platforms that catch
+/// userspace panics themselves, like the simulator, call this instead, and
+/// must not run userspace again.
+pub fn report_panic(message: &dyn Display) {
+    // No other task will run again, so write straight to the kernel
+    stdio::write_now(format_args!("{}\r\n", message));
+    // If there is no room in the ring, there is nothing else we can do
+    let _ = MAILBOX.try_send(UserRequestBody::Halt {
+        code: crate::PANIC_EXIT_CODE,
+    });
+}
+
+fn exit_code() -> Option<i32> {
+    if MAIN_DONE.load(Ordering::Acquire) {
+        Some(EXIT_CODE.load(Ordering::Acquire))
+    } else {
+        None
+    }
+}
@@ -1,24 +1,150 @@
-use crate::executor::mailbox::MAILBOX;
+use crate::executor::{
+    mailbox::MAILBOX,
+    wire::{self, WireBuf, WIRE_BUF_SIZE},
+};
 use abi::syscall::{
     serial::{SerialError, SerialRequest, SerialResponse},
     KernelResponseBody, UserRequestBody,
 };
 
-#[allow(dead_code)]
+/// A virtual serial port, provided by the kernel's serial mux.
+///
+/// Dropping a `SerialPort` without calling [SerialPort::close] leaves the port
+/// open, and it can not be opened again.
 pub struct SerialPort {
     port: u16,
+    /// Data received from the kernel, but not yet read
+    rx: Option<RxBuf>,
+}
+
+struct RxBuf {
+    data: WireBuf,
+    pos: usize,
+    len: usize,
 }
 
 impl SerialPort {
     pub async fn open(req_port: u16) -> Result<Self, SerialError> {
-        let msg = UserRequestBody::Serial(SerialRequest::OpenPort { port: req_port });
-        if let Ok(KernelResponseBody::Serial(sr)) = MAILBOX.request(msg).await {
-            if let SerialResponse::OpenPort { port } = sr? {
-                if port == req_port {
-                    return Ok(SerialPort { port });
-                }
+        match request(SerialRequest::OpenPort { port: req_port }).await? {
+            SerialResponse::OpenPort { port } if port == req_port => {
+                Ok(SerialPort { port, rx: None })
             }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Write all of `data` to the port, returning the number of bytes written.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, SerialError> {
+        // Reuse the same buffer for every chunk
+        let mut spare = None;
+
+        for chunk in data.chunks(WIRE_BUF_SIZE) {
+            let mut buf = match spare.take() {
+                Some(buf) => buf,
+                None => WireBuf::new().await,
+            };
+            buf[..chunk.len()].copy_from_slice(chunk);
+            spare = Some(self.write_buf(buf, chunk.len()).await?);
+        }
+
+        Ok(data.len())
+    }
+
+    /// Write the first `used` bytes of an already filled buffer, returning the
+    /// buffer once the kernel is done with it.
+    pub(crate) async fn write_buf(
+        &mut self,
+        buf: WireBuf,
+        used: usize,
+    ) -> Result<WireBuf, SerialError> {
+        let req = SerialRequest::SendData {
+            port: self.port,
+            buffer: buf.into_wire(),
+            used,
+        };
+        match request(req).await? {
+            SerialResponse::SendComplete { port, buffer } if port == self.port => {
+                // SAFETY: The kernel gives back the buffer we lent it
+                unsafe { WireBuf::from_wire(buffer) }.ok_or(SerialError::Unknown)
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Read data from the port into `buf`, waiting until some is available.
+    ///
+    /// Returns the number of bytes read. Data that doesn't fit in `buf` is
+    /// kept for the next read.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, SerialError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.rx.is_none() {
+            let req = SerialRequest::ProvideReceiveBuffer {
+                port: self.port,
+                buffer: WireBuf::new().await.into_wire(),
+            };
+            let (buffer, used) = match request(req).await? {
+                SerialResponse::ReceiveData { port, buffer, used } if port == self.port => {
+                    (buffer, used)
+                }
+                other => return Err(unexpected(other)),
+            };
+            // SAFETY: The kernel gives back the buffer we lent it
+            let data = unsafe { WireBuf::from_wire(buffer) }.ok_or(SerialError::Unknown)?;
+            self.rx = Some(RxBuf {
+                data,
+                pos: 0,
+                len: used.min(WIRE_BUF_SIZE),
+            });
+        }
+
+        let rx = match self.rx.as_mut() {
+            Some(rx) => rx,
+            None => return Ok(0),
+        };
+        let n = (rx.len - rx.pos).min(buf.len());
+        buf[..n].copy_from_slice(&rx.data[rx.pos..][..n]);
+        rx.pos += n;
+        if rx.pos == rx.len {
+            self.rx = None;
+        }
+        Ok(n)
+    }
+
+    /// Wait until all written data has been handed to the serial mux.
+    pub async fn flush(&mut self) -> Result<(), SerialError> {
+        match request(SerialRequest::Flush { port: self.port }).await? {
+            SerialResponse::FlushAck { port } if port == self.port => Ok(()),
+            other => Err(unexpected(other)),
         }
-        Err(SerialError::Unknown)
     }
+
+    /// Close the port, so that it can be opened again.
+    ///
+    /// Any data that was received, but not yet read, is discarded.
+    pub async fn close(self) -> Result<(), SerialError> {
+        match request(SerialRequest::ClosePort { port: self.port }).await? {
+            SerialResponse::ClosePort { port } if port == self.port => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+async fn request(req: SerialRequest) -> Result<SerialResponse, SerialError> {
+    match MAILBOX.request(UserRequestBody::Serial(req)).await {
+        Ok(KernelResponseBody::Serial(resp)) => resp,
+        _ => Err(SerialError::Unknown),
+    }
+}
+
+/// Handle a response that doesn't match the request, freeing any buffer it carries.
+fn unexpected(resp: SerialResponse) -> SerialError {
+    wire::reclaim_response(KernelResponseBody::Serial(Ok(resp)));
+    SerialError::Unknown
 }
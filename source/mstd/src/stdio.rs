@@ -0,0 +1,334 @@
+//! Standard output
+//!
+//! [Stdout] and [Stderr] implement [fmt::Write], and are used by the
+//! [print!](crate::print), [println!](crate::println),
+//! [eprint!](crate::eprint) and [eprintln!](crate::eprintln) macros. Both are
+//! written to serial mux port [STDIO_PORT].
+//!
+//! Writing never waits. Output is copied into buffers allocated from the
+//! userspace heap, which are sent to the kernel by a background task. That
+//! task is spawned the first time anything is printed. If the heap is busy or
+//! full, or too much output is already waiting, the output is dropped, and
+//! the write returns an error.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use abi::syscall::{serial::SerialRequest, UserRequestBody};
+use heapless::Deque;
+use maitake::wait::WaitQueue;
+
+use crate::{
+    executor::{
+        mailbox::MAILBOX,
+        wire::{WireBuf, WIRE_BUF_SIZE},
+        Task, EXECUTOR,
+    },
+    serial::SerialPort,
+    utils::ArfCell,
+};
+
+/// The serial mux port used for stdout and stderr
+pub const STDIO_PORT: u16 = 0;
+
+/// The number of filled buffers that can be waiting to be sent
+const MAX_PENDING: usize = 8;
+
+static OUTPUT: Output = Output::new();
+
+/// A writer for standard output.
+///
+/// Output is sent when the writer is dropped, or a buffer fills up.
+pub struct Stdout {
+    writer: Writer,
+}
+
+/// A writer for standard error.
+///
+/// Output is sent when the writer is dropped, or a buffer fills up.
+pub struct Stderr {
+    writer: Writer,
+}
+
+/// Output that is waiting to be sent by the stdio task
+struct Output {
+    pending: ArfCell<Deque<(WireBuf, usize), MAX_PENDING>>,
+    ready: WaitQueue,
+    /// Is there output that has not been sent yet?
+    busy: AtomicBool,
+    /// Woken when all output has been sent
+    drained: WaitQueue,
+    task_spawned: AtomicBool,
+    /// Has [STDIO_PORT] been opened, or asked to be?
+    port_open: AtomicBool,
+    dropped_bytes: AtomicU32,
+}
+
+// SAFETY: Userspace is single threaded, and the pending buffers are only
+// accessed through the `ArfCell`.
+unsafe impl Sync for Output {}
+
+/// Fills buffers, handing each one to `sink` once it is full, or the writer
+/// is dropped.
+struct Writer {
+    current: Option<(WireBuf, usize)>,
+    sink: fn(WireBuf, usize) -> fmt::Result,
+}
+
+pub fn stdout() -> Stdout {
+    Stdout {
+        writer: Writer::new(queue),
+    }
+}
+
+pub fn stderr() -> Stderr {
+    Stderr {
+        writer: Writer::new(queue),
+    }
+}
+
+/// The number of bytes of output that were dropped, because they could not
+/// be buffered.
+pub fn dropped_bytes() -> u32 {
+    OUTPUT.dropped_bytes.load(Ordering::Relaxed)
+}
+
+/// Wait until everything printed so far has been sent to the kernel.
+pub async fn flush() {
+    OUTPUT.flush().await
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments<'_>) {
+    let _ = stdout().write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments<'_>) {
+    let _ = stderr().write_fmt(args);
+}
+
+/// Write directly to the kernel, bypassing the stdio task.
+///
+/// This is only meant for when no other tasks will run again, e.g. in the
+/// panic handler. Output is dropped if there is no room in the u2k ring.
+pub fn write_now(args: fmt::Arguments<'_>) {
+    let _ = Writer::new(send_now).write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::stdio::_print(core::format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\r\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::stdio::_print(core::format_args!("{}\r\n", core::format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::stdio::_eprint(core::format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\r\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::stdio::_eprint(core::format_args!("{}\r\n", core::format_args!($($arg)*)))
+    };
+}
+
+// impl Stdout/Stderr
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.writer.write_str(s)
+    }
+}
+
+impl Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.writer.write_str(s)
+    }
+}
+
+// impl Writer
+
+impl Writer {
+    const fn new(sink: fn(WireBuf, usize) -> fmt::Result) -> Self {
+        Self {
+            current: None,
+            sink,
+        }
+    }
+
+    fn submit(&mut self) -> fmt::Result {
+        match self.current.take() {
+            Some((buf, used)) if used > 0 => (self.sink)(buf, used),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut data = s.as_bytes();
+        while !data.is_empty() {
+            let (buf, used) = match self.current.as_mut() {
+                Some(current) => current,
+                None => match WireBuf::try_new() {
+                    Some(buf) => self.current.insert((buf, 0)),
+                    None => {
+                        OUTPUT.drop_bytes(data.len());
+                        return Err(fmt::Error);
+                    }
+                },
+            };
+
+            let len = data.len().min(WIRE_BUF_SIZE - *used);
+            buf[*used..][..len].copy_from_slice(&data[..len]);
+            *used += len;
+            data = &data[len..];
+
+            if *used == WIRE_BUF_SIZE {
+                self.submit()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let _ = self.submit();
+    }
+}
+
+/// Queue a buffer for the stdio task
+fn queue(buf: WireBuf, used: usize) -> fmt::Result {
+    OUTPUT.push(buf, used)
+}
+
+/// Send a buffer straight to the kernel, see [write_now]
+fn send_now(buf: WireBuf, used: usize) -> fmt::Result {
+    // The stdio task opens the port once it runs, which may not have happened
+    // yet. The kernel handles requests in order, so the port will be open by
+    // the time the data arrives. If it already was, opening it again fails
+    // harmlessly.
+    if !OUTPUT.port_open.swap(true, Ordering::AcqRel) {
+        let req = SerialRequest::OpenPort { port: STDIO_PORT };
+        let _ = MAILBOX.try_send(UserRequestBody::Serial(req));
+    }
+
+    let req = SerialRequest::SendData {
+        port: STDIO_PORT,
+        buffer: buf.into_wire(),
+        used,
+    };
+    // If this fails, the buffer is leaked. We're going down anyway.
+    MAILBOX.try_send(UserRequestBody::Serial(req)).map_err(|_| {
+        OUTPUT.drop_bytes(used);
+        fmt::Error
+    })
+}
+
+// impl Output
+
+impl Output {
+    const fn new() -> Self {
+        Self {
+            pending: ArfCell::new(Deque::new()),
+            ready: WaitQueue::new(),
+            busy: AtomicBool::new(false),
+            drained: WaitQueue::new(),
+            task_spawned: AtomicBool::new(false),
+            port_open: AtomicBool::new(false),
+            dropped_bytes: AtomicU32::new(0),
+        }
+    }
+
+    fn push(&'static self, buf: WireBuf, used: usize) -> fmt::Result {
+        if !self.task_spawned.load(Ordering::Acquire) && self.spawn_task().is_err() {
+            self.drop_bytes(used);
+            return Err(fmt::Error);
+        }
+
+        let pushed = match self.pending.borrow_mut() {
+            Ok(mut pending) => pending.push_back((buf, used)).is_ok(),
+            Err(_) => false,
+        };
+        if !pushed {
+            self.drop_bytes(used);
+            return Err(fmt::Error);
+        }
+        self.busy.store(true, Ordering::Release);
+        self.ready.wake();
+        Ok(())
+    }
+
+    async fn flush(&self) {
+        while self.busy.load(Ordering::Acquire) {
+            let _ = self.drained.wait().await;
+        }
+    }
+
+    /// Spawn the task that sends output to the kernel, without waiting.
+    fn spawn_task(&'static self) -> Result<(), ()> {
+        let task = Task::new(self.run());
+        let task = EXECUTOR.get_alloc().lock()?.alloc_box(task).map_err(drop)?;
+        EXECUTOR.spawn_allocated(task);
+        self.task_spawned.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn run(&'static self) {
+        // If the port can't be opened, keep draining the queue anyway
+        let mut port = SerialPort::open(STDIO_PORT).await.ok();
+        if port.is_some() {
+            self.port_open.store(true, Ordering::Release);
+        }
+
+        loop {
+            let next = match self.pending.borrow_mut() {
+                Ok(mut pending) => pending.pop_front(),
+                Err(_) => None,
+            };
+            let (buf, used) = match next {
+                Some(next) => next,
+                None => {
+                    self.busy.store(false, Ordering::Release);
+                    self.drained.wake_all();
+                    let _ = self.ready.wait().await;
+                    continue;
+                }
+            };
+
+            match port.as_mut() {
+                Some(port) => {
+                    if port.write_buf(buf, used).await.is_err() {
+                        self.drop_bytes(used);
+                    }
+                }
+                None => self.drop_bytes(used),
+            }
+        }
+    }
+
+    fn drop_bytes(&self, len: usize) {
+        self.dropped_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+}